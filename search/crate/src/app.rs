@@ -7,7 +7,7 @@ use web_sys::{History, Location, PopStateEvent};
 use yew::format::{Json, Nothing};
 use yew::prelude::*;
 use yew::services::console::ConsoleService;
-use yew::services::fetch::{FetchService, FetchTask, Request, Response, Uri};
+use yew::services::fetch::{FetchService, FetchTask, Method, Request, Response, Uri};
 use yew::utils::document;
 
 //https://github.com/JaniM/variant-go-server/blob/4f7b8206f605887a1d0e6bb5a10b6d4ae895e4dd/client/src/utils.rs#L30
@@ -32,6 +32,9 @@ macro_rules! if_html {
 pub struct App {
     link: ComponentLink<Self>,
     search: String,
+    // what is typed, searched on enter
+    input: String,
+    suggestions: Option<SuggestJson>,
     show_hash: String,
     settings_click: i64,
     port: String,
@@ -39,12 +42,38 @@ pub struct App {
     network_task: Option<yew::services::fetch::FetchTask>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SuggestJson {
+    queries: Vec<String>,
+    titles: Vec<String>,
+    domains: Vec<String>,
+}
+
+impl SuggestJson {
+    fn all(&self) -> Vec<&String> {
+        let mut all: Vec<&String> = vec![];
+        for suggestion in self
+            .queries
+            .iter()
+            .chain(self.titles.iter())
+            .chain(self.domains.iter())
+        {
+            if !all.contains(&suggestion) {
+                all.push(suggestion);
+            }
+        }
+        all
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize, Clone)]
 pub struct SystemSettings {
     pub port: String,
     pub ignore_domains: Vec<String>,
     pub ignore_strings: Vec<String>,
     pub indexer_enabled: bool,
+    #[serde(default)]
+    pub record_queries: bool,
 }
 
 pub struct Settings {
@@ -181,6 +210,15 @@ impl Settings {
                       { "On" }
                     </label>
                 </div>
+                <div class="switch">
+                    { "Search history: " }
+                    <label>
+                      { "Off" }
+                      <input type="checkbox" checked=settings.record_queries, onclick=self.link.callback(|_| Msg::ToggleRecordQueries ) />
+                      <span class="lever"></span>
+                      { "On" }
+                    </label>
+                </div>
               </div>
             </>}
         } else {
@@ -265,6 +303,12 @@ impl Component for Settings {
                     self.update_settings(None);
                 }
             }
+            Msg::ToggleRecordQueries => {
+                if let Some(settings) = self.settings.as_mut() {
+                    settings.record_queries = !settings.record_queries;
+                    self.update_settings(None);
+                }
+            }
 
             Msg::UpdatePort(string) => {
                 // server needs to be pre configured
@@ -301,6 +345,8 @@ impl Component for Settings {
 struct SearchArray {
     results: Vec<SearchJson>,
     meta: Option<SearchMeta>,
+    #[serde(default)]
+    suggestion: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -323,6 +369,16 @@ struct SearchJson {
     pinned: i64,
     duplicate: i64,
     accessed_count: i64,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    image: String,
+    #[serde(default)]
+    published_at: String,
+    #[serde(default)]
+    duplicates: Vec<String>,
+    #[serde(default)]
+    reading_status: String,
 }
 
 pub struct SearchResults {
@@ -336,10 +392,30 @@ pub struct SearchResults {
     props: SearchProps,
     network_task: Option<yew::services::fetch::FetchTask>,
     pin_task: Option<yew::services::fetch::FetchTask>,
+    history_task: Option<yew::services::fetch::FetchTask>,
+    // ids of checked results, or every result of the search with select_all
+    selected: Vec<String>,
+    select_all: bool,
+    bulk_tag: String,
+    bulk_report: Option<String>,
+    bulk_task: Option<yew::services::fetch::FetchTask>,
+    // the results are narrowed to pages with all of these facets
+    facets: Vec<String>,
+    facet_counts: Vec<FacetCount>,
+    facet_task: Option<yew::services::fetch::FetchTask>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FacetCount {
+    name: String,
+    count: u64,
 }
 #[derive(Properties, Clone, PartialEq, Debug)]
 pub struct SearchProps {
     search_input: String,
+    // lets a "did you mean" click update the search bar
+    #[prop_or_default]
+    onsearch: Callback<String>,
 }
 impl Component for SearchResults {
     type Message = Msg;
@@ -360,6 +436,15 @@ impl Component for SearchResults {
             fetching: false,
             network_task: None,
             pin_task: None,
+            history_task: None,
+            selected: vec![],
+            select_all: false,
+            bulk_tag: String::new(),
+            bulk_report: None,
+            bulk_task: None,
+            facets: vec![],
+            facet_counts: vec![],
+            facet_task: None,
             props,
         };
         if !s.search.is_empty() {
@@ -369,7 +454,8 @@ impl Component for SearchResults {
     }
     fn change(&mut self, props: Self::Properties) -> ShouldRender {
         ConsoleService::log(&format!("p{:?}", props));
-        if self.props != props {
+        // the callback is rebuilt on every parent render, only the text matters
+        if self.props.search_input != props.search_input {
             self.update(Msg::Search(props.search_input.clone()));
             self.props = props;
             true
@@ -384,6 +470,7 @@ impl Component for SearchResults {
             Msg::Unpin(string) => self.remote_set_attribute(&string, &"pinned", 0),
             Msg::Hide(string) => self.remote_set_attribute(&string, &"hide", 1),
             Msg::HideDomain(string) => self.remote_set_attribute(&string, &"hide_domain", 1),
+            Msg::ReadingStatus((url, status)) => self.remote_set_reading_status(&url, &status),
             Msg::UpdatePort(string) => {
                 self.port = string;
             }
@@ -401,7 +488,88 @@ impl Component for SearchResults {
                     self.new_tag = tag;
                 }
             }
+            Msg::AddFacet(facet) => {
+                // a facet below one already picked replaces it
+                self.facets
+                    .retain(|picked| !facet.starts_with(&format!("{}/", picked)));
+                if !self.facets.contains(&facet) {
+                    self.facets.push(facet);
+                }
+                self.fetch_search(&self.search.clone());
+            }
+            Msg::RemoveFacet(facet) => {
+                self.facets.retain(|picked| picked != &facet);
+                self.fetch_search(&self.search.clone());
+            }
+            Msg::FetchReady(response) if response.0 == "facet_counts" => {
+                self.facet_task = None;
+                self.facet_counts = response
+                    .1
+                    .ok()
+                    .and_then(|counts| serde_json::from_value(counts).ok())
+                    .unwrap_or_default();
+            }
+            Msg::Select(id) => {
+                if self.selected.contains(&id) {
+                    self.selected.retain(|selected| selected != &id);
+                } else {
+                    self.selected.push(id);
+                }
+                self.select_all = false;
+            }
+            Msg::SelectAllMatching => {
+                self.select_all = !self.select_all;
+                self.selected.clear();
+            }
+            Msg::BulkTag(tag) => {
+                self.bulk_tag = tag;
+            }
+            Msg::Bulk(action) => {
+                if action == "delete"
+                    && !web_sys::window()
+                        .and_then(|window| {
+                            window
+                                .confirm_with_message("delete the selected pages?")
+                                .ok()
+                        })
+                        .unwrap_or(false)
+                {
+                    return false;
+                }
+                let mut body = serde_json::json!({"action": action, "value": self.bulk_tag.trim()});
+                if self.select_all {
+                    body["q"] = serde_json::json!(self.search);
+                    body["facets"] = serde_json::json!(self.facets);
+                } else if !self.selected.is_empty() {
+                    body["ids"] = serde_json::json!(self.selected);
+                } else {
+                    return false;
+                }
+                self.bulk_task = Some(send_json(
+                    &self.link,
+                    Method::POST,
+                    format!("http://localhost:{}/bulk", self.port),
+                    Json(&body),
+                    "bulk".to_string(),
+                ));
+            }
+            Msg::FetchReady(response) if response.0 == "bulk" => {
+                self.bulk_task = None;
+                self.bulk_report = response.1.ok().map(|report| {
+                    format!("changed {} of {}", report["changed"], report["documents"])
+                });
+                self.selected.clear();
+                self.select_all = false;
+                self.bulk_tag = String::new();
+                self.update(Msg::Search(self.search.clone()));
+            }
             Msg::Search(search_string) => {
+                if search_string != self.search {
+                    self.selected.clear();
+                    self.select_all = false;
+                    self.bulk_report = None;
+                    self.facets.clear();
+                }
                 self.search = search_string;
                 // remove dup?
                 if !self.search.trim().is_empty() {
@@ -418,6 +586,22 @@ impl Component for SearchResults {
                     self.network_task = None;
                 }
             }
+            Msg::SaveSearch => {
+                let body =
+                    serde_json::json!({"name": self.search.trim(), "query": self.search.trim()});
+                self.history_task = Some(send_json(
+                    &self.link,
+                    Method::POST,
+                    format!("http://localhost:{}/queries/saved", self.port),
+                    Json(&body),
+                    "history".to_string(),
+                ));
+                return false;
+            }
+            Msg::FetchReady(response) if response.0 == "history" => {
+                self.history_task = None;
+                return false;
+            }
             Msg::FetchReady(response) => {
                 if let Some(next) = &self.queued_search {
                     self.fetching = false;
@@ -446,6 +630,7 @@ impl Component for SearchResults {
                             ConsoleService::log(&format!("{:?}", results));
                             // remove dup
                             self.for_value(results);
+                            self.fetch_facet_counts();
                         }
                         "set_attributes" => {
                             self.pin_task = None;
@@ -491,11 +676,48 @@ impl SearchResults {
         // cause "debounce" the js kills the request the server still processes them
         self.network_task = Some(self.fetch_json(
             false,
-            format!("http://localhost:{}/search?q={}", self.port, urlencoded),
+            format!(
+                "http://localhost:{}/search?q={}{}",
+                self.port,
+                urlencoded,
+                self.facet_params("facet")
+            ),
             "search_items".to_string(),
         ));
     }
 
+    fn facet_params(&self, key: &str) -> String {
+        self.facets
+            .iter()
+            .map(|facet| {
+                let facet: String = byte_serialize(facet.as_bytes()).collect();
+                format!("&{}={}", key, facet)
+            })
+            .collect()
+    }
+
+    // counts below the last picked facet, for the pages the search finds
+    fn fetch_facet_counts(&mut self) {
+        let root = self
+            .facets
+            .last()
+            .cloned()
+            .unwrap_or_else(|| "/".to_string());
+        let root: String = byte_serialize(root.as_bytes()).collect();
+        let query: String = byte_serialize(self.search.as_bytes()).collect();
+        self.facet_task = Some(self.fetch_json(
+            false,
+            format!(
+                "http://localhost:{}/facets?facet={}&q={}{}",
+                self.port,
+                root,
+                query,
+                self.facet_params("filter")
+            ),
+            "facet_counts".to_string(),
+        ));
+    }
+
     fn remote_set_tag(&mut self, url: &str, tag: &str, action: &str) {
         let urlencoded: String = byte_serialize(url.as_bytes()).collect();
         let urlencoded_tag: String = byte_serialize(tag.as_bytes()).collect();
@@ -510,6 +732,18 @@ impl SearchResults {
         ));
     }
 
+    fn remote_set_reading_status(&mut self, url: &str, status: &str) {
+        let urlencoded: String = byte_serialize(url.as_bytes()).collect();
+        self.pin_task = Some(self.fetch_json(
+            false,
+            format!(
+                "http://localhost:{}/reading?url={}&status={}",
+                self.port, urlencoded, status
+            ),
+            "set_attributes".to_string(),
+        ));
+    }
+
     fn remote_set_attribute(&mut self, url: &str, field: &str, value: i64) {
         let urlencoded: String = byte_serialize(url.as_bytes()).collect();
         // cause "debounce" the js kills the request the server still processes them
@@ -557,8 +791,14 @@ impl SearchResults {
         )
     }
 
-    fn search_item_html(&self, obj: &SearchJson) -> Html {
-        let link = if let Some(location) = document().location() {
+    fn search_item_html(&self, obj: &SearchJson, position: usize) -> Html {
+        // through the server so the click is counted
+        let query: String = byte_serialize(self.search.as_bytes()).collect();
+        let go = format!(
+            "http://localhost:{}/go/{}?q={}&pos={}",
+            self.port, obj.id, query, position
+        );
+        let cached = if let Some(location) = document().location() {
             if let Ok(href) = location.origin() {
                 format!(
                     "{}/{}?view={}",
@@ -572,23 +812,149 @@ impl SearchResults {
         } else {
             format!("/index.html?view={}", obj.id)
         };
+        let id = obj.id.clone();
         html! {
           <li class="collection-item avatar">
-            <span class="title"><a href=link.clone() target="_blank">{&obj.title}{" "}{&obj.url}</a></span>
-            <p> {&obj.description} <br/>
+            <label class="bulk-select">
+                <input type="checkbox" checked=self.select_all || self.selected.contains(&obj.id)
+                    disabled=self.select_all onclick=self.link.callback(move |_| Msg::Select(id.clone()))/>
+                <span></span>
+            </label>
+            { self.thumbnail(&obj.image) }
+            <span class="title"><a href=go target="_blank">{&obj.title}{" "}{&obj.url}</a></span>
+            <p> { self.byline(obj) }
+            {&obj.description} <br/>
             {&obj.summary}
             <br/>
             { obj.tags.iter().map(|keyword| self.chip(&obj.url.clone(), &keyword)).collect::<Vec<Html>>()}
+            { self.duplicates_html(&obj.duplicates) }
             </p>
 
             { self.pinned(&obj.pinned, obj.url.clone()) }
             { self.bookmarked(&obj.bookmarked) }
-            { self.menu(&obj.url, &obj.id) }
+            { self.menu(&obj.url, &obj.id, &cached) }
           </li>
         }
     }
 
-    fn menu(&self, url: &str, id: &str) -> Html {
+    fn suggestion_html(&self, suggestion: &Option<String>) -> Html {
+        if let Some(suggestion) = suggestion {
+            let onsearch = self.props.onsearch.clone();
+            let search = suggestion.clone();
+            html! {
+                <p class="grey-text">{"did you mean "}
+                    <a href="#!" onclick=Callback::from(move |_| onsearch.emit(search.clone()))>{suggestion}</a>
+                </p>
+            }
+        } else {
+            html! {}
+        }
+    }
+
+    // picked facets to remove and the next level to narrow down to
+    fn facets_html(&self) -> Html {
+        if self.facets.is_empty() && self.facet_counts.is_empty() {
+            return html! {};
+        }
+        html! {
+            <div class="facets">
+                { self.facets.iter().map(|facet| {
+                    let remove = facet.clone();
+                    html! {
+                        <div class="chip grey darken-2 white-text">
+                            {facet}
+                            <i class="close material-icons" onclick=self.link.callback(move |_| Msg::RemoveFacet(remove.clone()))>{"close"}</i>
+                        </div>
+                    }
+                }).collect::<Html>() }
+                { self.facet_counts.iter().map(|count| {
+                    let add = count.name.clone();
+                    html! {
+                        <a href="#!" class="chip" onclick=self.link.callback(move |_| Msg::AddFacet(add.clone()))>
+                            {format!("{} {}", count.name, count.count)}
+                        </a>
+                    }
+                }).collect::<Html>() }
+            </div>
+        }
+    }
+
+    // acts on the checked results, or on everything the search finds
+    fn bulk_html(&self, json: &SearchArray) -> Html {
+        if json.results.is_empty() {
+            return html! {};
+        }
+        let chosen = if self.select_all {
+            "all matching".to_string()
+        } else {
+            format!("{} selected", self.selected.len())
+        };
+        let action = |name: &'static str| {
+            html! {
+                <a href="#!" class="btn-flat btn-small" onclick=self.link.callback(move |_| Msg::Bulk(name.to_string()))>{name}</a>
+            }
+        };
+        html! {
+            <div class="bulk">
+                <label>
+                    <input type="checkbox" checked=self.select_all onclick=self.link.callback(|_| Msg::SelectAllMatching)/>
+                    <span>{"select all matching"}</span>
+                </label>
+                {" "}<span class="grey-text">{chosen}</span>{" "}
+                <input type="text" class="bulk-tag" placeholder="tag" value=self.bulk_tag.clone()
+                    oninput=self.link.callback(|e: InputData| Msg::BulkTag(e.value))/>
+                { for ["tag", "untag", "pin", "unpin", "hide", "refetch", "delete"].iter().map(|name| action(name)) }
+                { if let Some(report) = &self.bulk_report {
+                    html! { <span class="grey-text">{report}</span> }
+                } else {
+                    html! {}
+                } }
+            </div>
+        }
+    }
+
+    fn thumbnail(&self, image: &str) -> Html {
+        if image.is_empty() {
+            html! {}
+        } else {
+            html! {
+                <img src=image.to_string() class="circle" loading="lazy"/>
+            }
+        }
+    }
+
+    fn duplicates_html(&self, duplicates: &[String]) -> Html {
+        if duplicates.is_empty() {
+            html! {}
+        } else {
+            html! {
+                <><br/><span class="grey-text">{"also at "}
+                { duplicates.iter().map(|url| html! {
+                    <><a href=url.clone() target="_blank">{url}</a>{" "}</>
+                }).collect::<Vec<Html>>() }
+                </span></>
+            }
+        }
+    }
+
+    fn byline(&self, obj: &SearchJson) -> Html {
+        // only the date part of the rfc3339 timestamp
+        let published = obj.published_at.get(0..10).unwrap_or("");
+        let byline = vec![obj.author.as_str(), published, obj.reading_status.as_str()]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join(" · ");
+        if byline.is_empty() {
+            html! {}
+        } else {
+            html! {
+                <><span class="grey-text">{byline}</span><br/></>
+            }
+        }
+    }
+
+    fn menu(&self, url: &str, id: &str, cached: &str) -> Html {
         let base_url = url.clone();
         let base_url = base_url.to_string();
 
@@ -601,8 +967,10 @@ impl SearchResults {
             <>
                 <a class="dropdown-trigger secondary-content" href="#" data-target=format!("dropdown-{}",id)><i class="material-icons">{"arrow_drop_down"}</i> </a>
                 <ul id=format!("dropdown-{}",id) class="dropdown-content">
+                    <li class="clickclose"><a href=cached.to_string() target="_blank">{"cached copy"}</a></li>
                     <li class="clickclose"><a href="#!" onclick=self.link.callback(move |e| Msg::Hide(base_url.clone())) >{"hide url"}</a></li>
                     <li class="clickclose"><a href="#!" onclick=self.link.callback(move |e| Msg::HideDomain(base2_url.clone())) >{"hide domain"}</a></li>
+                    { self.reading_menu(url) }
                     <li> <input id="add_tag" type="text" placeholder="Add Tag" value=self.new_tag.clone()
                     oninput=self.link.callback(move |e: InputData| Msg::Tag((base3_url.clone(), e.value)))/> </li>
                 </ul>
@@ -610,6 +978,23 @@ impl SearchResults {
         }
     }
 
+    fn reading_menu(&self, url: &str) -> Html {
+        vec![
+            ("unread", "read later"),
+            ("reading", "reading"),
+            ("done", "done"),
+            ("archived", "archive"),
+        ]
+        .into_iter()
+        .map(|(status, label)| {
+            let url = url.to_string();
+            html! {
+                <li class="clickclose"><a href="#!" onclick=self.link.callback(move |_| Msg::ReadingStatus((url.clone(), status.to_string())))>{label}</a></li>
+            }
+        })
+        .collect::<Html>()
+    }
+
     fn pinned(&self, marked: &i64, url: String) -> Html {
         let url_pin = url.clone();
         if marked == &1 {
@@ -648,10 +1033,11 @@ impl SearchResults {
     fn chip(&self, url: &str, string: &str) -> Html {
         let string = string.trim().to_string();
         let domain = url.to_string().clone();
+        let facet = string.clone();
         if_html!(
             !string.is_empty() =>
                 <div class="chip">
-                    {string.clone()}
+                    <a href="#!" onclick=self.link.callback(move |_| Msg::AddFacet(facet.clone()))>{string.clone()}</a>
                     <i class="close material-icons" onclick=self.link.callback(move |e: MouseEvent| Msg::Untag((domain.clone(), string.clone())))>{"close"}</i>
                 </div>
         )
@@ -663,8 +1049,12 @@ impl SearchResults {
         } else if let Some(json) = &self.search_json {
             html! {
             <>
+                <a href="#!" class="right" onclick=self.link.callback(|_| Msg::SaveSearch)>{"save search"}</a>
+                { self.suggestion_html(&json.suggestion) }
+                { self.facets_html() }
+                { self.bulk_html(json) }
                 <ul class="collection">
-                    { json.results.iter().map(|i|{ self.search_item_html(&i) }).collect::<Html>() }
+                    { json.results.iter().enumerate().map(|(position, i)|{ self.search_item_html(&i, position) }).collect::<Html>() }
                 </ul>
 
                 <script>
@@ -709,6 +1099,51 @@ impl SearchResults {
 }
 
 impl App {
+    fn fetch_json(
+        &mut self,
+        binary: bool,
+        url: String,
+        stored_data: String,
+    ) -> yew::services::fetch::FetchTask {
+        let callback = self
+            .link
+            .callback(move |response: Response<Json<Result<Value, Error>>>| {
+                let (meta, Json(data)) = response.into_parts();
+                if meta.status.is_success() {
+                    Msg::FetchReady((stored_data.clone(), data))
+                } else {
+                    Msg::Ignore // FIXME: Handle this error accordingly.
+                }
+            });
+        let request = Request::get(url)
+            .header("Accept", "application/json")
+            .body(Nothing)
+            .unwrap();
+        if binary {
+            FetchService::fetch_binary(request, callback).unwrap()
+        } else {
+            FetchService::fetch(request, callback).unwrap()
+        }
+    }
+
+    fn suggestions_html(&self) -> Html {
+        match &self.suggestions {
+            Some(suggestions) if !suggestions.all().is_empty() => html! {
+                <ul class="collection suggestions">
+                { suggestions.all().into_iter().map(|suggestion| {
+                    let search = suggestion.clone();
+                    html! {
+                        <li class="collection-item">
+                            <a href="#!" onclick=self.link.callback(move |_| Msg::Search(search.clone()))>{suggestion}</a>
+                        </li>
+                    }
+                }).collect::<Html>() }
+                </ul>
+            },
+            _ => html! {},
+        }
+    }
+
     fn setting_modal(&self) -> Html {
         html! {
             <Settings clicked_at=self.settings_click />
@@ -718,7 +1153,11 @@ impl App {
         html! {
         <div class="row results">
             <div class="col s11">
-                <SearchResults search_input=self.search.clone()/>
+                { if self.search.trim().is_empty() {
+                    html! { <HomePage port=self.port.clone() onsearch=self.link.callback(Msg::Search)/> }
+                } else {
+                    html! { <SearchResults search_input=self.search.clone() onsearch=self.link.callback(Msg::Search)/> }
+                } }
             </div>
         </div>
         }
@@ -731,8 +1170,11 @@ impl App {
                     <div class="nav-wrapper">
                         <a href="#" data-target="slide-out" class="sidenav-trigger brand-logo"><i class="material-icons">{"menu"}</i></a>
                         <div class="input-field">
-                            <input id="search" type="search" autocomplete="off" required=true value={self.search.clone()} oninput=self.link.callback(|e: InputData| Msg::Search(e.value))/>
+                            <input id="search" type="search" autocomplete="off" required=true value={self.input.clone()}
+                                oninput=self.link.callback(|e: InputData| Msg::Suggest(e.value))
+                                onkeypress=self.link.callback(|e: KeyboardEvent| if e.key() == "Enter" { Msg::SubmitSearch } else { Msg::Ignore })/>
                             <label class="label-icon" for="search"><i class="material-icons">{"search"}</i></label>
+                            { self.suggestions_html() }
                         </div>
                          <a class="btn-floating btn-large halfway-fab waves-effect waves-light grey modal-trigger" href="#setting_modal" onclick=self.link.callback(|_| Msg::ClickSettings)>
                             <i class="material-icons">{"settings"}</i>
@@ -746,12 +1188,16 @@ impl App {
 
 pub enum Msg {
     Search(String),
+    Suggest(String),
+    SubmitSearch,
     Pin(String),
     Unpin(String),
     Hide(String),
     Tag((String, String)),
     Untag((String, String)),
     HideDomain(String),
+    // url and status
+    ReadingStatus((String, String)),
 
     //settings
     RemoveIgnoreDomains(String),
@@ -759,10 +1205,34 @@ pub enum Msg {
     IgnoreStrings(String),
     UpdatePort(String),
     ToggleIndexer,
+    ToggleRecordQueries,
+
+    // facets
+    AddFacet(String),
+    RemoveFacet(String),
+
+    // bulk
+    Select(String),
+    SelectAllMatching,
+    BulkTag(String),
+    Bulk(String),
+
+    // history
+    SaveSearch,
+    DeleteSavedSearch(String),
+    ClearHistory,
 
     ClickSettings,
     FetchReady((String, Result<Value, Error>)),
     ViewString(String),
+    PickVersion(i64),
+    CloseDiff,
+
+    // notes
+    EditNotes(String),
+    SaveNotes,
+    SaveHighlight,
+    RemoveHighlight(String),
     Ignore,
 }
 
@@ -786,6 +1256,8 @@ impl Component for App {
         }
         App {
             link,
+            input: param_search.clone(),
+            suggestions: None,
             search: param_search,
             show_hash: show_hash,
             settings_click: 0,
@@ -803,11 +1275,40 @@ impl Component for App {
                 self.settings_click += 1;
             }
             Msg::Search(search_string) => {
+                self.input = search_string.clone();
                 self.search = search_string;
+                self.suggestions = None;
+                self.network_task = None;
+            }
+            Msg::Suggest(input) => {
+                self.input = input;
+                if self.input.trim().is_empty() {
+                    self.update(Msg::Search(String::new()));
+                } else {
+                    let urlencoded: String = byte_serialize(self.input.as_bytes()).collect();
+                    // replacing the task drops the request for the previous keystroke
+                    self.network_task = Some(self.fetch_json(
+                        false,
+                        format!(
+                            "http://localhost:{}/suggest?prefix={}",
+                            self.port, urlencoded
+                        ),
+                        "suggest".to_string(),
+                    ));
+                }
+            }
+            Msg::SubmitSearch => {
+                self.update(Msg::Search(self.input.clone()));
             }
-            Msg::FetchReady(_response) => {
+            Msg::FetchReady(response) => {
                 self.fetching = false;
                 self.network_task = None;
+                if response.0 == "suggest" {
+                    self.suggestions = response
+                        .1
+                        .ok()
+                        .and_then(|value| serde_json::from_value(value).ok());
+                }
             }
             Msg::Ignore => {
                 return false;
@@ -844,7 +1345,19 @@ pub struct ViewPage {
     content: String,
     fetching: bool,
     port: String,
+    versions: Vec<VersionJson>,
+    picked_versions: Vec<i64>,
+    diff: Option<DiffJson>,
+    notes: NotesJson,
     network_task: Option<yew::services::fetch::FetchTask>,
+    versions_task: Option<yew::services::fetch::FetchTask>,
+    notes_task: Option<yew::services::fetch::FetchTask>,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default)]
+pub struct NotesJson {
+    notes: String,
+    highlights: Vec<String>,
 }
 
 #[derive(Properties, Clone, PartialEq, Debug)]
@@ -858,6 +1371,26 @@ pub struct ViewJson {
     content: String,
 }
 
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct VersionJson {
+    version: i64,
+    fetched_at: String,
+    current: bool,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct DiffChunk {
+    op: String,
+    text: String,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct DiffJson {
+    from: i64,
+    to: Option<i64>,
+    chunks: Vec<DiffChunk>,
+}
+
 impl Component for ViewPage {
     type Message = Msg;
     type Properties = ViewPageProps;
@@ -868,11 +1401,22 @@ impl Component for ViewPage {
             content: "".to_string(),
             fetching: false,
             network_task: None,
+            versions: vec![],
+            picked_versions: vec![],
+            diff: None,
+            versions_task: None,
+            notes: NotesJson::default(),
+            notes_task: None,
             port: props.port,
             link,
         };
 
         view.fetch_settings(Some(view.port.clone()));
+        view.fetch_versions();
+        view.notes_task = Some(view.fetch_value(
+            format!("http://localhost:{}/notes/{}", view.port, view.hash),
+            "notes".to_string(),
+        ));
         view
     }
 
@@ -884,6 +1428,68 @@ impl Component for ViewPage {
                 ConsoleService::log(&format!("{:?}", response));
                 self.content = response;
             }
+            Msg::PickVersion(version) => {
+                if self.picked_versions.contains(&version) {
+                    self.picked_versions.retain(|v| v != &version);
+                } else {
+                    self.picked_versions.push(version);
+                    if self.picked_versions.len() > 2 {
+                        self.picked_versions.remove(0);
+                    }
+                }
+                if self.picked_versions.len() == 2 {
+                    self.fetch_diff();
+                } else {
+                    self.diff = None;
+                }
+            }
+            Msg::CloseDiff => {
+                self.picked_versions = vec![];
+                self.diff = None;
+            }
+            Msg::EditNotes(notes) => {
+                self.notes.notes = notes;
+                return false;
+            }
+            Msg::SaveNotes => {
+                self.send_notes(serde_json::json!({ "notes": self.notes.notes }));
+            }
+            Msg::SaveHighlight => {
+                // the text selected in the offline copy
+                let selected = web_sys::window()
+                    .and_then(|window| window.get_selection().ok().flatten())
+                    .and_then(|selection| selection.to_string().as_string())
+                    .unwrap_or_default();
+                if selected.trim().is_empty() {
+                    return false;
+                }
+                self.send_notes(serde_json::json!({ "highlights_add": [selected.trim()] }));
+            }
+            Msg::RemoveHighlight(highlight) => {
+                self.send_notes(serde_json::json!({ "highlights_remove": [highlight] }));
+            }
+            Msg::FetchReady(response) if response.0 == "notes" => {
+                self.notes_task = None;
+                if let Ok(notes) = response.1 {
+                    self.notes = serde_json::from_value(notes).unwrap_or_default();
+                }
+            }
+            Msg::FetchReady(response) => {
+                self.versions_task = None;
+                match response.0.as_str() {
+                    "versions" => {
+                        if let Ok(results) = response.1 {
+                            self.versions = serde_json::from_value(results).unwrap_or_default();
+                        }
+                    }
+                    "diff" => {
+                        if let Ok(results) = response.1 {
+                            self.diff = serde_json::from_value(results).ok();
+                        }
+                    }
+                    _ => {}
+                }
+            }
             _ => {}
         }
         true
@@ -900,9 +1506,20 @@ impl Component for ViewPage {
                 <div class="indeterminate"></div>
             </div>
                 }
+        } else if let Some(diff) = &self.diff {
+            html! {
+                <div class="container">
+                    { self.versions_html() }
+                    <div id="content" class="diff">
+                        { diff.chunks.iter().map(|chunk| self.diff_chunk(chunk)).collect::<Html>() }
+                    </div>
+                </div>
+            }
         } else if !self.content.is_empty() {
             html! {
                 <div class="container">
+                    { self.versions_html() }
+                    { self.notes_html() }
                     <RawHTML inner_html=self.content.clone()/>
                 </div>
             }
@@ -913,6 +1530,127 @@ impl Component for ViewPage {
 }
 
 impl ViewPage {
+    fn send_notes(&mut self, body: Value) {
+        self.notes_task = Some(send_json(
+            &self.link,
+            Method::POST,
+            format!("http://localhost:{}/notes/{}", self.port, self.hash),
+            Json(&body),
+            "notes".to_string(),
+        ));
+    }
+
+    fn notes_html(&self) -> Html {
+        html! {
+            <div class="row notes">
+                <div class="input-field col s12">
+                    <textarea id="notes" class="materialize-textarea" value=self.notes.notes.clone()
+                        oninput=self.link.callback(|e: InputData| Msg::EditNotes(e.value))></textarea>
+                    <label for="notes" class="active">{"Notes"}</label>
+                </div>
+                <a href="#!" class="btn-flat" onclick=self.link.callback(|_| Msg::SaveNotes)>{"save note"}</a>
+                <a href="#!" class="btn-flat" onclick=self.link.callback(|_| Msg::SaveHighlight)>{"highlight selection"}</a>
+                <ul class="highlights">
+                { self.notes.highlights.iter().map(|highlight| {
+                    let remove = highlight.clone();
+                    html! {
+                        <li><blockquote>
+                            {highlight}
+                            <i class="close material-icons" onclick=self.link.callback(move |_| Msg::RemoveHighlight(remove.clone()))>{"close"}</i>
+                        </blockquote></li>
+                    }
+                }).collect::<Html>() }
+                </ul>
+            </div>
+        }
+    }
+
+    fn versions_html(&self) -> Html {
+        if_html!(self.versions.len() > 1 =>
+            <div class="row versions">
+                { "Versions (pick two to compare): " }
+                { self.versions.iter().map(|v| self.version_chip(v)).collect::<Html>() }
+                { if_html!(self.diff.is_some() =>
+                    <div class="chip" onclick=self.link.callback(|_| Msg::CloseDiff)>
+                        { "close diff" }
+                        <i class="close material-icons">{"close"}</i>
+                    </div>
+                ) }
+            </div>
+        )
+    }
+
+    fn version_chip(&self, version: &VersionJson) -> Html {
+        let value = version.version;
+        let class = if self.picked_versions.contains(&value) {
+            "chip version-picked"
+        } else {
+            "chip"
+        };
+        let label = if version.current {
+            format!("{} (current)", version.fetched_at)
+        } else {
+            version.fetched_at.clone()
+        };
+        html! {
+            <div class=class onclick=self.link.callback(move |_| Msg::PickVersion(value))>
+                { label }
+            </div>
+        }
+    }
+
+    fn diff_chunk(&self, chunk: &DiffChunk) -> Html {
+        match chunk.op.as_str() {
+            "add" => html! { <p><ins class="diff-add">{ &chunk.text }</ins></p> },
+            "remove" => html! { <p><del class="diff-remove">{ &chunk.text }</del></p> },
+            _ => html! { <p>{ &chunk.text }</p> },
+        }
+    }
+
+    fn fetch_versions(&mut self) {
+        self.versions_task = Some(self.fetch_value(
+            format!("http://localhost:{}/versions/{}", self.port, self.hash),
+            "versions".to_string(),
+        ));
+    }
+
+    fn fetch_diff(&mut self) {
+        let mut picked = self.picked_versions.clone();
+        picked.sort_unstable();
+        // the current copy is always the newest
+        let current = self.versions.iter().find(|v| v.current).map(|v| v.version);
+        let to = if Some(picked[1]) == current {
+            "".to_string()
+        } else {
+            format!("&to={}", picked[1])
+        };
+        self.versions_task = Some(self.fetch_value(
+            format!(
+                "http://localhost:{}/versions/{}/diff?from={}{}",
+                self.port, self.hash, picked[0], to
+            ),
+            "diff".to_string(),
+        ));
+    }
+
+    fn fetch_value(&mut self, url: String, stored_data: String) -> yew::services::fetch::FetchTask {
+        let callback = self
+            .link
+            .callback(move |response: Response<Json<Result<Value, Error>>>| {
+                let (meta, Json(data)) = response.into_parts();
+                if meta.status.is_success() {
+                    Msg::FetchReady((stored_data.clone(), data))
+                } else {
+                    Msg::Ignore // FIXME: Handle this error accordingly.
+                }
+            });
+        let request = Request::get(url)
+            .header("Accept", "application/json")
+            .body(Nothing)
+            .unwrap();
+        FetchService::fetch(request, callback).unwrap()
+    }
+
     fn fetch_json(
         &mut self,
         binary: bool,
@@ -948,6 +1686,215 @@ impl ViewPage {
         ));
     }
 }
+// sends a request with a json body, the response comes back as FetchReady
+fn send_json<C: Component<Message = Msg>>(
+    link: &ComponentLink<C>,
+    method: Method,
+    url: String,
+    body: Json<&Value>,
+    stored_data: String,
+) -> yew::services::fetch::FetchTask {
+    let callback = link.callback(move |response: Response<Json<Result<Value, Error>>>| {
+        let (meta, Json(data)) = response.into_parts();
+        if meta.status.is_success() {
+            Msg::FetchReady((stored_data.clone(), data))
+        } else {
+            Msg::Ignore // FIXME: Handle this error accordingly.
+        }
+    });
+    let request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("Accept", "application/json")
+        .header("Content-Type", "application/json")
+        .body(body)
+        .unwrap();
+    FetchService::fetch(request, callback).unwrap()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct QueryEntry {
+    query: String,
+    searched_at: i64,
+    #[serde(default)]
+    clicks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SavedSearch {
+    name: String,
+    query: String,
+}
+
+// shown instead of results while nothing is searched
+pub struct HomePage {
+    link: ComponentLink<Self>,
+    props: HomePageProps,
+    recent: Vec<QueryEntry>,
+    saved: Vec<SavedSearch>,
+    recent_task: Option<yew::services::fetch::FetchTask>,
+    saved_task: Option<yew::services::fetch::FetchTask>,
+}
+
+#[derive(Properties, Clone, PartialEq, Debug)]
+pub struct HomePageProps {
+    pub port: String,
+    #[prop_or_default]
+    pub onsearch: Callback<String>,
+}
+
+impl Component for HomePage {
+    type Message = Msg;
+    type Properties = HomePageProps;
+
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        let mut home = HomePage {
+            link,
+            props,
+            recent: vec![],
+            saved: vec![],
+            recent_task: None,
+            saved_task: None,
+        };
+        home.recent_task = Some(home.fetch_value(
+            Method::GET,
+            format!(
+                "http://localhost:{}/queries/recent?limit=10",
+                home.props.port
+            ),
+            "recent",
+        ));
+        home.saved_task = Some(home.fetch_value(
+            Method::GET,
+            format!("http://localhost:{}/queries/saved", home.props.port),
+            "saved",
+        ));
+        home
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::Search(query) => {
+                self.props.onsearch.emit(query);
+                return false;
+            }
+            Msg::DeleteSavedSearch(name) => {
+                let name: String = byte_serialize(name.as_bytes()).collect();
+                self.saved_task = Some(self.fetch_value(
+                    Method::DELETE,
+                    format!(
+                        "http://localhost:{}/queries/saved/{}",
+                        self.props.port, name
+                    ),
+                    "saved",
+                ));
+            }
+            Msg::ClearHistory => {
+                self.recent_task = Some(self.fetch_value(
+                    Method::DELETE,
+                    format!("http://localhost:{}/queries/recent", self.props.port),
+                    "recent",
+                ));
+            }
+            Msg::FetchReady((key, Ok(value))) => match key.as_str() {
+                "recent" => {
+                    self.recent_task = None;
+                    self.recent = serde_json::from_value(value).unwrap_or_default();
+                }
+                "saved" => {
+                    self.saved_task = None;
+                    self.saved = serde_json::from_value(value).unwrap_or_default();
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        true
+    }
+
+    fn change(&mut self, props: Self::Properties) -> ShouldRender {
+        self.props = props;
+        false
+    }
+
+    fn view(&self) -> Html {
+        html! {
+        <div class="row">
+            { if_html!(!self.saved.is_empty() =>
+                <div class="col s12">
+                    <h6>{"Saved searches"}</h6>
+                    { self.saved.iter().map(|saved| self.saved_chip(saved)).collect::<Html>() }
+                </div>
+            ) }
+            { if_html!(!self.recent.is_empty() =>
+                <div class="col s12">
+                    <h6>{"Recent searches "}
+                        <a href="#!" class="grey-text" onclick=self.link.callback(|_| Msg::ClearHistory)>{"clear"}</a>
+                    </h6>
+                    <ul class="collection">
+                    { self.recent.iter().map(|entry| self.recent_item(entry)).collect::<Html>() }
+                    </ul>
+                </div>
+            ) }
+        </div>
+        }
+    }
+}
+
+impl HomePage {
+    fn saved_chip(&self, saved: &SavedSearch) -> Html {
+        let query = saved.query.clone();
+        let name = saved.name.clone();
+        html! {
+            <div class="chip">
+                <a href="#!" onclick=self.link.callback(move |_| Msg::Search(query.clone()))>{&saved.name}</a>
+                <i class="close material-icons" onclick=self.link.callback(move |_: MouseEvent| Msg::DeleteSavedSearch(name.clone()))>{"close"}</i>
+            </div>
+        }
+    }
+
+    fn recent_item(&self, entry: &QueryEntry) -> Html {
+        let query = entry.query.clone();
+        let clicks = match entry.clicks.len() {
+            0 => String::new(),
+            1 => " · 1 result opened".to_string(),
+            count => format!(" · {} results opened", count),
+        };
+        html! {
+            <li class="collection-item">
+                <a href="#!" onclick=self.link.callback(move |_| Msg::Search(query.clone()))>{&entry.query}</a>
+                <span class="grey-text">{clicks}</span>
+            </li>
+        }
+    }
+
+    fn fetch_value(
+        &mut self,
+        method: Method,
+        url: String,
+        stored_data: &str,
+    ) -> yew::services::fetch::FetchTask {
+        let stored_data = stored_data.to_string();
+        let callback = self
+            .link
+            .callback(move |response: Response<Json<Result<Value, Error>>>| {
+                let (meta, Json(data)) = response.into_parts();
+                if meta.status.is_success() {
+                    Msg::FetchReady((stored_data.clone(), data))
+                } else {
+                    Msg::Ignore // FIXME: Handle this error accordingly.
+                }
+            });
+        let request = Request::builder()
+            .method(method)
+            .uri(url)
+            .header("Accept", "application/json")
+            .body(Nothing)
+            .unwrap();
+        FetchService::fetch(request, callback).unwrap()
+    }
+}
+
 // https://github.com/yewstack/yew/issues/1281
 #[derive(Debug, Clone, Eq, PartialEq, Properties)]
 struct RawHTMLProps {
@@ -140,6 +140,11 @@ fn main() -> tantivy::Result<()> {
                             tags_add: None,
                             tags_remove: None,
                             hidden: None,
+                            summary: None,
+                            notes: None,
+                            highlights_add: None,
+                            highlights_remove: None,
+                            reading_status: None,
                         };
                         Some((place.url, meta, place.id, place.last_visit_date))
                     } else {
@@ -178,6 +178,11 @@ fn main() -> tantivy::Result<()> {
                             tags_add: None,
                             tags_remove: None,
                             hidden: Some(0),
+                            summary: None,
+                            notes: None,
+                            highlights_add: None,
+                            highlights_remove: None,
+                            reading_status: None,
                         };
                         Some((place.url, meta, place.id, place.last_visit_date))
                     } else {
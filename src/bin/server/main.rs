@@ -7,8 +7,15 @@ use actix_cors::Cors;
 use actix_files::NamedFile;
 use actix_web::http::StatusCode;
 use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer, Result};
+use chrono::TimeZone;
 
+use personal_search::bulk;
+use personal_search::collections;
+use personal_search::export;
 use personal_search::indexer;
+use personal_search::queries;
+use personal_search::tagging;
+use personal_search::vectors;
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -18,7 +25,8 @@ use tantivy::collector::FacetCollector;
 use tantivy::collector::TopDocs;
 use tantivy::doc;
 use tantivy::query::AllQuery;
-use tantivy::query::QueryParser;
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::{Facet, IndexRecordOption, Term};
 
 #[derive(StructOpt, Debug)]
 pub struct Opt {
@@ -39,7 +47,7 @@ struct FacetCount {
     count: u64,
 }
 
-fn facets(query: String, field: String) -> Vec<FacetCount> {
+fn facets(query: String, field: String, filter: Option<Box<dyn Query>>) -> Vec<FacetCount> {
     let query = if query.starts_with('/') {
         query
     } else {
@@ -55,7 +63,11 @@ fn facets(query: String, field: String) -> Vec<FacetCount> {
     let mut facet_collector = FacetCollector::for_field(tags);
     facet_collector.add_facet(&query);
 
-    let facet_counts = searcher.search(&AllQuery, &facet_collector).expect("facet");
+    let facet_counts = match filter {
+        Some(filter) => searcher.search(&filter, &facet_collector),
+        None => searcher.search(&AllQuery, &facet_collector),
+    }
+    .expect("facet");
 
     facet_counts
         .get(&query)
@@ -81,6 +93,19 @@ struct SearchJson {
     accessed_count: i64,
     added_at: String,
     last_accessed_at: String,
+    author: String,
+    lang: String,
+    canonical_url: String,
+    image: String,
+    meta_type: String,
+    published_at: String,
+    duplicate_of: String,
+    // urls of near duplicates folded into this result
+    duplicates: Vec<String>,
+    notes: String,
+    highlights: Vec<String>,
+    reading_status: String,
+    queued_at: String,
 }
 fn doc_to_json(retrieved_doc: &tantivy::Document, schema: &tantivy::schema::Schema) -> SearchJson {
     let mut m = HashMap::new();
@@ -139,6 +164,65 @@ fn doc_to_json(retrieved_doc: &tantivy::Document, schema: &tantivy::schema::Sche
             .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
             .unwrap_or("")
             .to_string(),
+        author: m
+            .get("author")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        lang: m
+            .get("lang")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        canonical_url: m
+            .get("canonical_url")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        image: m
+            .get("image")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        meta_type: m
+            .get("meta_type")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        duplicate_of: m
+            .get("duplicate_of")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        duplicates: vec![],
+        notes: m
+            .get("notes")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        highlights: m
+            .get("highlights")
+            .map(|t| {
+                t.iter()
+                    .map(|f| f.text().unwrap_or("").to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        reading_status: m
+            .get("reading_status")
+            .map(|t| t.get(0).map(|f| f.text().unwrap_or("")).unwrap())
+            .unwrap_or("")
+            .to_string(),
+        queued_at: m
+            .get("queued_at_i")
+            .map(|t| t.get(0).map(|f| f.i64_value()).unwrap())
+            .map(|queued| chrono::Utc.timestamp(queued, 0).to_rfc3339())
+            .unwrap_or_default(),
+        published_at: m
+            .get("published_at_i")
+            .map(|t| t.get(0).map(|f| f.i64_value()).unwrap())
+            .map(|published| chrono::Utc.timestamp(published, 0).to_rfc3339())
+            .unwrap_or_default(),
         //no longer real
         keywords: m
             .get("keywords")
@@ -169,62 +253,373 @@ fn doc_to_json(retrieved_doc: &tantivy::Document, schema: &tantivy::schema::Sche
     }
 }
 
-fn search(query: String, limit: usize) -> Vec<SearchJson> {
+// facets a search is narrowed to, like /tags/rust. pages need all of them,
+// or one with any
+#[derive(Debug, Default)]
+struct FacetFilter {
+    facets: Vec<String>,
+    any: bool,
+}
+
+impl FacetFilter {
+    // facet=/tags/a&facet=/tags/b, repeated keys don't fit a Query struct
+    fn from_request(request: &HttpRequest, key: &str, op: Option<&str>) -> FacetFilter {
+        FacetFilter {
+            facets: url::form_urlencoded::parse(request.query_string().as_bytes())
+                .filter(|(name, value)| name == key && !value.trim().is_empty())
+                .map(|(_, value)| tagging::tag_facet(&value))
+                .collect(),
+            any: op == Some("or"),
+        }
+    }
+
+    fn query(&self, index: &tantivy::Index) -> Option<Box<dyn Query>> {
+        if self.facets.is_empty() {
+            return None;
+        }
+        let tags = index.schema().get_field("tags").expect("tags");
+        let occur = if self.any { Occur::Should } else { Occur::Must };
+        let clauses = self
+            .facets
+            .iter()
+            .map(|facet| {
+                // a facet term matches the pages tagged below it too
+                let query: Box<dyn Query> = Box::new(TermQuery::new(
+                    Term::from_facet(tags, &Facet::from(facet.as_str())),
+                    IndexRecordOption::Basic,
+                ));
+                (occur, query)
+            })
+            .collect::<Vec<_>>();
+        Some(Box::new(BooleanQuery::from(clauses)))
+    }
+
+    fn matches(&self, tags: &[String]) -> bool {
+        let has = |facet: &String| {
+            tags.iter()
+                .any(|tag| tag == facet || tag.starts_with(&format!("{}/", facet)))
+        };
+        self.facets.is_empty()
+            || if self.any {
+                self.facets.iter().any(has)
+            } else {
+                self.facets.iter().all(has)
+            }
+    }
+}
+
+// the query /search runs narrowed to the facets
+fn search_query(
+    index: &tantivy::Index,
+    query: &str,
+    lang: Option<&str>,
+    fuzzy: bool,
+    facets: &FacetFilter,
+) -> Result<Box<dyn Query>, tantivy::query::QueryParserError> {
+    let text = indexer::search_query(index, query, lang, fuzzy)?;
+    Ok(match facets.query(index) {
+        Some(facets) => Box::new(BooleanQuery::from(vec![
+            (Occur::Must, text),
+            (Occur::Must, facets),
+        ])),
+        None => text,
+    })
+}
+
+fn search(
+    query: String,
+    limit: usize,
+    sort: Option<String>,
+    lang: Option<String>,
+    fuzzy: bool,
+    mode: Option<String>,
+    facets: FacetFilter,
+) -> SearchResponse {
     let index = indexer::search_index().expect("could not open search index");
     let searcher = indexer::searcher(&index);
-    let default_fields: Vec<tantivy::schema::Field> = index
-        .schema()
-        .fields()
-        .filter(|&(_, ref field_entry)| match *field_entry.field_type() {
-            tantivy::schema::FieldType::Str(ref text_field_options) => {
-                text_field_options.get_indexing_options().is_some()
+    let lang = lang.filter(|lang| !lang.is_empty());
+    let parsed = search_query(&index, &query, lang.as_deref(), fuzzy, &facets);
+
+    let by_published = sort.as_deref() == Some("published");
+    let mode = if by_published {
+        "keyword"
+    } else {
+        mode.as_deref().unwrap_or("keyword")
+    };
+    let keyword_hits = match parsed {
+        Ok(parsed) if mode != "semantic" => {
+            let top_docs = if by_published {
+                let published = index
+                    .schema()
+                    .get_field("published_at_i")
+                    .expect("published_at_i");
+                // newest first, pages without a date sort last
+                let by_published = TopDocs::with_limit(limit * 3).custom_score(
+                    move |segment_reader: &tantivy::SegmentReader| {
+                        let published = segment_reader
+                            .fast_fields()
+                            .i64(published)
+                            .expect("published_at_i fast field");
+                        move |doc: tantivy::DocId| published.get(doc)
+                    },
+                );
+                searcher
+                    .search(&parsed, &by_published)
+                    .expect("serach")
+                    .into_iter()
+                    .map(|doc| (0.0, doc.1))
+                    .collect::<Vec<_>>()
+            } else {
+                searcher
+                    .search(&parsed, &TopDocs::with_limit(limit * 3))
+                    .expect("serach")
+            };
+            let schema = index.schema();
+            top_docs
+                .iter()
+                .map(|(score, doc)| {
+                    let retrieved_doc = searcher.doc(*doc).expect("doc");
+                    (*score, doc_to_json(&retrieved_doc, &schema))
+                })
+                .collect::<Vec<_>>()
+        }
+        _ => vec![],
+    };
+    let show_hidden = query.contains("hidden:");
+    let mut hits = match mode {
+        "semantic" => semantic_hits(&index, &query, limit * 3, show_hidden, lang.as_deref()),
+        "hybrid" => fuse(
+            keyword_hits,
+            semantic_hits(&index, &query, limit * 3, show_hidden, lang.as_deref()),
+        ),
+        _ => keyword_hits,
+    };
+    hits.retain(|(_, json)| facets.matches(&json.tags));
+    // results opened before for similar searches move up
+    if !by_published {
+        let boosts = queries::click_boosts(&query);
+        for (score, json) in hits.iter_mut() {
+            if let Some(clicks) = boosts.get(&json.id) {
+                *score = queries::boosted_score(*score, *clicks);
             }
-            _ => false,
-        })
-        .map(|(field, _)| field)
-        .collect();
+        }
+        hits.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    }
 
-    let query_parser = QueryParser::new(index.schema(), default_fields, index.tokenizers().clone());
-    let query = if query.contains("hidden:") {
-        query
+    // near duplicates are folded into the first result of their group. more
+    // docs are read than asked for so grouping still fills the page
+    let mut results: Vec<SearchJson> = vec![];
+    let mut groups = HashMap::<String, usize>::new();
+    for (_, json) in hits {
+        let group = if json.duplicate_of.is_empty() {
+            json.id.clone()
+        } else {
+            json.duplicate_of.clone()
+        };
+        if let Some(position) = groups.get(&group) {
+            results[*position].duplicates.push(json.url);
+        } else if results.len() < limit {
+            groups.insert(group, results.len());
+            results.push(json);
+        }
+    }
+    let suggestion = if results.is_empty() {
+        indexer::did_you_mean(&index, &query)
     } else {
-        format!("(({}) AND {})", query, "hidden:0")
+        None
     };
+    SearchResponse {
+        results,
+        suggestion,
+    }
+}
 
-    if let Ok(query) = query_parser.parse_query(&query) {
-        let top_docs = searcher
-            .search(&query, &TopDocs::with_limit(limit))
-            .expect("serach");
-        let schema = index.schema();
+// documents close to the search in meaning, scored by cosine similarity
+fn semantic_hits(
+    index: &tantivy::Index,
+    query: &str,
+    limit: usize,
+    show_hidden: bool,
+    lang: Option<&str>,
+) -> Vec<(f32, SearchJson)> {
+    let searcher = indexer::searcher(index);
+    let schema = index.schema();
+    let hidden = schema.get_field("hidden").expect("hidden");
+    vectors::nearest(&vectors::query_text(query), limit)
+        .into_iter()
+        .filter_map(|(id, similarity)| {
+            let doc = searcher.doc(indexer::find_id(&id, index)?).ok()?;
+            let is_hidden = doc.get_first(hidden).map(|v| v.i64_value()).unwrap_or(0) != 0;
+            let json = doc_to_json(&doc, &schema);
+            if (is_hidden && !show_hidden) || lang.map_or(false, |lang| json.lang != lang) {
+                None
+            } else {
+                Some((similarity, json))
+            }
+        })
+        .collect()
+}
 
-        top_docs
-            .iter()
-            .map(|doc| {
-                let retrieved_doc = searcher.doc(doc.1).expect("doc");
-                doc_to_json(&retrieved_doc, &schema)
-            })
-            .collect()
-    } else {
-        vec![]
+// reciprocal rank fusion, bm25 and cosine scores don't share a scale so only
+// the ranks in each list count
+const RRF_K: f32 = 60.0;
+
+fn fuse(
+    keyword: Vec<(f32, SearchJson)>,
+    semantic: Vec<(f32, SearchJson)>,
+) -> Vec<(f32, SearchJson)> {
+    let mut fused: Vec<(f32, SearchJson)> = vec![];
+    let mut positions = HashMap::<String, usize>::new();
+    for list in vec![keyword, semantic] {
+        for (rank, (_, json)) in list.into_iter().enumerate() {
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            match positions.get(&json.id) {
+                Some(position) => fused[*position].0 += score,
+                None => {
+                    positions.insert(json.id.clone(), fused.len());
+                    fused.push((score, json));
+                }
+            }
+        }
     }
+    fused
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    results: Vec<SearchJson>,
+    // a corrected query when nothing matched
+    suggestion: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SearchRequest {
     q: String,
     limit: Option<usize>,
+    // published for newest publication date first, otherwise by relevance
+    sort: Option<String>,
+    // only pages in this language, like en or de
+    lang: Option<String>,
+    // tolerate typos in every word, not just the ones ending in ~
+    fuzzy: Option<bool>,
+    // keyword by default, semantic for meaning only or hybrid for both
+    mode: Option<String>,
+    // or for pages with any of the facet= params, all of them by default
+    facet_op: Option<String>,
 }
 
 /// This handler uses json extractor
 async fn search_request(
+    request: HttpRequest,
     web::Query(info): web::Query<SearchRequest>,
-) -> web::Json<HashMap<String, Vec<SearchJson>>> {
-    let mut m = HashMap::new();
-    m.insert(
-        "results".to_string(),
-        search(info.q, info.limit.unwrap_or(20)),
-    );
-    web::Json(m)
+) -> web::Json<SearchResponse> {
+    queries::record(&info.q);
+    let facets = FacetFilter::from_request(&request, "facet", info.facet_op.as_deref());
+    web::Json(search(
+        info.q,
+        info.limit.unwrap_or(20),
+        info.sort,
+        info.lang,
+        info.fuzzy.unwrap_or(false),
+        info.mode,
+        facets,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SuggestRequest {
+    prefix: String,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct SuggestJson {
+    queries: Vec<String>,
+    titles: Vec<String>,
+    domains: Vec<String>,
+}
+
+// completions while typing, from earlier searches and the title and host
+// term dictionaries
+async fn suggest_request(web::Query(info): web::Query<SuggestRequest>) -> web::Json<SuggestJson> {
+    let limit = info.limit.unwrap_or(5);
+    let index = indexer::search_index().expect("could not open search index");
+    web::Json(SuggestJson {
+        queries: queries::popular(&info.prefix, limit),
+        titles: indexer::title_completions(&index, &info.prefix, limit),
+        domains: indexer::domain_completions(&index, &info.prefix, limit),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecentQueriesRequest {
+    limit: Option<usize>,
+}
+
+async fn recent_queries(
+    web::Query(info): web::Query<RecentQueriesRequest>,
+) -> web::Json<Vec<queries::QueryEntry>> {
+    web::Json(queries::recent(info.limit.unwrap_or(20)))
+}
+
+async fn clear_recent_queries() -> web::Json<Vec<queries::QueryEntry>> {
+    queries::clear_history();
+    web::Json(vec![])
+}
+
+async fn clear_query_counts() -> HttpResponse {
+    queries::clear_counts();
+    HttpResponse::Ok().finish()
+}
+
+async fn clear_query_clicks() -> HttpResponse {
+    queries::clear_clicks();
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GoRequest {
+    // the search and the position of the result in it, counting from 0
+    q: Option<String>,
+    pos: Option<usize>,
+}
+
+// opens a search result: counts the visit, remembers the click for ranking
+// and redirects to the page
+async fn go(
+    web::Path(hash): web::Path<String>,
+    web::Query(info): web::Query<GoRequest>,
+) -> HttpResponse {
+    let index = indexer::search_index().expect("could not open search index");
+    match indexer::record_visit(&hash, &index) {
+        Some(url) => {
+            if let Some(query) = &info.q {
+                queries::record_click(query, &hash, info.pos);
+            }
+            HttpResponse::Found().header("Location", url).finish()
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveSearchRequest {
+    name: String,
+    query: String,
+}
+
+async fn saved_searches() -> web::Json<Vec<queries::SavedSearch>> {
+    web::Json(queries::saved())
+}
+
+async fn save_search(info: web::Json<SaveSearchRequest>) -> web::Json<Vec<queries::SavedSearch>> {
+    web::Json(queries::save_search(&info.name, &info.query))
+}
+
+async fn delete_saved_search(
+    web::Path(name): web::Path<String>,
+) -> web::Json<Vec<queries::SavedSearch>> {
+    web::Json(queries::delete_saved_search(&name))
 }
 
 #[derive(Debug, Deserialize)]
@@ -264,8 +659,9 @@ async fn attribute_array_request(
         _ => {}
     }
 
-    if let Some(_doc_address) = indexer::find_url(&info.url, &index) {
-        let id = indexer::md5_hash(&info.url);
+    if let Some(doc_address) = indexer::find_url(&info.url, &index) {
+        let id =
+            indexer::document_id(&index, doc_address).unwrap_or_else(|| indexer::url_id(&info.url));
         let old_doc = tantivy::Term::from_field_text(
             index.schema().get_field("id").expect("domain field"),
             &id,
@@ -274,9 +670,7 @@ async fn attribute_array_request(
         let mut index_writer = index.writer(50_000_000).expect("writer");
         index_writer.delete_term(old_doc);
 
-        let url_hash = indexer::md5_hash(&info.url);
-
-        indexer::update_cached(&url_hash, &index, meta, &mut index_writer);
+        indexer::update_cached(&id, &index, meta, &mut index_writer);
         index_writer.commit().expect("commit");
         index_writer.wait_merging_threads().expect("merge");
     } else {
@@ -298,9 +692,6 @@ async fn attribute_array_request(
 }
 
 fn attribute_update(info: &AttributeRequest) -> web::Json<Option<SearchJson>> {
-    let index = indexer::search_index().expect("could not open search index");
-    let _searcher = indexer::searcher(&index);
-
     let mut meta = indexer::UrlMeta::default();
     match info.field.as_str() {
         "pinned" => {
@@ -311,8 +702,14 @@ fn attribute_update(info: &AttributeRequest) -> web::Json<Option<SearchJson>> {
         }
         _ => {}
     }
-    if let Some(_doc_address) = indexer::find_url(&info.url, &index) {
-        let id = indexer::md5_hash(&info.url);
+    update_url(&info.url, meta)
+}
+
+// applies meta to the page, importing it first when it is not indexed yet
+fn update_url(url: &str, meta: indexer::UrlMeta) -> web::Json<Option<SearchJson>> {
+    let index = indexer::search_index().expect("could not open search index");
+    if let Some(doc_address) = indexer::find_url(url, &index) {
+        let id = indexer::document_id(&index, doc_address).unwrap_or_else(|| indexer::url_id(url));
         let old_doc = tantivy::Term::from_field_text(
             index.schema().get_field("id").expect("domain field"),
             &id,
@@ -323,21 +720,23 @@ fn attribute_update(info: &AttributeRequest) -> web::Json<Option<SearchJson>> {
         //    index_writer.commit().expect("commit");
         //     index_writer.wait_merging_threads().expect("merge");
 
-        let url_hash = indexer::md5_hash(&info.url);
-
-        indexer::update_cached(&url_hash, &index, meta, &mut index_writer);
+        indexer::update_cached(&id, &index, meta, &mut index_writer);
         index_writer.commit().expect("commit");
         index_writer.wait_merging_threads().expect("merge");
     } else {
-        let url = info.url.clone();
         //tokio::spawn(lazy(move |_| {
         println!("new");
-        indexer::index_url(url, meta, Some(&index), indexer::NoAuthBlockingGetter {});
+        indexer::index_url(
+            url.to_string(),
+            meta,
+            Some(&index),
+            indexer::NoAuthBlockingGetter {},
+        );
         //}));
     }
 
     let index = indexer::search_index().expect("could not open search index");
-    if let Some(doc_address) = indexer::find_url(&info.url, &index) {
+    if let Some(doc_address) = indexer::find_url(url, &index) {
         let searcher = indexer::searcher(&index);
         let schema = index.schema();
         let retrieved_doc = searcher.doc(doc_address).expect("doc");
@@ -370,15 +769,448 @@ async fn attribute_request(
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ReadingRequest {
+    url: String,
+    status: String,
+}
+
+// GET so a bookmarklet can save the page it is on for later
+async fn reading_request(web::Query(info): web::Query<ReadingRequest>) -> HttpResponse {
+    if !indexer::READING_STATUSES.contains(&info.status.as_str()) {
+        return HttpResponse::BadRequest().body(format!(
+            "unknown status {}, use one of {}",
+            info.status,
+            indexer::READING_STATUSES.join(", ")
+        ));
+    }
+    let mut meta = indexer::UrlMeta::default();
+    meta.reading_status = Some(info.status);
+    HttpResponse::Ok().json(update_url(&info.url, meta).into_inner())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueueRequest {
+    status: Option<String>,
+    limit: Option<usize>,
+}
+
+// unread pages by the date they were added to the queue, oldest first
+async fn queue_request(web::Query(info): web::Query<QueueRequest>) -> HttpResponse {
+    let status = info.status.unwrap_or_else(|| "unread".to_string());
+    if !indexer::READING_STATUSES.contains(&status.as_str()) {
+        return HttpResponse::BadRequest().body(format!("unknown status {}", status));
+    }
+    let index = indexer::search_index().expect("could not open search index");
+    let schema = index.schema();
+    let queue = indexer::reading_queue(&index, &status, info.limit.unwrap_or(100))
+        .iter()
+        .map(|doc| doc_to_json(doc, &schema))
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(queue)
+}
+
+async fn list_collections() -> web::Json<Vec<collections::Collection>> {
+    web::Json(collections::list())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveCollectionRequest {
+    name: String,
+    #[serde(default)]
+    description: String,
+}
+
+async fn save_collection(info: web::Json<SaveCollectionRequest>) -> HttpResponse {
+    match collections::save_collection(&info.name, &info.description) {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+async fn get_collection(web::Path(name): web::Path<String>) -> HttpResponse {
+    match collections::get(&name) {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+// any of a new description, a new name and the ids in their new order
+#[derive(Debug, Deserialize)]
+pub struct UpdateCollectionRequest {
+    description: Option<String>,
+    name: Option<String>,
+    order: Option<Vec<String>>,
+}
+
+async fn update_collection(
+    web::Path(name): web::Path<String>,
+    info: web::Json<UpdateCollectionRequest>,
+) -> HttpResponse {
+    let info = info.into_inner();
+    let mut collection = match collections::get(&name) {
+        Some(collection) => collection,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    if let Some(description) = info.description {
+        collection = collections::save_collection(&name, &description).expect("named collection");
+    }
+    if let Some(order) = info.order {
+        collection = collections::reorder(&name, &order).unwrap_or(collection);
+    }
+    if let Some(new_name) = info.name {
+        collection = match collections::rename_collection(&name, &new_name) {
+            Ok(collection) => collection,
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        };
+    }
+    HttpResponse::Ok().json(collection)
+}
+
+async fn delete_collection(web::Path(name): web::Path<String>) -> HttpResponse {
+    if collections::delete_collection(&name) {
+        HttpResponse::Ok().json(collections::list())
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CollectionItemRequest {
+    id: String,
+    note: Option<String>,
+    // from 0, the end when left out
+    position: Option<usize>,
+}
+
+async fn put_collection_item(
+    web::Path(name): web::Path<String>,
+    info: web::Json<CollectionItemRequest>,
+) -> HttpResponse {
+    let info = info.into_inner();
+    if !indexer::source_exists(&info.id) {
+        return HttpResponse::BadRequest().body(format!("no page {}", info.id));
+    }
+    match collections::put_item(&name, &info.id, info.note, info.position) {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn remove_collection_item(
+    web::Path((name, id)): web::Path<(String, String)>,
+) -> HttpResponse {
+    match collections::remove_item(&name, &id) {
+        Some(collection) => HttpResponse::Ok().json(collection),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CollectionSearchRequest {
+    q: Option<String>,
+    lang: Option<String>,
+    fuzzy: Option<bool>,
+}
+
+// the pages of a collection that match a search, best first. without a
+// search all of them in the collection order
+async fn search_collection(
+    web::Path(name): web::Path<String>,
+    web::Query(info): web::Query<CollectionSearchRequest>,
+) -> HttpResponse {
+    let collection = match collections::get(&name) {
+        Some(collection) => collection,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let index = indexer::search_index().expect("could not open search index");
+    let schema = index.schema();
+    let searcher = indexer::searcher(&index);
+    let q = info.q.unwrap_or_default();
+    if q.trim().is_empty() {
+        let results = collection
+            .items
+            .iter()
+            .filter_map(|item| indexer::find_id(&item.id, &index))
+            .filter_map(|address| searcher.doc(address).ok())
+            .map(|doc| doc_to_json(&doc, &schema))
+            .collect::<Vec<_>>();
+        return HttpResponse::Ok().json(results);
+    }
+    let query = match search_query(
+        &index,
+        &q,
+        info.lang.as_deref(),
+        info.fuzzy.unwrap_or(false),
+        &FacetFilter::default(),
+    ) {
+        Ok(query) => query,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{:?}", e)),
+    };
+    let id_field = schema.get_field("id").expect("id");
+    let ids = collection
+        .items
+        .iter()
+        .map(|item| {
+            let query: Box<dyn Query> = Box::new(TermQuery::new(
+                Term::from_field_text(id_field, &item.id),
+                IndexRecordOption::Basic,
+            ));
+            (Occur::Should, query)
+        })
+        .collect::<Vec<_>>();
+    if ids.is_empty() {
+        return HttpResponse::Ok().json(Vec::<SearchJson>::new());
+    }
+    let query = BooleanQuery::from(vec![
+        (Occur::Must, query),
+        (
+            Occur::Must,
+            Box::new(BooleanQuery::from(ids)) as Box<dyn Query>,
+        ),
+    ]);
+    let results = searcher
+        .search(&query, &TopDocs::with_limit(collection.items.len()))
+        .expect("search")
+        .into_iter()
+        .filter_map(|(_, address)| searcher.doc(address).ok())
+        .map(|doc| doc_to_json(&doc, &schema))
+        .collect::<Vec<_>>();
+    HttpResponse::Ok().json(results)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CollectionExportRequest {
+    format: Option<String>,
+}
+
+async fn export_collection(
+    web::Path(name): web::Path<String>,
+    web::Query(info): web::Query<CollectionExportRequest>,
+) -> HttpResponse {
+    let format = info.format.unwrap_or_else(|| "html".to_string());
+    if collections::get(&name).is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+    match collections::export(&name, &format) {
+        Ok(page) if format == "html" => HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(page),
+        Ok(page) => HttpResponse::Ok()
+            .content_type("text/markdown; charset=utf-8")
+            .body(page),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportRequest {
+    q: String,
+    format: Option<String>,
+    lang: Option<String>,
+    fuzzy: Option<bool>,
+    limit: Option<usize>,
+    facet_op: Option<String>,
+}
+
+// search results as markdown, html, csv or json, narrowed by facet= like /search
+async fn export_request(
+    request: HttpRequest,
+    web::Query(info): web::Query<ExportRequest>,
+) -> HttpResponse {
+    let format = info.format.unwrap_or_else(|| "md".to_string());
+    let facets = FacetFilter::from_request(&request, "facet", info.facet_op.as_deref());
+    let index = indexer::search_index().expect("could not open search index");
+    let query = match search_query(
+        &index,
+        &info.q,
+        info.lang.as_deref(),
+        info.fuzzy.unwrap_or(false),
+        &facets,
+    ) {
+        Ok(query) => query,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{:?}", e)),
+    };
+    let rows = export::rows(&index, query.as_ref(), info.limit.unwrap_or(100));
+    match export::render(&format!("Search: {}", info.q), &rows, &format) {
+        Ok(page) => HttpResponse::Ok()
+            .content_type(export::content_type(&format))
+            .body(page),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FacetRequest {
     facet: String,
     facet_field: Option<String>,
+    // count only the pages this search finds, narrowed by filter= facets
+    q: Option<String>,
+    lang: Option<String>,
+    fuzzy: Option<bool>,
+    filter_op: Option<String>,
 }
 
-async fn facet_request(web::Query(info): web::Query<FacetRequest>) -> web::Json<Vec<FacetCount>> {
+async fn facet_request(
+    request: HttpRequest,
+    web::Query(info): web::Query<FacetRequest>,
+) -> HttpResponse {
     let field = info.facet_field.unwrap_or_else(|| "tags".to_string());
-    web::Json(facets(info.facet, field))
+    let filters = FacetFilter::from_request(&request, "filter", info.filter_op.as_deref());
+    let q = info.q.unwrap_or_default();
+    let filter = if q.trim().is_empty() && filters.facets.is_empty() {
+        None
+    } else {
+        let index = indexer::search_index().expect("could not open search index");
+        let lang = info.lang.as_deref().filter(|lang| !lang.is_empty());
+        match search_query(&index, &q, lang, info.fuzzy.unwrap_or(false), &filters) {
+            Ok(filter) => Some(filter),
+            Err(e) => return HttpResponse::BadRequest().body(format!("{:?}", e)),
+        }
+    };
+    HttpResponse::Ok().json(facets(info.facet, field, filter))
+}
+
+async fn notes(web::Path(hash): web::Path<String>) -> HttpResponse {
+    match indexer::read_notes(&hash) {
+        Some(notes) => HttpResponse::Ok().json(notes),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotesRequest {
+    // replaces the note, left alone when missing
+    notes: Option<String>,
+    #[serde(default)]
+    highlights_add: Vec<String>,
+    #[serde(default)]
+    highlights_remove: Vec<String>,
+}
+
+async fn update_notes(
+    web::Path(hash): web::Path<String>,
+    info: web::Json<NotesRequest>,
+) -> HttpResponse {
+    let index = indexer::search_index().expect("could not open search index");
+    let info = info.into_inner();
+    let highlights_add = info
+        .highlights_add
+        .into_iter()
+        .map(|highlight| highlight.trim().to_string())
+        .filter(|highlight| !highlight.is_empty())
+        .collect();
+    match indexer::update_notes(
+        &hash,
+        &index,
+        info.notes,
+        highlights_add,
+        info.highlights_remove,
+    ) {
+        Some(notes) => HttpResponse::Ok().json(notes),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkRequest {
+    // the documents are either listed or everything a search finds
+    ids: Option<Vec<String>>,
+    q: Option<String>,
+    lang: Option<String>,
+    fuzzy: Option<bool>,
+    #[serde(default)]
+    facets: Vec<String>,
+    facet_op: Option<String>,
+    // tag, untag, pin, unpin, hide, unhide, delete or refetch
+    action: String,
+    // the tag for tag and untag
+    value: Option<String>,
+}
+
+async fn bulk_request(info: web::Json<BulkRequest>) -> HttpResponse {
+    let index = indexer::search_index().expect("could not open search index");
+    let ids = match (&info.ids, &info.q) {
+        (Some(ids), _) => ids.clone(),
+        (None, Some(q)) if !q.trim().is_empty() => {
+            let lang = info.lang.as_deref().filter(|lang| !lang.is_empty());
+            let facets = FacetFilter {
+                facets: info.facets.iter().map(|f| tagging::tag_facet(f)).collect(),
+                any: info.facet_op.as_deref() == Some("or"),
+            };
+            let parsed = match search_query(&index, q, lang, info.fuzzy.unwrap_or(false), &facets) {
+                Ok(parsed) => parsed,
+                Err(e) => return HttpResponse::BadRequest().body(format!("{:?}", e)),
+            };
+            let searcher = indexer::searcher(&index);
+            let id_field = index.schema().get_field("id").expect("id");
+            let limit = (searcher.num_docs() as usize).max(1);
+            searcher
+                .search(&parsed, &TopDocs::with_limit(limit))
+                .expect("search")
+                .into_iter()
+                .filter_map(|(_, address)| {
+                    let doc = searcher.doc(address).ok()?;
+                    Some(doc.get_first(id_field)?.text()?.to_string())
+                })
+                .collect()
+        }
+        _ => return HttpResponse::BadRequest().body("ids or q are needed"),
+    };
+    match bulk::run(&index, &ids, &info.action, info.value.as_deref()) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsRequest {
+    prefix: Option<String>,
+}
+
+async fn tags_request(web::Query(info): web::Query<TagsRequest>) -> web::Json<Vec<FacetCount>> {
+    let prefix = info.prefix.unwrap_or_else(|| "/".to_string());
+    web::Json(
+        tagging::list_tags(&prefix)
+            .into_iter()
+            .map(|(name, count)| FacetCount { name, count })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagChangeRequest {
+    // one tag for a rename, any number to merge
+    from: Vec<String>,
+    to: String,
+    #[serde(default)]
+    subtree: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagDeleteRequest {
+    tag: String,
+    subtree: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct TagChangeJson {
+    documents: usize,
+}
+
+async fn rename_tags(info: web::Json<TagChangeRequest>) -> HttpResponse {
+    if info.from.is_empty() || info.to.trim().is_empty() {
+        return HttpResponse::BadRequest().body("from and to are needed");
+    }
+    HttpResponse::Ok().json(TagChangeJson {
+        documents: tagging::rewrite_tags(&info.from, Some(&info.to), info.subtree),
+    })
+}
+
+async fn delete_tag(web::Query(info): web::Query<TagDeleteRequest>) -> web::Json<TagChangeJson> {
+    web::Json(TagChangeJson {
+        documents: tagging::rewrite_tags(&[info.tag], None, info.subtree.unwrap_or(false)),
+    })
 }
 
 #[derive(Serialize, Debug, Deserialize, Default)]
@@ -387,6 +1219,7 @@ pub struct UpdateSystemSettings {
     ignore_domains: Option<Vec<String>>,
     ignore_strings: Option<Vec<String>>,
     indexer_enabled: Option<bool>,
+    record_queries: Option<bool>,
 }
 async fn update_settings(
     info: web::Json<UpdateSystemSettings>,
@@ -399,6 +1232,9 @@ async fn update_settings(
     if let Some(enabled) = &info.indexer_enabled {
         settings.indexer_enabled = *enabled;
     }
+    if let Some(record_queries) = &info.record_queries {
+        settings.record_queries = *record_queries;
+    }
     if let Some(ignore_domains) = &info.ignore_domains {
         settings.ignore_domains = ignore_domains.clone();
     }
@@ -407,6 +1243,7 @@ async fn update_settings(
         settings.ignore_strings = ignore_strings.clone();
     }
     indexer::write_settings(&settings);
+    queries::set_recording(settings.record_queries);
     web::Json(settings)
 }
 
@@ -431,15 +1268,23 @@ fn static_assets() -> actix_web::Resource {
     web::resource("/{filename:.*}").route(web::get().to(filesystem))
 }
 
-async fn view(web::Path(hash): web::Path<String>) -> Result<HttpResponse> {
+#[derive(Debug, Deserialize)]
+pub struct ViewRequest {
+    version: Option<i64>,
+}
+
+async fn view(
+    web::Path(hash): web::Path<String>,
+    web::Query(info): web::Query<ViewRequest>,
+) -> Result<HttpResponse> {
     let hash = if hash.contains("://") {
-        indexer::md5_hash(&hash)
+        indexer::url_id(&hash)
     } else {
         hash
     };
 
     let mut body = format!("<div id='content'>url hash {} is not found</div>", hash);
-    if let Some(json_string) = indexer::read_source(&hash) {
+    if let Some(json_string) = indexer::read_version_or_source(&hash, info.version) {
         let json: Result<serde_json::Value, _> = serde_json::from_str(&json_string);
         if let Ok(json) = json {
             if let Some(content) = json.get("content_raw") {
@@ -467,6 +1312,67 @@ async fn view(web::Path(hash): web::Path<String>) -> Result<HttpResponse> {
         .body(body))
 }
 
+#[derive(Serialize, Debug)]
+struct VersionJson {
+    version: i64,
+    fetched_at: String,
+    current: bool,
+}
+
+async fn versions_request(web::Path(hash): web::Path<String>) -> web::Json<Vec<VersionJson>> {
+    let version_json = |version: i64, current: bool| VersionJson {
+        version,
+        fetched_at: chrono::Utc.timestamp(version, 0).to_rfc3339(),
+        current,
+    };
+    let mut versions: Vec<VersionJson> = indexer::versions(&hash)
+        .into_iter()
+        .map(|version| version_json(version, false))
+        .collect();
+    if let Some(json_string) = indexer::read_source(&hash) {
+        let json: serde_json::Value = serde_json::from_str(&json_string).unwrap_or_default();
+        if let Some(fetched_at) = json["fetched_at_i"][0]
+            .as_i64()
+            .or_else(|| json["added_at_i"][0].as_i64())
+        {
+            versions.push(version_json(fetched_at, true));
+        }
+    }
+    web::Json(versions)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffRequest {
+    from: i64,
+    to: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct DiffJson {
+    from: i64,
+    to: Option<i64>,
+    chunks: Vec<indexer::DiffChunk>,
+}
+
+async fn diff_request(
+    web::Path(hash): web::Path<String>,
+    web::Query(info): web::Query<DiffRequest>,
+) -> web::Json<Option<DiffJson>> {
+    let text = |version: Option<i64>| {
+        indexer::read_version_or_source(&hash, version)
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .map(|json| indexer::source_text(&json))
+    };
+    match (text(Some(info.from)), text(info.to)) {
+        (Some(old), Some(new)) => web::Json(Some(DiffJson {
+            from: info.from,
+            to: info.to,
+            chunks: indexer::diff_text(&old, &new),
+        })),
+        _ => web::Json(None),
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let opt = Opt::from_args();
@@ -474,8 +1380,29 @@ async fn main() -> std::io::Result<()> {
         std::env::set_var("RUST_LOG", "actix_web=debug");
         env_logger::init();
     }
+    // unlock before anything reads the settings or sources
+    if indexer::encryption_enabled() {
+        if let Err(e) = indexer::unlock(&indexer::encryption_secret("passphrase: ")) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
     let port = opt.port.unwrap_or_else(|| indexer::read_settings().port);
     let server_port = port.clone();
+    indexer::spawn_visit_flusher();
+    // pages indexed while no summarization model was loaded still have the
+    // start of their text as a summary
+    std::thread::spawn(|| {
+        let count = indexer::summarize_truncated();
+        if count > 0 {
+            println!("summarized {}", count);
+        }
+    });
+    // searches and clicks are kept in memory and written out from here
+    std::thread::spawn(|| loop {
+        std::thread::sleep(std::time::Duration::from_secs(queries::FLUSH_SECONDS));
+        queries::flush();
+    });
     HttpServer::new(move || {
         App::new()
             .wrap(
@@ -484,7 +1411,7 @@ async fn main() -> std::io::Result<()> {
                     //.allowed_origin("http://localhost")
                     //.allowed_origin(&format!("http://localhost:{}", &port.clone()))
                     .max_age(3600)
-                    .allowed_methods(vec!["GET", "POST", "PUT"])
+                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
                     .finish(),
             )
             // enable logger
@@ -495,11 +1422,58 @@ async fn main() -> std::io::Result<()> {
                     .route(web::get().to(search_request))
                     .route(web::head().to(HttpResponse::MethodNotAllowed)),
             )
+            .service(
+                web::resource("/suggest")
+                    .route(web::get().to(suggest_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/queries/recent")
+                    .route(web::get().to(recent_queries))
+                    .route(web::delete().to(clear_recent_queries))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/queries/counts")
+                    .route(web::delete().to(clear_query_counts))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/queries/clicks")
+                    .route(web::delete().to(clear_query_clicks))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/go/{hash}")
+                    .route(web::get().to(go))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/queries/saved")
+                    .route(web::get().to(saved_searches))
+                    .route(web::post().to(save_search))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/queries/saved/{name}")
+                    .route(web::delete().to(delete_saved_search))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
             .service(
                 web::resource("/view/{hash}")
                     .route(web::get().to(view))
                     .route(web::head().to(HttpResponse::MethodNotAllowed)),
             )
+            .service(
+                web::resource("/versions/{hash}")
+                    .route(web::get().to(versions_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/versions/{hash}/diff")
+                    .route(web::get().to(diff_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
             .service(
                 web::resource("/settings")
                     .route(web::get().to(get_settings))
@@ -516,6 +1490,85 @@ async fn main() -> std::io::Result<()> {
                 //yes i know it should be a post i dont care
                 web::resource("/attributes_array").route(web::get().to(attribute_array_request)),
             )
+            .service(
+                web::resource("/reading")
+                    .route(web::get().to(reading_request))
+                    .route(web::post().to(reading_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/queue")
+                    .route(web::get().to(queue_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/export")
+                    .route(web::get().to(export_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/collections")
+                    .route(web::get().to(list_collections))
+                    .route(web::post().to(save_collection))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/collections/{name}")
+                    .route(web::get().to(get_collection))
+                    .route(web::post().to(update_collection))
+                    .route(web::delete().to(delete_collection))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/collections/{name}/items")
+                    .route(web::post().to(put_collection_item))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/collections/{name}/items/{id}")
+                    .route(web::delete().to(remove_collection_item))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/collections/{name}/search")
+                    .route(web::get().to(search_collection))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/collections/{name}/export")
+                    .route(web::get().to(export_collection))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/notes/{hash}")
+                    .app_data(web::JsonConfig::default().limit(1_000_000))
+                    .route(web::get().to(notes))
+                    .route(web::post().to(update_notes))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/bulk")
+                    // a list of ids is bigger than the default limit
+                    .app_data(web::JsonConfig::default().limit(1_000_000))
+                    .route(web::post().to(bulk_request))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/tags")
+                    .route(web::get().to(tags_request))
+                    .route(web::delete().to(delete_tag))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/tags/rename")
+                    .route(web::post().to(rename_tags))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
+            .service(
+                web::resource("/tags/merge")
+                    .route(web::post().to(rename_tags))
+                    .route(web::head().to(HttpResponse::MethodNotAllowed)),
+            )
             .service(
                 web::resource("/facets")
                     .route(web::get().to(facet_request))
@@ -525,5 +1578,9 @@ async fn main() -> std::io::Result<()> {
     })
     .bind(&format!("127.0.0.1:{}", server_port))?
     .run()
-    .await
+    .await?;
+    // visits opened and searches made since the last flush
+    indexer::flush_visits(&indexer::search_index().expect("could not open search index"));
+    queries::flush();
+    Ok(())
 }
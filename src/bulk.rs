@@ -0,0 +1,147 @@
+use crate::indexer;
+use crate::tagging;
+use serde::Serialize;
+use std::collections::HashMap;
+use tantivy::schema::*;
+use tantivy::Index;
+
+#[derive(Serialize, Debug, Default)]
+pub struct BulkReport {
+    // documents the action was asked for
+    pub documents: usize,
+    pub changed: usize,
+    // ids without a source, or refetches that got nothing new
+    pub skipped: usize,
+}
+
+pub const ACTIONS: [&str; 8] = [
+    "tag", "untag", "pin", "unpin", "hide", "unhide", "delete", "refetch",
+];
+
+type Patch = Box<dyn Fn(&mut serde_json::Value)>;
+
+// the change made to the source of each document, None for actions that
+// replace it
+fn patch_for(action: &str, value: Option<&str>) -> Result<Option<Patch>, String> {
+    let tag = || match value.map(str::trim) {
+        Some(tag) if !tag.is_empty() => Ok(serde_json::json!(tagging::tag_facet(tag))),
+        _ => Err(format!("{} needs a tag", action)),
+    };
+    let set = |key: &'static str, value: i64| -> Patch {
+        Box::new(move |json| json[key] = serde_json::json!([value]))
+    };
+    let patch: Patch = match action {
+        "tag" => {
+            let tag = tag()?;
+            Box::new(move |json| {
+                let mut tags = json["tags"].as_array().cloned().unwrap_or_default();
+                if !tags.contains(&tag) {
+                    tags.push(tag.clone());
+                }
+                json["tags"] = serde_json::Value::Array(tags);
+            })
+        }
+        "untag" => {
+            let tag = tag()?;
+            Box::new(move |json| {
+                if let Some(tags) = json["tags"].as_array_mut() {
+                    tags.retain(|value| value != &tag);
+                }
+            })
+        }
+        "pin" => set("pinned", 1),
+        "unpin" => set("pinned", 0),
+        "hide" => set("hidden", 1),
+        "unhide" => set("hidden", 0),
+        "delete" | "refetch" => return Ok(None),
+        _ => {
+            return Err(format!(
+                "unknown action {}, use one of {}",
+                action,
+                ACTIONS.join(", ")
+            ))
+        }
+    };
+    Ok(Some(patch))
+}
+
+// runs one action over the documents with a single writer and one commit.
+// pages are refetched before the writer is taken and deleted documents lose
+// their sources only once the commit went through
+pub fn run(
+    index: &Index,
+    ids: &[String],
+    action: &str,
+    value: Option<&str>,
+) -> Result<BulkReport, String> {
+    let patch = patch_for(action, value)?;
+    let schema = index.schema();
+    let id_field = schema.get_field("id").expect("id");
+    let mut report = BulkReport {
+        documents: ids.len(),
+        ..BulkReport::default()
+    };
+    let ids = ids
+        .iter()
+        .filter(|id| indexer::source_exists(id))
+        .cloned()
+        .collect::<Vec<_>>();
+    report.skipped = report.documents - ids.len();
+
+    let mut refetched = HashMap::new();
+    if action == "refetch" {
+        for id in ids.iter() {
+            let url = indexer::read_source(id)
+                .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+                .and_then(|json| json["url"][0].as_str().map(str::to_string));
+            if let Some(refetch) = url.and_then(|url| {
+                indexer::refetch_document(&url, index, indexer::NoAuthBlockingGetter {})
+            }) {
+                refetched.insert(id.clone(), refetch);
+            }
+        }
+    }
+
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    for id in ids.iter() {
+        let (doc, changed) = match (action, &patch) {
+            ("delete", _) => {
+                index_writer.delete_term(Term::from_field_text(id_field, id));
+                report.changed += 1;
+                continue;
+            }
+            ("refetch", _) => match refetched.remove(id) {
+                // kept even when nothing changed for the new fetch time
+                Some((doc, changed)) => {
+                    indexer::write_source(id, schema.to_json(&doc));
+                    (doc, changed)
+                }
+                None => {
+                    report.skipped += 1;
+                    continue;
+                }
+            },
+            (_, Some(patch)) => match indexer::patch_source(id, index, |json| patch(json)) {
+                Some(doc) => (doc, true),
+                None => {
+                    report.skipped += 1;
+                    continue;
+                }
+            },
+            _ => continue,
+        };
+        index_writer.delete_term(Term::from_field_text(id_field, id));
+        index_writer.add_document(doc);
+        if changed {
+            report.changed += 1;
+        } else {
+            report.skipped += 1;
+        }
+    }
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+    if action == "delete" {
+        indexer::remove_document_files(&ids);
+    }
+    Ok(report)
+}
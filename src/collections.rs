@@ -0,0 +1,277 @@
+use crate::export::{escape_html, escape_markdown};
+use crate::indexer;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct CollectionItem {
+    // document id
+    pub id: String,
+    pub note: String,
+    pub added_at: i64,
+}
+
+// a curated list of pages, in the order they were arranged
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Collection {
+    pub name: String,
+    pub description: String,
+    pub items: Vec<CollectionItem>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+struct CollectionStore {
+    collections: Vec<Collection>,
+}
+
+lazy_static::lazy_static! {
+    // loaded on first use and written back on every change
+    static ref COLLECTIONS: RwLock<Option<CollectionStore>> = RwLock::new(None);
+}
+
+fn collections_path() -> PathBuf {
+    Path::new(indexer::BASE_INDEX_DIR.as_str()).join("collections.json")
+}
+
+fn load() -> CollectionStore {
+    fs::read(collections_path())
+        .ok()
+        .and_then(|bytes| indexer::unseal(bytes).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+fn save(store: &CollectionStore) {
+    let json = serde_json::to_vec(store).expect("collections json");
+    fs::write(collections_path(), indexer::seal(json)).expect("write collections");
+}
+
+fn with_store<T>(f: impl FnOnce(&mut CollectionStore) -> T) -> T {
+    let mut guard = COLLECTIONS.write().expect("collections lock");
+    f(guard.get_or_insert_with(load))
+}
+
+// changes one collection and saves, None when there is no such collection
+fn with_collection<T>(name: &str, f: impl FnOnce(&mut Collection) -> T) -> Option<T> {
+    with_store(|store| {
+        let collection = store.collections.iter_mut().find(|c| c.name == name)?;
+        let result = f(collection);
+        collection.updated_at = Utc::now().timestamp();
+        save(store);
+        Some(result)
+    })
+}
+
+pub fn list() -> Vec<Collection> {
+    with_store(|store| store.collections.clone())
+}
+
+pub fn get(name: &str) -> Option<Collection> {
+    with_store(|store| store.collections.iter().find(|c| c.name == name).cloned())
+}
+
+// creates the collection or changes its description, the items are kept
+pub fn save_collection(name: &str, description: &str) -> Result<Collection, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("a collection needs a name".to_string());
+    }
+    let now = Utc::now().timestamp();
+    Ok(with_store(|store| {
+        let index = match store.collections.iter().position(|c| c.name == name) {
+            Some(index) => index,
+            None => {
+                store.collections.push(Collection {
+                    name,
+                    created_at: now,
+                    ..Collection::default()
+                });
+                store.collections.len() - 1
+            }
+        };
+        let collection = &mut store.collections[index];
+        collection.description = description.to_string();
+        collection.updated_at = now;
+        let collection = collection.clone();
+        save(store);
+        collection
+    }))
+}
+
+pub fn rename_collection(name: &str, new_name: &str) -> Result<Collection, String> {
+    let new_name = new_name.trim();
+    if new_name.is_empty() {
+        return Err("a collection needs a name".to_string());
+    }
+    if new_name != name && get(new_name).is_some() {
+        return Err(format!("there is already a collection {}", new_name));
+    }
+    with_collection(name, |collection| {
+        collection.name = new_name.to_string();
+        collection.clone()
+    })
+    .ok_or_else(|| format!("no collection {}", name))
+}
+
+pub fn delete_collection(name: &str) -> bool {
+    with_store(|store| {
+        let count = store.collections.len();
+        store.collections.retain(|c| c.name != name);
+        save(store);
+        store.collections.len() != count
+    })
+}
+
+// adds the page at position, or at the end. a page already in the collection
+// moves there and keeps its note unless a new one is given
+pub fn put_item(
+    name: &str,
+    id: &str,
+    note: Option<String>,
+    position: Option<usize>,
+) -> Option<Collection> {
+    with_collection(name, |collection| {
+        let mut item = match collection.items.iter().position(|item| item.id == id) {
+            Some(index) => collection.items.remove(index),
+            None => CollectionItem {
+                id: id.to_string(),
+                note: String::new(),
+                added_at: Utc::now().timestamp(),
+            },
+        };
+        if let Some(note) = note {
+            item.note = note;
+        }
+        let position = position
+            .unwrap_or_else(|| collection.items.len())
+            .min(collection.items.len());
+        collection.items.insert(position, item);
+        collection.clone()
+    })
+}
+
+pub fn remove_item(name: &str, id: &str) -> Option<Collection> {
+    with_collection(name, |collection| {
+        collection.items.retain(|item| item.id != id);
+        collection.clone()
+    })
+}
+
+// reorders the items to follow ids. items left out keep their order after them
+pub fn reorder(name: &str, ids: &[String]) -> Option<Collection> {
+    with_collection(name, |collection| {
+        let mut items = vec![];
+        for id in ids {
+            if let Some(index) = collection.items.iter().position(|item| &item.id == id) {
+                items.push(collection.items.remove(index));
+            }
+        }
+        items.append(&mut collection.items);
+        collection.items = items;
+        collection.clone()
+    })
+}
+
+// a page of the collection as exported, from its cached source
+struct ExportItem {
+    title: String,
+    url: String,
+    summary: String,
+    note: String,
+}
+
+fn export_items(collection: &Collection) -> Vec<ExportItem> {
+    collection
+        .items
+        .iter()
+        .filter_map(|item| {
+            let json: serde_json::Value =
+                serde_json::from_str(&indexer::read_source(&item.id)?).ok()?;
+            let text = |name: &str| json[name][0].as_str().unwrap_or("").trim().to_string();
+            let url = text("url");
+            let title = text("title");
+            Some(ExportItem {
+                title: if title.is_empty() { url.clone() } else { title },
+                url,
+                summary: text("summary"),
+                note: item.note.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn to_markdown(collection: &Collection) -> String {
+    let mut markdown = format!("# {}\n\n", collection.name);
+    if !collection.description.trim().is_empty() {
+        markdown.push_str(&format!("{}\n\n", collection.description.trim()));
+    }
+    for (number, item) in export_items(collection).iter().enumerate() {
+        markdown.push_str(&format!(
+            "{}. [{}](<{}>)\n",
+            number + 1,
+            escape_markdown(&item.title),
+            item.url
+        ));
+        for text in &[&item.note, &item.summary] {
+            if !text.is_empty() {
+                markdown.push_str(&format!("   {}\n", text.replace('\n', "\n   ")));
+            }
+        }
+        markdown.push('\n');
+    }
+    markdown
+}
+
+// a static page that can be shared without the server
+pub fn to_html(collection: &Collection) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n",
+        escape_html(&collection.name)
+    );
+    if !collection.description.trim().is_empty() {
+        html.push_str(&format!(
+            "<p>{}</p>\n",
+            escape_html(collection.description.trim())
+        ));
+    }
+    html.push_str("<ol>\n");
+    for item in export_items(collection) {
+        html.push_str(&format!(
+            "<li>\n<a href=\"{}\">{}</a>\n",
+            escape_html(&item.url),
+            escape_html(&item.title)
+        ));
+        if !item.note.is_empty() {
+            html.push_str(&format!(
+                "<p class=\"note\">{}</p>\n",
+                escape_html(&item.note)
+            ));
+        }
+        if !item.summary.is_empty() {
+            html.push_str(&format!(
+                "<p class=\"summary\">{}</p>\n",
+                escape_html(&item.summary)
+            ));
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str("</ol>\n</body>\n</html>\n");
+    html
+}
+
+pub fn export(name: &str, format: &str) -> Result<String, String> {
+    let collection = get(name).ok_or_else(|| format!("no collection {}", name))?;
+    match format {
+        "md" | "markdown" => Ok(to_markdown(&collection)),
+        "html" => Ok(to_html(&collection)),
+        _ => Err(format!("unknown format {}, use md or html", format)),
+    }
+}
@@ -0,0 +1,274 @@
+use crate::indexer;
+use chrono::prelude::*;
+use serde::Serialize;
+use tantivy::collector::TopDocs;
+use tantivy::query::Query;
+use tantivy::schema::*;
+use tantivy::Index;
+
+pub const FORMATS: [&str; 4] = ["md", "html", "csv", "json"];
+
+// a search result as it is exported
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ExportRow {
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    pub tags: Vec<String>,
+    pub summary: String,
+    // first and last visit as rfc3339
+    pub added_at: String,
+    pub last_accessed_at: String,
+}
+
+fn row(doc: &Document, schema: &Schema) -> ExportRow {
+    let text = |name: &str| {
+        doc.get_first(schema.get_field(name).expect(name))
+            .and_then(|value| value.text())
+            .unwrap_or("")
+            .trim()
+            .to_string()
+    };
+    let date = |name: &str| {
+        doc.get_first(schema.get_field(name).expect(name))
+            .map(|value| Utc.timestamp(value.i64_value(), 0).to_rfc3339())
+            .unwrap_or_default()
+    };
+    let tags = doc
+        .get_all(schema.get_field("tags").expect("tags"))
+        .iter()
+        .filter_map(|value| match value {
+            Value::Facet(facet) => Some(facet.to_path_string()),
+            _ => None,
+        })
+        .collect();
+    ExportRow {
+        id: text("id"),
+        title: text("title"),
+        url: text("url"),
+        tags,
+        summary: text("summary"),
+        added_at: date("added_at_i"),
+        last_accessed_at: date("last_accessed_at_i"),
+    }
+}
+
+// the best matches of a query, best first
+pub fn rows(index: &Index, query: &dyn Query, limit: usize) -> Vec<ExportRow> {
+    let schema = index.schema();
+    let searcher = indexer::searcher(index);
+    searcher
+        .search(query, &TopDocs::with_limit(limit.max(1)))
+        .expect("search")
+        .into_iter()
+        .filter_map(|(_, address)| searcher.doc(address).ok())
+        .map(|doc| row(&doc, &schema))
+        .collect()
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn escape_markdown(text: &str) -> String {
+    text.replace('[', "\\[").replace(']', "\\]")
+}
+
+fn escape_csv(text: &str) -> String {
+    if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// the date part of a rfc3339 timestamp
+pub fn day(timestamp: &str) -> &str {
+    timestamp.get(0..10).unwrap_or(timestamp)
+}
+
+pub fn title_or_url(row: &ExportRow) -> &str {
+    if row.title.is_empty() {
+        &row.url
+    } else {
+        &row.title
+    }
+}
+
+fn to_markdown(heading: &str, rows: &[ExportRow]) -> String {
+    let mut markdown = format!("# {}\n\n", heading);
+    for row in rows {
+        markdown.push_str(&format!(
+            "- [{}](<{}>)\n",
+            escape_markdown(title_or_url(row)),
+            row.url
+        ));
+        markdown.push_str(&format!(
+            "  first visit {}, last visit {}\n",
+            day(&row.added_at),
+            day(&row.last_accessed_at)
+        ));
+        if !row.tags.is_empty() {
+            let tags = row
+                .tags
+                .iter()
+                .map(|tag| format!("`{}`", tag))
+                .collect::<Vec<_>>();
+            markdown.push_str(&format!("  {}\n", tags.join(" ")));
+        }
+        if !row.summary.is_empty() {
+            markdown.push_str(&format!("  {}\n", row.summary.replace('\n', "\n  ")));
+        }
+    }
+    markdown
+}
+
+fn to_html(heading: &str, rows: &[ExportRow]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n</head>\n<body>\n<h1>{0}</h1>\n<ul>\n",
+        escape_html(heading)
+    );
+    for row in rows {
+        html.push_str(&format!(
+            "<li>\n<a href=\"{}\">{}</a>\n<p class=\"visits\">first visit {}, last visit {}</p>\n",
+            escape_html(&row.url),
+            escape_html(title_or_url(row)),
+            day(&row.added_at),
+            day(&row.last_accessed_at)
+        ));
+        if !row.tags.is_empty() {
+            html.push_str(&format!(
+                "<p class=\"tags\">{}</p>\n",
+                escape_html(&row.tags.join(" "))
+            ));
+        }
+        if !row.summary.is_empty() {
+            html.push_str(&format!(
+                "<p class=\"summary\">{}</p>\n",
+                escape_html(&row.summary)
+            ));
+        }
+        html.push_str("</li>\n");
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+fn to_csv(rows: &[ExportRow]) -> String {
+    let mut csv = "id,title,url,tags,summary,added_at,last_accessed_at\n".to_string();
+    for row in rows {
+        let fields = vec![
+            row.id.clone(),
+            row.title.clone(),
+            row.url.clone(),
+            row.tags.join(" "),
+            row.summary.clone(),
+            row.added_at.clone(),
+            row.last_accessed_at.clone(),
+        ];
+        let fields = fields
+            .iter()
+            .map(|field| escape_csv(field))
+            .collect::<Vec<_>>();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+pub fn content_type(format: &str) -> &'static str {
+    match format {
+        "html" => "text/html; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "json" => "application/json",
+        _ => "text/markdown; charset=utf-8",
+    }
+}
+
+pub fn render(heading: &str, rows: &[ExportRow], format: &str) -> Result<String, String> {
+    match format {
+        "md" | "markdown" => Ok(to_markdown(heading, rows)),
+        "html" => Ok(to_html(heading, rows)),
+        "csv" => Ok(to_csv(rows)),
+        "json" => Ok(format!(
+            "{}\n",
+            serde_json::to_string_pretty(rows).expect("export json")
+        )),
+        _ => Err(format!(
+            "unknown format {}, use one of {}",
+            format,
+            FORMATS.join(", ")
+        )),
+    }
+}
+
+// renders the keyword matches of a search by relevance only. unlike /search there
+// are no facets, pins, click boosts or semantic ranking, and hidden pages are
+// left out unless the query names hidden:
+pub fn export_query(
+    index: &Index,
+    query: &str,
+    fuzzy: bool,
+    limit: usize,
+    format: &str,
+) -> Result<String, String> {
+    let parsed =
+        indexer::search_query(index, query, None, fuzzy).map_err(|e| format!("{:?}", e))?;
+    render(
+        &format!("Search: {}", query),
+        &rows(index, parsed.as_ref(), limit),
+        format,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(escape_csv("plain"), "plain");
+        assert_eq!(escape_csv("a, b"), "\"a, b\"");
+        assert_eq!(escape_csv("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn markdown_link_text_escapes_brackets() {
+        assert_eq!(escape_markdown("[draft] notes"), "\\[draft\\] notes");
+        assert_eq!(escape_markdown("plain"), "plain");
+    }
+
+    #[test]
+    fn html_escapes_markup() {
+        assert_eq!(
+            escape_html("<a href=\"x\">&</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn csv_export_has_a_header_and_a_line_per_row() {
+        let row = ExportRow {
+            id: "1".to_string(),
+            title: "Rust, the book".to_string(),
+            url: "https://doc.rust-lang.org/book/".to_string(),
+            tags: vec!["/tags/rust".to_string()],
+            ..ExportRow::default()
+        };
+        let csv = render("Search: rust", &[row], "csv").unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "id,title,url,tags,summary,added_at,last_accessed_at"
+        );
+        assert_eq!(
+            lines[1],
+            "1,\"Rust, the book\",https://doc.rust-lang.org/book/,/tags/rust,,,"
+        );
+        assert!(render("Search: rust", &[], "pdf").is_err());
+    }
+}
@@ -1,14 +1,18 @@
+use crate::tagging;
+use crate::vectors;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use chrono::prelude::*;
 use glob::glob;
 use probabilistic_collections::similarity::{ShingleIterator, SimHash};
 use probabilistic_collections::SipHasherBuilder;
+use rand::RngCore;
 #[cfg(feature = "ml")]
 use rust_bert::pipelines::summarization::{SummarizationConfig, SummarizationModel};
 use select::document;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashSet;
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -17,11 +21,16 @@ use std::io::Write;
 use std::panic;
 use std::path::Path;
 use std::time::Duration;
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{
+    AllQuery, BooleanQuery, FuzzyTermQuery, Occur, Query, QueryParser, TermQuery,
+};
 use tantivy::schema::*;
+use tantivy::tokenizer::{
+    BoxTokenStream, Language, LowerCaser, RemoveLongFilter, SimpleTokenizer, Stemmer, TextAnalyzer,
+    Token, TokenStream, Tokenizer,
+};
 use tantivy::{Index, ReloadPolicy};
-use triple_accel::hamming;
 
 pub enum GetterResults {
     Html(String),
@@ -66,11 +75,41 @@ pub struct NoAuthBlockingGetter {}
 impl IndexGetter for NoAuthBlockingGetter {}
 
 #[derive(Serialize, Debug, Deserialize)]
+#[serde(default)]
 pub struct SystemSettings {
     pub port: String,
     pub ignore_domains: Vec<String>,
     pub indexer_enabled: bool,
     pub ignore_strings: Vec<String>,
+    // a visited url is fetched again once it has this many visits and its
+    // copy is older than refetch_after_days. 0 days turns refetching off.
+    pub refetch_min_visits: i64,
+    pub refetch_after_days: i64,
+    // files or pack. change it with migrate-store
+    pub source_store: String,
+    // how urls are normalized before they are hashed into ids. query params
+    // ending in * match by prefix. run merge-duplicates after changing these
+    pub strip_query_params: Vec<String>,
+    pub force_https: bool,
+    pub strip_www: bool,
+    pub strip_trailing_slash: bool,
+    pub respect_canonical: bool,
+    // keep searches made through /search for suggestions and the history
+    pub record_queries: bool,
+    // directory of a sentence embeddings model for semantic search. empty for
+    // models/embeddings in the index directory
+    pub embedding_model: String,
+    // summaries are made on the gpu when there is one. on the cpu they are
+    // slow so they are off unless this is set
+    pub summarize_on_cpu: bool,
+    // most tokens in a model summary
+    pub summary_length: i64,
+    // keyphrases added to a new page as /auto tags, 0 turns them off
+    pub auto_tags: usize,
+    // tags added to new pages matching a url or content regex. run
+    // tags apply-rules to add them to pages already indexed. toml wants
+    // tables after plain values so this stays last
+    pub tag_rules: Vec<tagging::TagRule>,
 }
 
 impl Default for SystemSettings {
@@ -79,6 +118,34 @@ impl Default for SystemSettings {
             port: "7172".to_string(),
             ignore_strings: vec![],
             indexer_enabled: false,
+            refetch_min_visits: 5,
+            refetch_after_days: 30,
+            source_store: "files".to_string(),
+            strip_query_params: vec![
+                "utm_*".to_string(),
+                "fbclid".to_string(),
+                "gclid".to_string(),
+                "dclid".to_string(),
+                "msclkid".to_string(),
+                "yclid".to_string(),
+                "mc_cid".to_string(),
+                "mc_eid".to_string(),
+                "_ga".to_string(),
+                "_hsenc".to_string(),
+                "_hsmi".to_string(),
+                "igshid".to_string(),
+                "ref_src".to_string(),
+            ],
+            force_https: true,
+            strip_www: true,
+            strip_trailing_slash: true,
+            respect_canonical: true,
+            record_queries: true,
+            embedding_model: String::new(),
+            summarize_on_cpu: false,
+            summary_length: 142,
+            auto_tags: 5,
+            tag_rules: vec![],
             ignore_domains: vec![
                 ".lvh.me".to_string(),
                 "//lvh.me".to_string(),
@@ -107,6 +174,10 @@ impl Default for SystemSettings {
 use std::env;
 lazy_static::lazy_static! {
     pub static ref CACHEDCONFIG: SystemSettings = read_settings();
+    pub static ref SOURCE_STORE: Box<dyn SourceStore + Send + Sync> =
+        open_source_store(&CACHEDCONFIG.source_store);
+    pub static ref VERSION_STORE: Box<dyn SourceStore + Send + Sync> =
+        open_version_store(&CACHEDCONFIG.source_store);
     pub static ref BASE_INDEX_DIR: String = match env::var("PS_INDEX_DIRECTORY") {
         Ok(val) => {
             if val.ends_with('/') || val.ends_with('\\') {
@@ -140,7 +211,7 @@ pub fn write_settings(config: &SystemSettings) {
         .create(true)
         .open(&path_name)
         .expect("setting file write");
-    file.write_all(toml::to_string(&config).unwrap().as_bytes())
+    file.write_all(&seal(toml::to_string(&config).unwrap().into_bytes()))
         .expect("file");
     file.sync_all().expect("file write");
 }
@@ -149,7 +220,7 @@ pub fn read_settings() -> SystemSettings {
     let path = Path::new(BASE_INDEX_DIR.as_str());
     let path_name = path.join("server_settings.toml");
     create_directory(&BASE_INDEX_DIR);
-    let mut s = String::new();
+    let mut bytes = vec![];
     let file = OpenOptions::new()
         .read(true)
         .write(true)
@@ -161,11 +232,15 @@ pub fn read_settings() -> SystemSettings {
             //println!("couldn't open {}: {}", path_name, why.to_string());
         }
         Ok(mut file) => {
-            if let Err(why) = file.read_to_string(&mut s) {
+            if let Err(why) = file.read_to_end(&mut bytes) {
                 panic!("couldn't read {:#?}: {}", path_name.to_str(), why)
             };
         }
     };
+    let s = unseal(bytes)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .expect("settings could not be decrypted");
     if !s.is_empty() {
         let config: SystemSettings = toml::from_str(&s).expect("bad config parse");
         config
@@ -174,13 +249,219 @@ pub fn read_settings() -> SystemSettings {
     }
 }
 
+// sealed data starts with this so data written before encryption was turned on
+// can still be read
+const SEALED_MAGIC: &[u8] = b"PSE1";
+const KEY_CHECK: &[u8] = b"personal_search";
+
+lazy_static::lazy_static! {
+    static ref ENCRYPTION_KEY: std::sync::RwLock<Option<[u8; 32]>> = std::sync::RwLock::new(None);
+}
+
+// written next to the settings when the archive is encrypted. check is
+// KEY_CHECK sealed with the key so a wrong passphrase is caught up front
+#[derive(Serialize, Deserialize)]
+struct EncryptionConfig {
+    salt: String,
+    rounds: u32,
+    check: String,
+}
+
+fn encryption_config_path() -> std::path::PathBuf {
+    Path::new(BASE_INDEX_DIR.as_str()).join("encryption.toml")
+}
+
+pub fn encryption_enabled() -> bool {
+    encryption_config_path().exists()
+}
+
+fn derive_key(secret: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(secret, salt, rounds, &mut key);
+    key
+}
+
+fn seal_with(key: &[u8; 32], bytes: &[u8]) -> Vec<u8> {
+    let cipher = ChaCha20Poly1305::new(&Key::from(*key));
+    let mut nonce = [0u8; 12];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let sealed = cipher.encrypt(&Nonce::from(nonce), bytes).expect("encrypt");
+    [SEALED_MAGIC, &nonce, &sealed].concat()
+}
+
+fn open_with(key: &[u8; 32], bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let header = SEALED_MAGIC.len() + 12;
+    if bytes.len() < header {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "sealed data is truncated",
+        ));
+    }
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&bytes[SEALED_MAGIC.len()..header]);
+    let cipher = ChaCha20Poly1305::new(&Key::from(*key));
+    cipher
+        .decrypt(&Nonce::from(nonce), &bytes[header..])
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "could not decrypt"))
+}
+
+pub fn seal(bytes: Vec<u8>) -> Vec<u8> {
+    if encryption_enabled() {
+        seal_with(&encryption_key(), &bytes)
+    } else {
+        bytes
+    }
+}
+
+pub fn unseal(bytes: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if bytes.starts_with(SEALED_MAGIC) {
+        open_with(&encryption_key(), &bytes)
+    } else {
+        Ok(bytes)
+    }
+}
+
+// checks the passphrase or key file contents against the archive and keeps the key
+pub fn unlock(secret: &[u8]) -> Result<(), String> {
+    let config = fs::read_to_string(encryption_config_path())
+        .map_err(|e| e.to_string())
+        .and_then(|s| toml::from_str::<EncryptionConfig>(&s).map_err(|e| e.to_string()))?;
+    let salt = hex::decode(&config.salt).map_err(|e| e.to_string())?;
+    let check = hex::decode(&config.check).map_err(|e| e.to_string())?;
+    let key = derive_key(secret, &salt, config.rounds);
+    match open_with(&key, &check) {
+        Ok(check) if check == KEY_CHECK => {
+            *ENCRYPTION_KEY.write().expect("key lock") = Some(key);
+            Ok(())
+        }
+        _ => Err("wrong passphrase or key file".to_string()),
+    }
+}
+
+// PS_KEY_FILE, then PS_PASSPHRASE, then asks on the terminal
+pub fn encryption_secret(prompt: &str) -> Vec<u8> {
+    if let Ok(path) = env::var("PS_KEY_FILE") {
+        let mut secret = fs::read(path).expect("could not read PS_KEY_FILE");
+        while secret.last() == Some(&b'\n') || secret.last() == Some(&b'\r') {
+            secret.pop();
+        }
+        secret
+    } else if let Ok(passphrase) = env::var("PS_PASSPHRASE") {
+        passphrase.into_bytes()
+    } else {
+        rpassword::read_password_from_tty(Some(prompt))
+            .unwrap_or_default()
+            .into_bytes()
+    }
+}
+
+// asks for the passphrase when the archive is encrypted and not unlocked yet
+pub fn ensure_unlocked() -> Result<(), String> {
+    if !encryption_enabled() || ENCRYPTION_KEY.read().expect("key lock").is_some() {
+        return Ok(());
+    }
+    unlock(&encryption_secret("passphrase: "))
+}
+
+fn encryption_key() -> [u8; 32] {
+    if let Some(key) = *ENCRYPTION_KEY.read().expect("key lock") {
+        return key;
+    }
+    if let Err(e) = ensure_unlocked() {
+        panic!(
+            "the archive is encrypted and {}. set PS_PASSPHRASE or PS_KEY_FILE",
+            e
+        );
+    }
+    ENCRYPTION_KEY.read().expect("key lock").expect("key")
+}
+
+// turns encryption on and rewrites the settings, sources, versions and the
+// index, lsh and vectors directories sealed with a key derived from secret.
+// nothing else may have the index open while it runs. returns the number of
+// files converted
+pub fn encrypt_existing(secret: &[u8]) -> Result<usize, String> {
+    if encryption_enabled() {
+        return Err("the archive is already encrypted".to_string());
+    }
+    let settings = read_settings();
+
+    let mut salt = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    let rounds = 100_000;
+    let key = derive_key(secret, &salt, rounds);
+    let config = EncryptionConfig {
+        salt: hex::encode(salt),
+        rounds,
+        check: hex::encode(seal_with(&key, KEY_CHECK)),
+    };
+    fs::write(
+        encryption_config_path(),
+        toml::to_string(&config).expect("encryption config"),
+    )
+    .map_err(|e| e.to_string())?;
+    *ENCRYPTION_KEY.write().expect("key lock") = Some(key);
+    write_settings(&settings);
+
+    let mut converted = 1;
+    for url_hash in SOURCE_STORE.hashes().map_err(|e| e.to_string())? {
+        if let Some(bytes) = SOURCE_STORE.get(&url_hash).map_err(|e| e.to_string())? {
+            if !bytes.starts_with(SEALED_MAGIC) {
+                SOURCE_STORE
+                    .put(&url_hash, &seal_with(&key, &bytes))
+                    .map_err(|e| e.to_string())?;
+                converted += 1;
+            }
+        }
+    }
+    for version in VERSION_STORE.hashes().map_err(|e| e.to_string())? {
+        if let Some(bytes) = VERSION_STORE.get(&version).map_err(|e| e.to_string())? {
+            if !bytes.starts_with(SEALED_MAGIC) {
+                VERSION_STORE
+                    .put(&version, &seal_with(&key, &bytes))
+                    .map_err(|e| e.to_string())?;
+                converted += 1;
+            }
+        }
+    }
+    // the pack still holds the plain copies until it is rewritten
+    SOURCE_STORE.compact().map_err(|e| e.to_string())?;
+    VERSION_STORE.compact().map_err(|e| e.to_string())?;
+
+    // each index file is replaced in one rename so a crash leaves it whole,
+    // either plain or sealed. the lock files hold nothing
+    let index_path = Path::new(BASE_INDEX_DIR.as_str());
+    for directory in ["index", "lsh", "vectors"].iter() {
+        let entries = match fs::read_dir(index_path.join(directory)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !path.is_file() || (name.starts_with(".tantivy-") && name.ends_with(".lock")) {
+                continue;
+            }
+            let bytes = fs::read(&path).map_err(|e| e.to_string())?;
+            if !bytes.starts_with(SEALED_MAGIC) {
+                let sealing = path.with_file_name(format!("{}.sealing", name));
+                fs::write(&sealing, seal_with(&key, &bytes)).map_err(|e| e.to_string())?;
+                fs::rename(&sealing, &path).map_err(|e| e.to_string())?;
+                converted += 1;
+            }
+        }
+    }
+    Ok(converted)
+}
+
 fn create_directory(system_path: &str) {
     let index_path = Path::new(system_path);
     let paths = vec![
         index_path.join(""),
         index_path.join("source"),
         index_path.join("index"),
-        index_path.join("hashes"),
+        index_path.join("lsh"),
+        index_path.join("versions"),
     ];
 
     for path in paths {
@@ -190,30 +471,173 @@ fn create_directory(system_path: &str) {
     }
 }
 
-fn index_directory(
-) -> Result<tantivy::directory::MmapDirectory, tantivy::directory::error::OpenDirectoryError> {
+fn index_directory() -> Result<SealedDirectory, tantivy::directory::error::OpenDirectoryError> {
     create_directory(&BASE_INDEX_DIR);
     let index_path = Path::new(BASE_INDEX_DIR.as_str());
 
-    tantivy::directory::MmapDirectory::open(index_path.join("index"))
+    SealedDirectory::open(index_path.join("index"))
 }
 
-fn hash_directory(
-) -> Result<tantivy::directory::MmapDirectory, tantivy::directory::error::OpenDirectoryError> {
+fn lsh_directory() -> Result<SealedDirectory, tantivy::directory::error::OpenDirectoryError> {
     create_directory(&BASE_INDEX_DIR);
     let index_path = Path::new(BASE_INDEX_DIR.as_str());
 
-    tantivy::directory::MmapDirectory::open(index_path.join("hashes"))
+    SealedDirectory::open(index_path.join("lsh"))
+}
+
+// an index directory whose files are sealed when the archive is encrypted.
+// a sealed file is read into memory whole instead of being mapped, and a new
+// one is held in memory until tantivy finishes it. without encryption it is
+// the plain mmap directory
+#[derive(Clone)]
+pub struct SealedDirectory {
+    inner: tantivy::directory::MmapDirectory,
+    key: Option<[u8; 32]>,
 }
 
-pub fn hash_index(system_path: &str) -> std::result::Result<tantivy::Index, tantivy::TantivyError> {
-    // dont keep its own index? we are writing the domain and duplicate urls to prevent them
-    // from reloading. just use that?
-    let directory = hash_directory();
+impl SealedDirectory {
+    pub fn open(
+        path: std::path::PathBuf,
+    ) -> Result<SealedDirectory, tantivy::directory::error::OpenDirectoryError> {
+        let key = if encryption_enabled() {
+            Some(encryption_key())
+        } else {
+            None
+        };
+        Ok(SealedDirectory {
+            inner: tantivy::directory::MmapDirectory::open(path)?,
+            key,
+        })
+    }
+
+    fn open_bytes(
+        &self,
+        path: &Path,
+        bytes: Vec<u8>,
+    ) -> Result<Vec<u8>, tantivy::directory::error::OpenReadError> {
+        match self.key {
+            Some(key) if bytes.starts_with(SEALED_MAGIC) => open_with(&key, &bytes).map_err(|e| {
+                tantivy::directory::error::IOError::from(std::io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", path.display(), e),
+                ))
+                .into()
+            }),
+            _ => Ok(bytes),
+        }
+    }
+}
+
+impl std::fmt::Debug for SealedDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SealedDirectory")
+            .field("inner", &self.inner)
+            .field("sealed", &self.key.is_some())
+            .finish()
+    }
+}
+
+impl tantivy::directory::Directory for SealedDirectory {
+    fn open_read(
+        &self,
+        path: &Path,
+    ) -> Result<tantivy::directory::ReadOnlySource, tantivy::directory::error::OpenReadError> {
+        if self.key.is_none() {
+            return self.inner.open_read(path);
+        }
+        let bytes = self.inner.atomic_read(path)?;
+        Ok(self.open_bytes(path, bytes)?.into())
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), tantivy::directory::error::DeleteError> {
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn open_write(
+        &mut self,
+        path: &Path,
+    ) -> Result<tantivy::directory::WritePtr, tantivy::directory::error::OpenWriteError> {
+        let inner = self.inner.open_write(path)?;
+        match self.key {
+            Some(key) => Ok(std::io::BufWriter::new(Box::new(SealedWriter {
+                inner,
+                key,
+                buffer: vec![],
+            }))),
+            None => Ok(inner),
+        }
+    }
+
+    fn atomic_read(
+        &self,
+        path: &Path,
+    ) -> Result<Vec<u8>, tantivy::directory::error::OpenReadError> {
+        let bytes = self.inner.atomic_read(path)?;
+        self.open_bytes(path, bytes)
+    }
+
+    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        match self.key {
+            Some(key) => self.inner.atomic_write(path, &seal_with(&key, data)),
+            None => self.inner.atomic_write(path, data),
+        }
+    }
+
+    fn acquire_lock(
+        &self,
+        lock: &tantivy::directory::Lock,
+    ) -> Result<tantivy::directory::DirectoryLock, tantivy::directory::error::LockError> {
+        self.inner.acquire_lock(lock)
+    }
+
+    fn watch(
+        &self,
+        watch_callback: tantivy::directory::WatchCallback,
+    ) -> tantivy::Result<tantivy::directory::WatchHandle> {
+        self.inner.watch(watch_callback)
+    }
+}
+
+// collects a whole index file and writes it sealed when tantivy is done with it
+struct SealedWriter {
+    inner: tantivy::directory::WritePtr,
+    key: [u8; 32],
+    buffer: Vec<u8>,
+}
+
+impl Write for SealedWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    // nothing reaches the file before the end, a part can not be sealed
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl tantivy::directory::TerminatingWrite for SealedWriter {
+    fn terminate_ref(&mut self, token: tantivy::directory::AntiCallToken) -> std::io::Result<()> {
+        self.inner.write_all(&seal_with(&self.key, &self.buffer))?;
+        self.buffer = vec![];
+        self.inner.terminate_ref(token)
+    }
+}
+
+// simhashes of the documents that are not duplicates, split into bands so near
+// duplicates can be found with term lookups instead of comparing every hash
+pub fn lsh_index() -> std::result::Result<tantivy::Index, tantivy::TantivyError> {
+    let directory = lsh_directory();
 
     let mut schema_builder = Schema::builder();
-    schema_builder.add_text_field("domain", TEXT | STORED);
-    schema_builder.add_facet_field("hashes");
+    schema_builder.add_text_field("id", STRING | STORED);
+    schema_builder.add_i64_field("hash", STORED);
+    schema_builder.add_text_field("bands", STRING);
 
     let schema = schema_builder.build();
     match directory {
@@ -222,7 +646,7 @@ pub fn hash_index(system_path: &str) -> std::result::Result<tantivy::Index, tant
             println!("dir not found");
             Err(tantivy::TantivyError::SystemError(format!(
                 "could not open index directory {}",
-                system_path
+                BASE_INDEX_DIR.as_str()
             )))
         }
     }
@@ -238,7 +662,10 @@ pub fn search_index() -> std::result::Result<tantivy::Index, tantivy::TantivyErr
     schema_builder.add_text_field("url", TEXT | STORED);
     schema_builder.add_text_field("content", TEXT);
     schema_builder.add_text_field("domain", TEXT | STORED);
-    schema_builder.add_text_field("content_raw", STORED);
+    schema_builder.add_text_field("host", STRING | STORED);
+    // the fetched html only lives in the source, which is encrypted when the
+    // archive is. the field is here so sources parse into documents
+    schema_builder.add_text_field("content_raw", TextOptions::default());
     schema_builder.add_text_field("summary", STORED);
     schema_builder.add_text_field("description", STORED);
     schema_builder.add_i64_field("bookmarked", STORED | INDEXED);
@@ -251,11 +678,44 @@ pub fn search_index() -> std::result::Result<tantivy::Index, tantivy::TantivyErr
     schema_builder.add_date_field("last_accessed_at", STORED | INDEXED);
     schema_builder.add_i64_field("added_at_i", STORED | INDEXED);
     schema_builder.add_i64_field("last_accessed_at_i", STORED | INDEXED);
+    schema_builder.add_i64_field("fetched_at_i", STORED | INDEXED);
+    schema_builder.add_text_field("author", TEXT | STORED);
+    schema_builder.add_text_field("lang", STRING | STORED);
+    schema_builder.add_text_field("canonical_url", STRING | STORED);
+    schema_builder.add_text_field("image", STORED);
+    schema_builder.add_text_field("meta_type", STRING | STORED);
+    schema_builder.add_i64_field("published_at_i", STORED | INDEXED | FAST);
+    schema_builder.add_i64_field("modified_at_i", STORED | INDEXED);
+    // other normalized urls that resolved to this document through its canonical link
+    schema_builder.add_text_field("aliases", STRING | STORED);
+    // id of the earlier document this one is a near duplicate of
+    schema_builder.add_text_field("duplicate_of", STRING | STORED);
+    // what the user wrote about the page and passages they marked in it
+    schema_builder.add_text_field("notes", TEXT | STORED);
+    schema_builder.add_text_field("highlights", TEXT | STORED);
+    // read later: unread, reading, done or archived, when it last changed and
+    // when the page was saved for later
+    schema_builder.add_text_field("reading_status", STRING | STORED);
+    schema_builder.add_i64_field("reading_status_at_i", STORED | INDEXED);
+    schema_builder.add_i64_field("queued_at_i", STORED | INDEXED);
     schema_builder.add_facet_field("tags");
+    // title and content again, analyzed for the language of the page
+    for (language, _) in LANGUAGE_ANALYZERS.iter() {
+        let options = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer(&format!("lang_{}", language))
+                .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+        );
+        schema_builder.add_text_field(&format!("title_{}", language), options.clone());
+        schema_builder.add_text_field(&format!("content_{}", language), options);
+    }
 
     let schema = schema_builder.build();
     match directory {
-        Ok(dir) => Index::open_or_create(dir, schema),
+        Ok(dir) => Index::open_or_create(dir, schema).map(|index| {
+            register_language_analyzers(&index);
+            index
+        }),
         Err(_) => {
             println!("dir not found");
             Err(tantivy::TantivyError::SystemError(format!(
@@ -287,15 +747,40 @@ pub struct UrlMeta {
     pub pinned: Option<i64>,
     pub access_count: Option<i64>,
     pub hidden: Option<i64>,
+    pub summary: Option<String>,
+    pub notes: Option<String>,
+    pub highlights_add: Option<Vec<String>>,
+    pub highlights_remove: Option<Vec<String>>,
+    pub reading_status: Option<String>,
+}
+
+pub const READING_STATUSES: [&str; 4] = ["unread", "reading", "done", "archived"];
+
+// sets the reading status and its timestamps. unknown statuses and the
+// current one change nothing
+fn set_reading_status(json: &mut Value, status: &str) {
+    if !READING_STATUSES.contains(&status) || json["reading_status"][0].as_str() == Some(status) {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    json["reading_status"] = json!(vec![status]);
+    json["reading_status_at_i"] = json!(vec![now]);
+    if status == "unread" {
+        json["queued_at_i"] = json!(vec![now]);
+    }
 }
 
 pub fn url_skip(url: &str) -> bool {
+    url_skip_with(url, &CACHEDCONFIG)
+}
+
+fn url_skip_with(url: &str, settings: &SystemSettings) -> bool {
     // lazy static this
     let parsed = url::Url::parse(&url).expect("url pase");
     if !parsed.scheme().starts_with("http") {
         true
     } else {
-        CACHEDCONFIG.ignore_domains.iter().any(|s| {
+        settings.ignore_domains.iter().any(|s| {
             if s.ends_with('$') {
                 let mut x = s.clone();
                 x.pop();
@@ -312,58 +797,194 @@ pub fn md5_hash(domain: &str) -> String {
     format!("{:x}", digest)
 }
 
-pub fn add_hash(domain: &str, hash: u64) {
-    let index = hash_index(&BASE_INDEX_DIR).expect("hash index");
-    let searcher = searcher(&index);
-    let mut index_writer = index.writer(50_000_000).expect("writer");
-    let query_parser = QueryParser::for_index(
-        &index,
-        vec![index.schema().get_field("domain").expect("domain field")],
-    );
-    let domain_hash = md5_hash(&domain);
-    let query = query_parser
-        .parse_query(&format!("\"{}\"", &domain_hash))
-        .expect("query parse for domain match");
+// the form of a url that is hashed into its id. only used for ids, pages are
+// still fetched from the url as it was visited
+pub fn normalize_url(url: &str) -> String {
+    normalize_url_with(url, &CACHEDCONFIG)
+}
 
-    let top_docs = searcher
-        .search(&query, &TopDocs::with_limit(1))
-        .expect("search");
+pub fn normalize_url_with(url: &str, settings: &SystemSettings) -> String {
+    let mut parsed = match url::Url::parse(url.trim()) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    parsed.set_fragment(None);
+    if settings.force_https && parsed.scheme() == "http" {
+        let _ = parsed.set_scheme("https");
+        if parsed.port() == Some(443) {
+            let _ = parsed.set_port(None);
+        }
+    }
+    if settings.strip_www {
+        if let Some(host) = parsed.host_str().and_then(|host| host.strip_prefix("www.")) {
+            let host = host.to_string();
+            let _ = parsed.set_host(Some(&host));
+        }
+    }
 
-    let new_hash = format!("/{}", hash);
-    let mut doc = if let Some(result) = top_docs.first() {
-        let doc = searcher.doc(result.1).expect("doc");
-        // dont dup the facet
-        for s in doc
-            .get_all(index.schema().get_field("hashes").expect("f"))
-            .iter()
-        {
-            if let tantivy::schema::Value::Facet(facet) = s {
-                if facet.to_path_string() == new_hash {
-                    return;
+    let mut params = parsed
+        .query_pairs()
+        .filter(|(key, _)| {
+            !settings.strip_query_params.iter().any(|strip| {
+                if strip.ends_with('*') {
+                    key.starts_with(strip.trim_end_matches('*'))
+                } else {
+                    key == strip
                 }
-            }
-        }
-        let frankenstein_isbn = Term::from_field_text(
-            index.schema().get_field("domain").expect("domain field"),
-            &domain_hash,
-        );
-        index_writer.delete_term(frankenstein_isbn);
-        doc
+            })
+        })
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    // the order of query params does not change the page
+    params.sort();
+    if params.is_empty() {
+        parsed.set_query(None);
     } else {
-        let mut doc = tantivy::Document::default();
-        doc.add_text(
-            index.schema().get_field("domain").expect("domain"),
-            &domain_hash,
-        );
-        doc
+        parsed.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    if settings.strip_trailing_slash && parsed.path().len() > 1 && parsed.path().ends_with('/') {
+        let path = parsed.path().trim_end_matches('/').to_string();
+        parsed.set_path(&path);
+    }
+    let normalized = parsed.to_string();
+    // the root path always prints a slash
+    if settings.strip_trailing_slash && parsed.path() == "/" && parsed.query().is_none() {
+        normalized.trim_end_matches('/').to_string()
+    } else {
+        normalized
+    }
+}
+
+// the host without www, for domain completions
+pub fn url_host(url: &str) -> Option<String> {
+    let parsed = url::Url::parse(url).ok()?;
+    let host = parsed.host_str()?.to_lowercase();
+    Some(host.trim_start_matches("www.").to_string())
+}
+
+pub fn url_id(url: &str) -> String {
+    md5_hash(&normalize_url(url))
+}
+
+// the id the url is indexed under, which is not url_id for pages indexed
+// before normalization
+fn indexed_id(url: &str, index: &Index) -> String {
+    find_url(url, index)
+        .and_then(|doc_address| document_id(index, doc_address))
+        .unwrap_or_else(|| url_id(url))
+}
+
+// canonical links are only followed within the same site so syndicated copies
+// do not take over the original
+fn canonical_usable(url: &str, canonical: &str) -> bool {
+    canonical_usable_with(url, canonical, &CACHEDCONFIG)
+}
+
+fn canonical_usable_with(url: &str, canonical: &str, settings: &SystemSettings) -> bool {
+    let host = |url: &str| {
+        url::Url::parse(url).ok().and_then(|parsed| {
+            if parsed.scheme().starts_with("http") {
+                parsed
+                    .host_str()
+                    .map(|host| host.trim_start_matches("www.").to_string())
+            } else {
+                None
+            }
+        })
     };
+    settings.respect_canonical
+        && host(canonical).is_some()
+        && host(url) == host(canonical)
+        && !url_skip_with(canonical, settings)
+}
+
+// pages whose simhashes differ by this many bits or less are the same page.
+// with 4 bands of 16 bits two such hashes always share at least one band
+const NEAR_DUPLICATE_BITS: u32 = 3;
+const LSH_BANDS: u64 = 4;
+
+fn simhash_bands(hash: u64) -> Vec<String> {
+    (0..LSH_BANDS)
+        .map(|band| format!("{}:{:04x}", band, (hash >> (band * 16)) & 0xffff))
+        .collect()
+}
+
+fn lsh_document(index: &Index, id: &str, hash: u64) -> Document {
+    let schema = index.schema();
+    let mut doc = Document::default();
+    doc.add_text(schema.get_field("id").expect("id"), id);
+    doc.add_i64(schema.get_field("hash").expect("hash"), hash as i64);
+    for band in simhash_bands(hash) {
+        doc.add_text(schema.get_field("bands").expect("bands"), &band);
+    }
+    doc
+}
 
-    doc.add_facet(
-        index.schema().get_field("hashes").expect("hash"),
-        Facet::from(&new_hash),
+// the id of an earlier document with nearly the same content
+pub fn near_duplicate_of(hash: u64, id: &str) -> Option<String> {
+    let index = lsh_index().expect("lsh index");
+    let schema = index.schema();
+    let searcher = searcher(&index);
+    let bands_field = schema.get_field("bands").expect("bands");
+    let query = BooleanQuery::from(
+        simhash_bands(hash)
+            .iter()
+            .map(|band| {
+                let query: Box<dyn Query> = Box::new(TermQuery::new(
+                    Term::from_field_text(bands_field, band),
+                    IndexRecordOption::Basic,
+                ));
+                (Occur::Should, query)
+            })
+            .collect::<Vec<_>>(),
     );
+    let candidates = searcher
+        .search(&query, &TopDocs::with_limit(100))
+        .expect("search");
+    candidates
+        .iter()
+        .filter_map(|(_, doc_address)| {
+            let doc = searcher.doc(*doc_address).ok()?;
+            let candidate_id = doc.get_first(schema.get_field("id").expect("id"))?.text()?;
+            let candidate = doc
+                .get_first(schema.get_field("hash").expect("hash"))?
+                .i64_value() as u64;
+            let distance = (candidate ^ hash).count_ones();
+            if candidate_id != id && distance <= NEAR_DUPLICATE_BITS {
+                Some((distance, candidate_id.to_string()))
+            } else {
+                None
+            }
+        })
+        .min()
+        .map(|(_, candidate_id)| candidate_id)
+}
 
-    index_writer.add_document(doc);
+// documents that are not duplicates are added to the lsh index so later pages can
+// be matched against them. duplicates point at their original instead
+pub fn register_simhash(doc: &Document, schema: &Schema) {
+    let id = match doc
+        .get_first(schema.get_field("id").expect("id"))
+        .and_then(|value| value.text())
+    {
+        Some(id) => id,
+        None => return,
+    };
+    let index = lsh_index().expect("lsh index");
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    index_writer.delete_term(Term::from_field_text(
+        index.schema().get_field("id").expect("id"),
+        id,
+    ));
+    let duplicate = doc
+        .get_first(schema.get_field("duplicate_of").expect("duplicate_of"))
+        .is_some();
+    if let Some(hash) = doc.get_first(schema.get_field("content_hash").expect("content_hash")) {
+        if !duplicate {
+            index_writer.add_document(lsh_document(&index, id, hash.i64_value() as u64));
+        }
+    }
     index_writer.commit().expect("commit");
     index_writer.wait_merging_threads().expect("merge");
 }
@@ -401,14 +1022,19 @@ pub fn update_document(url_hash: &str, index: &Index, meta: UrlMeta) -> Document
         json["last_accessed_at_i"] = json!(Utc::now().timestamp());
     }
 
-    if json.get("cotent_raw").is_none() {
-        json["content_raw"] = json!("");
+    // the offline copy /view and the highlight panel read from
+    if json.get("content_raw").is_none() {
+        json["content_raw"] = json!([""]);
     }
 
     if json.get("added_at_i").is_none() {
         json["added_at_i"] = json!(Utc::now().timestamp());
     }
 
+    if json.get("fetched_at_i").is_none() {
+        json["fetched_at_i"] = json["added_at_i"].clone();
+    }
+
     if let Some(pinned) = meta.pinned {
         json["pinned"] = json!(vec![pinned]);
     }
@@ -423,6 +1049,30 @@ pub fn update_document(url_hash: &str, index: &Index, meta: UrlMeta) -> Document
         json["hidden"] = json!(vec![0]);
     }
 
+    if let Some(notes) = meta.notes {
+        json["notes"] = json!(vec![notes]);
+    }
+
+    let mut highlights = json["highlights"].as_array().cloned().unwrap_or_default();
+    for highlight in meta.highlights_add.unwrap_or_default() {
+        let value = json!(highlight);
+        if !highlights.contains(&value) {
+            highlights.push(value);
+        }
+    }
+    for highlight in meta.highlights_remove.unwrap_or_default() {
+        highlights.retain(|value| value != &json!(highlight));
+    }
+    json["highlights"] = json!(highlights);
+
+    if let Some(summary) = meta.summary {
+        json["summary"] = json!(vec![summary]);
+    }
+
+    if let Some(status) = meta.reading_status {
+        set_reading_status(&mut json, &status);
+    }
+
     if let Some(accessed_count) = meta.access_count {
         json["accessed_count"] = json!(vec![accessed_count]);
     }
@@ -437,6 +1087,25 @@ pub fn update_document(url_hash: &str, index: &Index, meta: UrlMeta) -> Document
         .expect("doc from json")
 }
 
+fn add_alias(url_hash: &str, alias: &str, index: &Index, meta: UrlMeta) {
+    if let Some(json) = read_source(url_hash) {
+        let mut json: Value = serde_json::from_str(&json).expect("cached json parse fail!");
+        let mut aliases = json["aliases"].as_array().cloned().unwrap_or_default();
+        if !aliases.contains(&json!(alias)) {
+            aliases.push(json!(alias));
+        }
+        json["aliases"] = json!(aliases);
+        write_source(url_hash, json.to_string());
+    }
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    index_writer.delete_term(Term::from_field_text(
+        index.schema().get_field("id").expect("id"),
+        url_hash,
+    ));
+    update_cached(url_hash, index, meta, &mut index_writer);
+    index_writer.wait_merging_threads().expect("merge");
+}
+
 pub fn update_cached(
     url_hash: &str,
     index: &Index,
@@ -450,61 +1119,296 @@ pub fn update_cached(
     write_source(url_hash, json);
 }
 
-#[cfg(not(feature = "ml"))]
-pub fn summary(body: &str) -> Option<String> {
+// changes the source json of a document, writes it back once and returns the
+// document rebuilt from it. None when there is no source. the caller replaces
+// the document in the index
+pub fn patch_source(
+    url_hash: &str,
+    index: &Index,
+    patch: impl FnOnce(&mut Value),
+) -> Option<Document> {
+    let mut json: Value =
+        serde_json::from_str(&read_source(url_hash)?).expect("cached json parse fail!");
+    patch(&mut json);
+    let json = json.to_string();
+    let doc = index.schema().parse_document(&json).expect("doc from json");
+    write_source(url_hash, json);
+    Some(doc)
+}
+
+// queued visits are written this often by the thread spawn_visit_flusher
+// starts, and once more when the server or the tui stops
+pub const VISIT_FLUSH_SECONDS: u64 = 60;
+
+lazy_static::lazy_static! {
+    // id -> (visits to add, time of the last one)
+    static ref PENDING_VISITS: std::sync::Mutex<HashMap<String, (i64, i64)>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+// a result opened from search counts as a visit. returns the url to go to
+pub fn record_visit(url_hash: &str, index: &Index) -> Option<String> {
+    let doc_address = find_id(url_hash, index)?;
+    let doc = searcher(index).doc(doc_address).ok()?;
+    let url = doc
+        .get_first(index.schema().get_field("url").expect("url"))
+        .and_then(|value| value.text())
+        .map(str::to_string)?;
+    let now = Utc::now().timestamp();
+    let mut pending = PENDING_VISITS.lock().expect("visits lock");
+    let visit = pending.entry(url_hash.to_string()).or_insert((0, now));
+    visit.0 += 1;
+    visit.1 = now;
+    Some(url)
+}
+
+// writes the queued visits with one commit, only touching the visit count and
+// time of each page. the queue is taken out under the lock so visits keep
+// coming in during the write, and put back while another writer holds the
+// index. returns the pages updated
+pub fn flush_visits(index: &Index) -> usize {
+    let visits = std::mem::take(&mut *PENDING_VISITS.lock().expect("visits lock"));
+    if visits.is_empty() {
+        return 0;
+    }
+    let mut index_writer = match index.writer(50_000_000) {
+        Ok(index_writer) => index_writer,
+        Err(_) => {
+            let mut pending = PENDING_VISITS.lock().expect("visits lock");
+            for (url_hash, (count, last_visit)) in visits {
+                let visit = pending.entry(url_hash).or_insert((0, last_visit));
+                visit.0 += count;
+                visit.1 = visit.1.max(last_visit);
+            }
+            return 0;
+        }
+    };
+    let id_field = index.schema().get_field("id").expect("id");
+    for (url_hash, (count, last_visit)) in visits.iter() {
+        let doc = patch_source(url_hash, index, |json| {
+            let accessed = json["accessed_count"][0].as_i64().unwrap_or(0);
+            json["accessed_count"] = json!([accessed + count]);
+            json["last_accessed_at_i"] = json!([last_visit]);
+        });
+        if let Some(doc) = doc {
+            index_writer.delete_term(Term::from_field_text(id_field, url_hash));
+            index_writer.add_document(doc);
+        }
+    }
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+    visits.len()
+}
+
+// flushes the queued visits every VISIT_FLUSH_SECONDS so they are kept even
+// when the process is killed between visits
+pub fn spawn_visit_flusher() {
+    std::thread::spawn(|| loop {
+        std::thread::sleep(Duration::from_secs(VISIT_FLUSH_SECONDS));
+        if let Ok(index) = search_index() {
+            flush_visits(&index);
+        }
+    });
+}
+
+// pages get this much of their text as a summary until a model summarizes them
+const SUMMARY_CHARS: usize = 150;
+// pages summarized per model call by summarize_truncated
+const SUMMARY_BATCH: usize = 8;
+// tries at the index writer per batch before summarize_truncated skips it
+const SUMMARY_WRITER_ATTEMPTS: usize = 5;
+
+pub fn truncated_summary(body: &str) -> String {
     let mut short_body = body.to_string();
-    let mut new_len = 150;
+    let mut new_len = SUMMARY_CHARS.min(short_body.len());
     // prevent panics by finding a safe spot to slice
     while !short_body.is_char_boundary(new_len) {
         new_len += 1;
     }
     short_body.truncate(new_len);
-    Some(short_body)
+    short_body
 }
 
 #[cfg(feature = "ml")]
-pub fn summary(body: &str) -> Option<String> {
-    let config = SummarizationConfig::default();
-    if config.device.is_cuda() {
-        let result = panic::catch_unwind(|| {
-            let summarization_model =
-                SummarizationModel::new(config).expect("summarization_model fail");
-            let input = [body];
-            summarization_model.summarize(&input).join(" ")
-        });
+lazy_static::lazy_static! {
+    // loaded on the first summary and kept for the process, None when there is
+    // no gpu and summarize_on_cpu is off or the model fails to load
+    static ref SUMMARIZATION_MODEL: std::sync::Mutex<Option<SummarizationModel>> =
+        std::sync::Mutex::new(load_summarizer());
+}
 
-        match result {
-            Ok(results) => {
-                let results = results.replace("Please email your photos to jennifer.smith@mailonline.co.uk. Send us photos of your family and pets. Visit CNN.com/sport for more photos and videos of family and friends in the U.S.", "");
-                let results = results.trim();
-                Some(results.to_string())
-            }
-            _ => {
-                println!("sum error");
-                None
-            }
-        }
-    } else {
-        let mut short_body = body.to_string();
-        let mut new_len = 150;
-        // prevent panics by finding a safe spot to slice
-        while !short_body.is_char_boundary(new_len) {
-            new_len += 1;
+#[cfg(feature = "ml")]
+fn load_summarizer() -> Option<SummarizationModel> {
+    let mut config = SummarizationConfig::default();
+    if !config.device.is_cuda() && !CACHEDCONFIG.summarize_on_cpu {
+        return None;
+    }
+    let length = CACHEDCONFIG.summary_length.max(1);
+    config.max_length = Some(length);
+    config.min_length = config.min_length.min(length / 2);
+    match SummarizationModel::new(config) {
+        Ok(model) => Some(model),
+        Err(e) => {
+            println!("summarization model error {}", e);
+            None
         }
-        short_body.truncate(new_len);
-        Some(short_body.to_string())
     }
 }
 
-// from select.rs::text()
-pub fn text_ignore(node: &select::node::Node, ignore_index: &HashSet<usize>) -> String {
-    let mut string = String::new();
-    recur(node, &mut string, ignore_index);
-    return string;
+#[cfg(feature = "ml")]
+pub fn summarizer_available() -> bool {
+    SUMMARIZATION_MODEL
+        .lock()
+        .map(|model| model.is_some())
+        .unwrap_or(false)
+}
 
-    fn recur(node: &select::node::Node, string: &mut String, ignore_index: &HashSet<usize>) {
-        if ignore_index.get(&node.raw().index).is_none() {
-            if let Some(text) = node.as_text() {
+#[cfg(not(feature = "ml"))]
+pub fn summarizer_available() -> bool {
+    false
+}
+
+// one summary per body, None without a model or when the model fails
+#[cfg(feature = "ml")]
+pub fn summarize_batch(bodies: &[String]) -> Option<Vec<String>> {
+    let model = SUMMARIZATION_MODEL.lock().ok()?;
+    let model = model.as_ref()?;
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| model.summarize(bodies)));
+    match result {
+        Ok(Ok(results)) => Some(
+            results
+                .iter()
+                .map(|result| {
+                    result
+                        .replace("Please email your photos to jennifer.smith@mailonline.co.uk. Send us photos of your family and pets. Visit CNN.com/sport for more photos and videos of family and friends in the U.S.", "")
+                        .trim()
+                        .to_string()
+                })
+                .collect(),
+        ),
+        Ok(Err(e)) => {
+            println!("sum error {}", e);
+            None
+        }
+        _ => {
+            println!("sum error");
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "ml"))]
+pub fn summarize_batch(_bodies: &[String]) -> Option<Vec<String>> {
+    None
+}
+
+// the model summary when there is one, the start of the text otherwise.
+// summarize_truncated picks those up later
+pub fn summary(body: &str) -> Option<String> {
+    summarize_batch(&[body.to_string()])
+        .and_then(|mut summaries| summaries.pop())
+        .filter(|summary| !summary.is_empty())
+        .or_else(|| Some(truncated_summary(body)))
+}
+
+// documents whose summary is still the start of their text, with that text
+fn truncated_summaries(index: &Index) -> Vec<(String, String)> {
+    let schema = index.schema();
+    let id_field = schema.get_field("id").expect("id");
+    let summary_field = schema.get_field("summary").expect("summary");
+    let mut pending = vec![];
+    for_each_document(index, |doc| {
+        let summary = doc
+            .get_first(summary_field)
+            .and_then(|value| value.text())
+            .unwrap_or("");
+        let id = doc
+            .get_first(id_field)
+            .and_then(|value| value.text())
+            .unwrap_or("");
+        if summary.is_empty() || id.is_empty() {
+            return;
+        }
+        let content = read_source(id)
+            .and_then(|json| serde_json::from_str::<Value>(&json).ok())
+            .and_then(|json| json["content"][0].as_str().map(str::to_string))
+            .unwrap_or_default();
+        if content.len() > summary.len() && truncated_summary(&content) == summary {
+            pending.push((id.to_string(), content));
+        }
+    });
+    pending
+}
+
+// a visit flush or a refetch holds the writer for a moment, so wait a little
+fn summary_writer(index: &Index) -> Option<tantivy::IndexWriter> {
+    for attempt in 0..SUMMARY_WRITER_ATTEMPTS {
+        match index.writer(50_000_000) {
+            Ok(index_writer) => return Some(index_writer),
+            Err(tantivy::TantivyError::LockFailure(..))
+                if attempt + 1 < SUMMARY_WRITER_ATTEMPTS =>
+            {
+                std::thread::sleep(Duration::from_secs(2))
+            }
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+// replaces truncated summaries with model summaries, a batch per commit. the
+// writer is only taken for the commit so the server can keep writing between
+// batches. a batch that can not get it is left for the next run. returns how
+// many changed
+pub fn summarize_truncated() -> usize {
+    if !summarizer_available() {
+        return 0;
+    }
+    let index = search_index().expect("search index");
+    let id_field = index.schema().get_field("id").expect("id");
+    let mut count = 0;
+    for batch in truncated_summaries(&index).chunks(SUMMARY_BATCH) {
+        let bodies = batch
+            .iter()
+            .map(|(_, content)| content.clone())
+            .collect::<Vec<_>>();
+        let summaries = match summarize_batch(&bodies) {
+            Some(summaries) => summaries,
+            None => continue,
+        };
+        let mut index_writer = match summary_writer(&index) {
+            Some(index_writer) => index_writer,
+            None => {
+                println!("index is busy, skipping {} pages", batch.len());
+                continue;
+            }
+        };
+        for ((id, _), summary) in batch.iter().zip(summaries) {
+            if summary.is_empty() {
+                continue;
+            }
+            if let Some(doc) = patch_source(id, &index, |json| json["summary"] = json!([summary])) {
+                index_writer.delete_term(Term::from_field_text(id_field, id));
+                index_writer.add_document(doc);
+                count += 1;
+            }
+        }
+        index_writer.commit().expect("commit");
+        index_writer.wait_merging_threads().expect("merge");
+        println!("summarized {}", count);
+    }
+    count
+}
+
+// from select.rs::text()
+pub fn text_ignore(node: &select::node::Node, ignore_index: &HashSet<usize>) -> String {
+    let mut string = String::new();
+    recur(node, &mut string, ignore_index);
+    return string;
+
+    fn recur(node: &select::node::Node, string: &mut String, ignore_index: &HashSet<usize>) {
+        if ignore_index.get(&node.raw().index).is_none() {
+            if let Some(text) = node.as_text() {
                 string.push_str(text);
             }
             for child in node.children() {
@@ -570,19 +1474,24 @@ pub fn html_ignore(node: &select::node::Node, ignore_index: &HashSet<usize>) ->
         }
     }
 }
-pub fn just_content_text(document: &document::Document) -> Option<String> {
+// tags that never hold readable text
+const IGNORED_TAGS: [&str; 15] = [
+    "script", "noscript", "style", "nav", "footer", "form", "map", "source", "canvas", "object",
+    "param", "picture", "progress", "video", "svg",
+];
+
+fn ignored_nodes(document: &document::Document) -> HashSet<usize> {
     let mut ignore = HashSet::<usize>::new();
-    //remove html tags
-    for name in vec![
-        "script", "noscript", "style", "nav", "footer", "form", "map", "source", "canvas",
-        "object", "param", "picture", "progress", "video", "svg",
-    ]
-    .iter()
-    {
-        for node in document.find(select::predicate::Name(name.clone())) {
+    for name in IGNORED_TAGS.iter() {
+        for node in document.find(select::predicate::Name(*name)) {
             ignore.insert(node.raw().index);
         }
     }
+    ignore
+}
+
+pub fn just_content_text(document: &document::Document) -> Option<String> {
+    let ignore = ignored_nodes(document);
 
     match document.find(select::predicate::Name("body")).next() {
         Some(node) => Some(
@@ -598,45 +1507,765 @@ pub fn just_content_text(document: &document::Document) -> Option<String> {
     }
 }
 
+// class and id hints used to score blocks, roughly what readability uses
+const UNLIKELY_HINTS: [&str; 29] = [
+    "ad-break",
+    "advert",
+    "agegate",
+    "banner",
+    "breadcrumb",
+    "combx",
+    "comment",
+    "community",
+    "consent",
+    "cookie",
+    "disqus",
+    "extra",
+    "footer",
+    "gdpr",
+    "header",
+    "menu",
+    "modal",
+    "newsletter",
+    "pager",
+    "pagination",
+    "popup",
+    "promo",
+    "related",
+    "replies",
+    "share",
+    "sidebar",
+    "social",
+    "sponsor",
+    "subscribe",
+];
+const LIKELY_HINTS: [&str; 12] = [
+    "article", "blog", "body", "column", "content", "entry", "hentry", "main", "page", "post",
+    "story", "text",
+];
+const BLOCK_TAGS: [&str; 10] = [
+    "article",
+    "blockquote",
+    "div",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+    "dl",
+];
+
+fn hints(node: &select::node::Node) -> String {
+    format!(
+        "{} {}",
+        node.attr("class").unwrap_or(""),
+        node.attr("id").unwrap_or("")
+    )
+    .to_lowercase()
+}
+
+fn class_weight(node: &select::node::Node) -> f64 {
+    let hints = hints(node);
+    let mut weight = 0.0;
+    if UNLIKELY_HINTS.iter().any(|hint| hints.contains(hint)) {
+        weight -= 25.0;
+    }
+    if LIKELY_HINTS.iter().any(|hint| hints.contains(hint)) {
+        weight += 25.0;
+    }
+    weight
+}
+
+fn tag_weight(node: &select::node::Node) -> f64 {
+    match node.name().unwrap_or("") {
+        "article" => 10.0,
+        "div" | "section" | "main" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    }
+}
+
+fn is_ignored(node: &select::node::Node, ignore: &HashSet<usize>) -> bool {
+    let mut current = Some(*node);
+    while let Some(node) = current {
+        if ignore.contains(&node.index()) {
+            return true;
+        }
+        current = node.parent();
+    }
+    false
+}
+
+fn link_density(node: &select::node::Node, ignore: &HashSet<usize>) -> f64 {
+    let text_len = text_ignore(node, ignore).trim().len();
+    if text_len == 0 {
+        return 1.0;
+    }
+    let link_len: usize = node
+        .find(select::predicate::Name("a"))
+        .filter(|link| !is_ignored(link, ignore))
+        .map(|link| text_ignore(&link, ignore).trim().len())
+        .sum();
+    (link_len as f64 / text_len as f64).min(1.0)
+}
+
+// blocks that read like paragraphs. divs only count when they hold no other blocks
+fn is_paragraph(node: &select::node::Node) -> bool {
+    match node.name() {
+        Some("p") | Some("pre") | Some("td") | Some("blockquote") => true,
+        Some("div") => !node.children().any(|child| {
+            child
+                .name()
+                .map(|name| BLOCK_TAGS.contains(&name))
+                .unwrap_or(false)
+        }),
+        _ => false,
+    }
+}
+
+// scores blocks by text length, commas, link density and class/id hints and returns
+// the best block plus any siblings that look like part of the same article, along
+// with the nodes to leave out of them. None when nothing looks like an article
+pub fn main_content(document: &document::Document) -> Option<(Vec<usize>, HashSet<usize>)> {
+    let mut ignore = ignored_nodes(document);
+    for node in document.find(select::predicate::Name("aside")) {
+        ignore.insert(node.index());
+    }
+    for node in document.find(select::predicate::Element) {
+        let name = node.name().unwrap_or("");
+        if name == "html" || name == "body" || name == "article" || name == "main" {
+            continue;
+        }
+        let hints = hints(&node);
+        if UNLIKELY_HINTS.iter().any(|hint| hints.contains(hint))
+            && !LIKELY_HINTS.iter().any(|hint| hints.contains(hint))
+        {
+            ignore.insert(node.index());
+        }
+    }
+
+    let mut scores = HashMap::<usize, f64>::new();
+    for node in document.find(is_paragraph) {
+        if is_ignored(&node, &ignore) {
+            continue;
+        }
+        let text = text_ignore(&node, &ignore);
+        let text = text.trim();
+        if text.len() < 25 {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (text.len() as f64 / 100.0).min(3.0);
+        let parent = node.parent();
+        let grandparent = parent.and_then(|parent| parent.parent());
+        for (ancestor, share) in vec![(parent, 1.0), (grandparent, 0.5)] {
+            if let Some(ancestor) = ancestor {
+                *scores
+                    .entry(ancestor.index())
+                    .or_insert_with(|| tag_weight(&ancestor) + class_weight(&ancestor)) +=
+                    score * share;
+            }
+        }
+    }
+
+    let scored = scores
+        .iter()
+        .filter_map(|(index, score)| {
+            document
+                .nth(*index)
+                .map(|node| (*index, score * (1.0 - link_density(&node, &ignore))))
+        })
+        .collect::<HashMap<usize, f64>>();
+    let (best, best_score) = scored
+        .iter()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(index, score)| (*index, *score))?;
+    let best_node = document.nth(best)?;
+
+    let threshold = (best_score * 0.2).max(10.0);
+    let siblings = match best_node.parent() {
+        Some(parent) => parent
+            .children()
+            .filter(|sibling| {
+                if sibling.index() == best {
+                    return true;
+                }
+                if is_ignored(sibling, &ignore) {
+                    return false;
+                }
+                if scored.get(&sibling.index()).unwrap_or(&0.0) >= &threshold {
+                    return true;
+                }
+                // loose paragraphs next to the article body
+                sibling.name() == Some("p") && {
+                    let text = text_ignore(sibling, &ignore);
+                    text.trim().len() > 80 && link_density(sibling, &ignore) < 0.25
+                }
+            })
+            .map(|sibling| sibling.index())
+            .collect(),
+        None => vec![best],
+    };
+
+    let length: usize = siblings
+        .iter()
+        .filter_map(|index| document.nth(*index))
+        .map(|node| text_ignore(&node, &ignore).trim().len())
+        .sum();
+    // too little text to trust, the whole body is a better guess
+    if length < 250 {
+        return None;
+    }
+    Some((siblings, ignore))
+}
+
+// the article text, or all of the body text when no article is found
+pub fn main_content_text(document: &document::Document) -> Option<String> {
+    match main_content(document) {
+        Some((nodes, ignore)) => Some(
+            nodes
+                .iter()
+                .filter_map(|index| document.nth(*index))
+                .map(|node| text_ignore(&node, &ignore))
+                .collect::<Vec<_>>()
+                .join(" ")
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        None => just_content_text(document),
+    }
+}
+
 pub fn view_body(body: &str) -> String {
     let document = document::Document::from(body);
 
-    let mut ignore = HashSet::<usize>::new();
-    //remove html tags
-    for name in vec![
-        "script", "noscript", "style", "nav", "footer", "form", "map", "source", "canvas",
-        "object", "param", "picture", "progress", "video", "svg",
-    ]
-    .iter()
-    {
-        for node in document.find(select::predicate::Name(name.clone())) {
-            ignore.insert(node.raw().index);
-        }
+    if let Some((nodes, ignore)) = main_content(&document) {
+        return nodes
+            .iter()
+            .filter_map(|index| document.nth(*index))
+            .map(|node| html_ignore(&node, &ignore))
+            .collect::<Vec<_>>()
+            .join("");
     }
+    let ignore = ignored_nodes(&document);
     match document.find(select::predicate::Name("body")).next() {
         Some(node) => html_ignore(&node, &ignore),
         _ => "".to_string(),
     }
 }
 
+// what a page says about itself in meta tags and json-ld
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PageMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub lang: Option<String>,
+    pub canonical_url: Option<String>,
+    pub image: Option<String>,
+    pub meta_type: Option<String>,
+    pub published_at: Option<i64>,
+    pub modified_at: Option<i64>,
+}
+
+const JSON_LD_TYPES: [&str; 9] = [
+    "Article",
+    "NewsArticle",
+    "BlogPosting",
+    "TechArticle",
+    "ScholarlyArticle",
+    "Report",
+    "Product",
+    "Recipe",
+    "WebPage",
+];
+
+pub fn parse_date(date: &str) -> Option<i64> {
+    let date = date.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.timestamp());
+    }
+    for format in &["%Y-%m-%dT%H:%M:%S%z", "%Y-%m-%dT%H:%M:%S%.f%z"] {
+        if let Ok(date) = DateTime::parse_from_str(date, format) {
+            return Some(date.timestamp());
+        }
+    }
+    for format in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(date, format) {
+            return Some(date.timestamp());
+        }
+    }
+    NaiveDate::parse_from_str(date.get(0..10).unwrap_or(date), "%Y-%m-%d")
+        .ok()
+        .map(|date| date.and_hms(0, 0, 0).timestamp())
+}
+
+// json-ld values are a string, an object with name or url, or a list of either
+fn json_ld_text(value: &Value, key: &str) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.trim().to_string()).filter(|text| !text.is_empty()),
+        Value::Object(_) => json_ld_text(&value[key], key),
+        Value::Array(values) => {
+            let texts = values
+                .iter()
+                .filter_map(|value| json_ld_text(value, key))
+                .collect::<Vec<_>>();
+            if texts.is_empty() {
+                None
+            } else if key == "url" {
+                texts.into_iter().next()
+            } else {
+                Some(texts.join(", "))
+            }
+        }
+        _ => None,
+    }
+}
+
+fn json_ld_nodes(value: &Value, nodes: &mut Vec<Value>) {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                json_ld_nodes(value, nodes);
+            }
+        }
+        Value::Object(object) => {
+            if let Some(graph) = object.get("@graph") {
+                json_ld_nodes(graph, nodes);
+            }
+            nodes.push(value.clone());
+        }
+        _ => {}
+    }
+}
+
+fn json_ld_type(node: &Value) -> Option<String> {
+    let types = match &node["@type"] {
+        Value::String(kind) => vec![kind.clone()],
+        Value::Array(kinds) => kinds
+            .iter()
+            .filter_map(|kind| kind.as_str().map(str::to_string))
+            .collect(),
+        _ => vec![],
+    };
+    types
+        .into_iter()
+        .find(|kind| JSON_LD_TYPES.contains(&kind.as_str()))
+}
+
+pub fn page_meta(url: &str, document: &document::Document) -> PageMeta {
+    let mut page = PageMeta::default();
+    let base = url::Url::parse(url).ok();
+    let absolute = |link: &str| match &base {
+        Some(base) => base
+            .join(link.trim())
+            .map(|link| link.to_string())
+            .unwrap_or_else(|_| link.trim().to_string()),
+        None => link.trim().to_string(),
+    };
+
+    let mut tags = HashMap::<String, String>::new();
+    for node in document.find(select::predicate::Name("meta")) {
+        let key = node
+            .attr("property")
+            .or_else(|| node.attr("name"))
+            .or_else(|| node.attr("itemprop"))
+            .unwrap_or("")
+            .to_lowercase();
+        if let Some(content) = node.attr("content") {
+            if !key.is_empty() && !content.trim().is_empty() {
+                tags.entry(key)
+                    .or_insert_with(|| content.trim().to_string());
+            }
+        }
+    }
+    let tag = |keys: &[&str]| keys.iter().find_map(|key| tags.get(*key).cloned());
+
+    page.title = tag(&["og:title", "twitter:title"]);
+    page.description = tag(&["og:description", "twitter:description"]);
+    page.author = tag(&["author", "article:author", "twitter:creator", "dc.creator"])
+        .filter(|author| !author.starts_with("http"));
+    page.image = tag(&[
+        "og:image",
+        "og:image:url",
+        "twitter:image",
+        "twitter:image:src",
+    ])
+    .map(|image| absolute(&image));
+    page.meta_type = tag(&["og:type"]);
+    page.published_at = tag(&[
+        "article:published_time",
+        "datepublished",
+        "date",
+        "dc.date",
+        "dc.date.issued",
+    ])
+    .and_then(|date| parse_date(&date));
+    page.modified_at = tag(&[
+        "article:modified_time",
+        "og:updated_time",
+        "datemodified",
+        "last-modified",
+    ])
+    .and_then(|date| parse_date(&date));
+    page.canonical_url = document
+        .find(select::predicate::Name("link"))
+        .filter(|node| {
+            node.attr("rel")
+                .map(|rel| rel.eq_ignore_ascii_case("canonical"))
+                .unwrap_or(false)
+        })
+        .find_map(|node| node.attr("href"))
+        .map(|link| absolute(link))
+        .or_else(|| tag(&["og:url"]).map(|link| absolute(&link)));
+    page.lang = document
+        .find(select::predicate::Name("html"))
+        .find_map(|node| node.attr("lang"))
+        .map(str::to_string)
+        .or_else(|| tag(&["og:locale", "content-language"]));
+
+    // json-ld is the most specific so it wins over the meta tags
+    let mut nodes = vec![];
+    for script in document.find(select::predicate::Attr("type", "application/ld+json")) {
+        if let Ok(value) = serde_json::from_str::<Value>(&script.text()) {
+            json_ld_nodes(&value, &mut nodes);
+        }
+    }
+    if let Some((kind, node)) = nodes
+        .iter()
+        .filter_map(|node| json_ld_type(node).map(|kind| (kind, node)))
+        .min_by_key(|(kind, _)| JSON_LD_TYPES.iter().position(|known| known == kind))
+    {
+        let text = |key: &str| json_ld_text(&node[key], "name");
+        page.title = text("headline").or_else(|| text("name")).or(page.title);
+        page.description = text("description").or(page.description);
+        page.author = text("author")
+            .or_else(|| text("creator"))
+            .or_else(|| text("brand"))
+            .or(page.author);
+        page.image = json_ld_text(&node["image"], "url")
+            .map(|image| absolute(&image))
+            .or(page.image);
+        page.lang = text("inLanguage").or(page.lang);
+        page.published_at = text("datePublished")
+            .and_then(|date| parse_date(&date))
+            .or(page.published_at);
+        page.modified_at = text("dateModified")
+            .and_then(|date| parse_date(&date))
+            .or(page.modified_at);
+        page.meta_type = Some(kind.to_lowercase());
+    }
+
+    page.lang = page.lang.map(|lang| {
+        lang.split(|c| c == '-' || c == '_')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_lowercase()
+    });
+    page.meta_type = page.meta_type.map(|kind| kind.trim().to_lowercase());
+    page
+}
+
+// languages with their own analyzed fields. cjk has no stemmer and is split
+// into bigrams instead
+const LANGUAGE_ANALYZERS: [(&str, Option<Language>); 10] = [
+    ("en", Some(Language::English)),
+    ("de", Some(Language::German)),
+    ("fr", Some(Language::French)),
+    ("es", Some(Language::Spanish)),
+    ("it", Some(Language::Italian)),
+    ("pt", Some(Language::Portuguese)),
+    ("nl", Some(Language::Dutch)),
+    ("sv", Some(Language::Swedish)),
+    ("ru", Some(Language::Russian)),
+    ("cjk", None),
+];
+
+// analyzers are not saved with the index so they are added every time it is opened
+fn register_language_analyzers(index: &Index) {
+    for (language, stemmer) in LANGUAGE_ANALYZERS.iter() {
+        let analyzer = match stemmer {
+            Some(stemmer) => TextAnalyzer::from(SimpleTokenizer)
+                .filter(RemoveLongFilter::limit(40))
+                .filter(LowerCaser)
+                .filter(Stemmer::new(*stemmer)),
+            None => TextAnalyzer::from(CjkTokenizer).filter(LowerCaser),
+        };
+        index
+            .tokenizers()
+            .register(&format!("lang_{}", language), analyzer);
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30ff // hiragana and katakana
+        | 0x3400..=0x4dbf // cjk extension a
+        | 0x4e00..=0x9fff // cjk unified ideographs
+        | 0xac00..=0xd7af // hangul syllables
+        | 0xf900..=0xfaff // cjk compatibility ideographs
+    )
+}
+
+// chinese, japanese and korean are not written with spaces so runs of those
+// characters become overlapping bigrams. other words are kept whole
+#[derive(Clone)]
+pub struct CjkTokenizer;
+
+pub struct CjkTokenStream {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Tokenizer for CjkTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        let mut tokens = vec![];
+        let mut push = |from: usize, to: usize| {
+            tokens.push(Token {
+                offset_from: from,
+                offset_to: to,
+                position: tokens.len(),
+                text: text[from..to].to_string(),
+                position_length: 1,
+            })
+        };
+        let chars = text.char_indices().collect::<Vec<_>>();
+        let end = |i: usize| {
+            chars
+                .get(i)
+                .map(|(offset, _)| *offset)
+                .unwrap_or(text.len())
+        };
+        let mut i = 0;
+        while i < chars.len() {
+            let (offset, c) = chars[i];
+            if is_cjk(c) {
+                let mut run = i;
+                while run < chars.len() && is_cjk(chars[run].1) {
+                    run += 1;
+                }
+                if run - i == 1 {
+                    push(offset, end(i + 1));
+                }
+                for start in i..run.saturating_sub(1) {
+                    push(chars[start].0, end(start + 2));
+                }
+                i = run;
+            } else if c.is_alphanumeric() {
+                let mut run = i;
+                while run < chars.len() && chars[run].1.is_alphanumeric() && !is_cjk(chars[run].1) {
+                    run += 1;
+                }
+                push(offset, end(run));
+                i = run;
+            } else {
+                i += 1;
+            }
+        }
+        BoxTokenStream::from(CjkTokenStream { tokens, index: 0 })
+    }
+}
+
+impl TokenStream for CjkTokenStream {
+    fn advance(&mut self) -> bool {
+        self.index += 1;
+        self.index <= self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.index - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.index - 1]
+    }
+}
+
+// two letter code of the language the text is written in, when it is clear
+pub fn detect_language(text: &str) -> Option<String> {
+    let info = whatlang::detect(text)?;
+    if !info.is_reliable() {
+        return None;
+    }
+    let code = match info.lang() {
+        whatlang::Lang::Epo => "eo",
+        whatlang::Lang::Eng => "en",
+        whatlang::Lang::Rus => "ru",
+        whatlang::Lang::Cmn => "zh",
+        whatlang::Lang::Spa => "es",
+        whatlang::Lang::Por => "pt",
+        whatlang::Lang::Ita => "it",
+        whatlang::Lang::Ben => "bn",
+        whatlang::Lang::Fra => "fr",
+        whatlang::Lang::Deu => "de",
+        whatlang::Lang::Ukr => "uk",
+        whatlang::Lang::Kat => "ka",
+        whatlang::Lang::Ara => "ar",
+        whatlang::Lang::Hin => "hi",
+        whatlang::Lang::Jpn => "ja",
+        whatlang::Lang::Heb => "he",
+        whatlang::Lang::Yid => "yi",
+        whatlang::Lang::Pol => "pl",
+        whatlang::Lang::Amh => "am",
+        whatlang::Lang::Jav => "jv",
+        whatlang::Lang::Kor => "ko",
+        whatlang::Lang::Nob => "nb",
+        whatlang::Lang::Dan => "da",
+        whatlang::Lang::Swe => "sv",
+        whatlang::Lang::Fin => "fi",
+        whatlang::Lang::Tur => "tr",
+        whatlang::Lang::Nld => "nl",
+        whatlang::Lang::Hun => "hu",
+        whatlang::Lang::Ces => "cs",
+        whatlang::Lang::Ell => "el",
+        whatlang::Lang::Bul => "bg",
+        whatlang::Lang::Bel => "be",
+        whatlang::Lang::Mar => "mr",
+        whatlang::Lang::Kan => "kn",
+        whatlang::Lang::Ron => "ro",
+        whatlang::Lang::Slv => "sl",
+        whatlang::Lang::Hrv => "hr",
+        whatlang::Lang::Srp => "sr",
+        whatlang::Lang::Mkd => "mk",
+        whatlang::Lang::Lit => "lt",
+        whatlang::Lang::Lav => "lv",
+        whatlang::Lang::Est => "et",
+        whatlang::Lang::Tam => "ta",
+        whatlang::Lang::Vie => "vi",
+        whatlang::Lang::Urd => "ur",
+        whatlang::Lang::Tha => "th",
+        whatlang::Lang::Guj => "gu",
+        whatlang::Lang::Uzb => "uz",
+        whatlang::Lang::Pan => "pa",
+        whatlang::Lang::Aze => "az",
+        whatlang::Lang::Ind => "id",
+        whatlang::Lang::Tel => "te",
+        whatlang::Lang::Pes => "fa",
+        whatlang::Lang::Mal => "ml",
+        whatlang::Lang::Ori => "or",
+        whatlang::Lang::Mya => "my",
+        whatlang::Lang::Nep => "ne",
+        whatlang::Lang::Sin => "si",
+        whatlang::Lang::Khm => "km",
+        whatlang::Lang::Tuk => "tk",
+        whatlang::Lang::Aka => "ak",
+        whatlang::Lang::Zul => "zu",
+        whatlang::Lang::Sna => "sn",
+        whatlang::Lang::Afr => "af",
+        whatlang::Lang::Lat => "la",
+        whatlang::Lang::Slk => "sk",
+        whatlang::Lang::Cat => "ca",
+        whatlang::Lang::Tgl => "tl",
+        whatlang::Lang::Hye => "hy",
+    };
+    Some(code.to_string())
+}
+
+fn language_analyzer(lang: &str) -> Option<&'static str> {
+    match lang {
+        "zh" | "ja" | "ko" => Some("cjk"),
+        lang => LANGUAGE_ANALYZERS
+            .iter()
+            .find(|(language, _)| *language == lang)
+            .map(|(language, _)| *language),
+    }
+}
+
+pub fn add_language_fields(
+    doc: &mut Document,
+    schema: &Schema,
+    lang: &str,
+    title: &str,
+    body: &str,
+) {
+    if let Some(language) = language_analyzer(lang) {
+        for (name, text) in vec![("title", title), ("content", body)] {
+            let name = format!("{}_{}", name, language);
+            doc.add_text(schema.get_field(&name).expect("language field"), text);
+        }
+    }
+}
+
+pub fn add_page_meta(doc: &mut Document, schema: &Schema, page: &PageMeta) {
+    for (name, value) in vec![
+        ("author", &page.author),
+        ("lang", &page.lang),
+        ("canonical_url", &page.canonical_url),
+        ("image", &page.image),
+        ("meta_type", &page.meta_type),
+    ] {
+        if let Some(value) = value {
+            doc.add_text(schema.get_field(name).expect(name), value);
+        }
+    }
+    for (name, value) in vec![
+        ("published_at_i", page.published_at),
+        ("modified_at_i", page.modified_at),
+    ] {
+        if let Some(value) = value {
+            doc.add_i64(schema.get_field(name).expect(name), value);
+        }
+    }
+}
+
 pub fn remote_index(url: &str, index: &Index, meta: UrlMeta, getter: impl IndexGetter) {
-    let url_hash = md5_hash(&url);
+    let mut doc = if let Some(doc) = fetch_document(url, index, meta.clone(), getter) {
+        doc
+    } else {
+        // nothing to index
+        return;
+    };
+    let schema = index.schema();
+    let url_hash = doc
+        .get_first(schema.get_field("id").expect("id"))
+        .and_then(|value| value.text())
+        .map(str::to_string)
+        .unwrap_or_else(|| url_id(url));
+    if url_hash != url_id(url) && source_exists(&url_hash) {
+        // the canonical page is already indexed. record the new alias on it
+        println!("canonical {} already indexed", url);
+        add_alias(&url_hash, &normalize_url(url), index, meta);
+        return;
+    }
+    tagging::add_tags(&mut doc, index);
+    let json = schema.to_json(&doc);
+    register_simhash(&doc, &schema);
+    vectors::register_embedding(&doc, &schema);
+
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    index_writer.add_document(doc);
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+
+    write_source(&url_hash, json);
+}
+
+// builds the index document for a url without writing it anywhere
+pub fn fetch_document(
+    url: &str,
+    index: &Index,
+    meta: UrlMeta,
+    getter: impl IndexGetter,
+) -> Option<Document> {
     let parsed = url::Url::parse(&url).expect("url pase");
 
     let mut doc = tantivy::Document::default();
+    let mut canonical = None;
+    let mut content_hash = None;
     match getter.get_url(&url) {
         GetterResults::Text(body) => {
             doc.add_text(index.schema().get_field("content").expect("content"), &body);
-            let mut short_body = body;
-            let mut new_len = 150;
-            // prevent panics by finding a safe spot to slice
-            while !short_body.is_char_boundary(new_len) {
-                new_len += 1;
+            if let Some(lang) = detect_language(&body) {
+                doc.add_text(index.schema().get_field("lang").expect("lang"), &lang);
+                add_language_fields(&mut doc, &index.schema(), &lang, "", &body);
             }
-            short_body.truncate(new_len);
             doc.add_text(
                 index.schema().get_field("summary").expect("summary"),
-                &short_body,
+                &truncated_summary(&body),
             );
         }
 
@@ -651,10 +2280,16 @@ pub fn remote_index(url: &str, index: &Index, meta: UrlMeta, getter: impl IndexG
                 &body.as_str(),
             );
             let document = document::Document::from(body.as_str());
+            let mut page = page_meta(url, &document);
+            canonical = page.canonical_url.clone();
 
             let title = match document.find(select::predicate::Name("title")).next() {
-                Some(node) => node.text(),
-                _ => meta.title.unwrap_or_else(|| "".to_string()),
+                Some(node) if !node.text().trim().is_empty() => node.text(),
+                _ => page
+                    .title
+                    .clone()
+                    .or(meta.title)
+                    .unwrap_or_else(|| "".to_string()),
             };
 
             let meta_description = document
@@ -663,43 +2298,41 @@ pub fn remote_index(url: &str, index: &Index, meta: UrlMeta, getter: impl IndexG
                 .filter_map(|n| n.attr("content"))
                 .map(str::to_string)
                 .collect::<Vec<String>>();
-            let empty = "".to_string();
+            let empty = page.description.clone().unwrap_or_default();
             let description = match meta_description.first() {
                 Some(node) => node,
                 _ => &empty,
             };
 
-            let body = if let Some(content) = just_content_text(&document) {
+            let body = if let Some(content) = main_content_text(&document) {
                 content
             } else {
                 // nothing to index
-                return;
+                return None;
             };
             if body.split_whitespace().nth(100).is_some() {
                 let sim_hash = SimHash::with_hasher(SipHasherBuilder::from_seed(0, 0));
-                let content_hash =
+                let hash =
                     sim_hash.get_sim_hash(ShingleIterator::new(2, body.split(' ').collect()));
-                let dup = duplicate(&parsed.domain().unwrap().to_string(), &content_hash);
+                content_hash = Some(hash);
 
                 doc.add_i64(
                     index
                         .schema()
                         .get_field("content_hash")
                         .expect("content_hash"),
-                    content_hash.try_into().unwrap_or(0),
+                    // keep all 64 bits so refetches can tell a changed page apart
+                    hash as i64,
                 );
-                add_hash(&parsed.domain().expect("domain"), content_hash);
-
-                if !dup {
-                    doc.add_text(index.schema().get_field("content").expect("content"), &body);
-                    if let Some(summary) = summary(&body) {
-                        doc.add_text(
-                            index.schema().get_field("summary").expect("summary"),
-                            &summary,
-                        );
-                    }
-                } else {
-                    doc.add_i64(index.schema().get_field("duplicate").expect("duplicate"), 1);
+
+                // duplicates keep their text so they still show up, grouped
+                // under the original
+                doc.add_text(index.schema().get_field("content").expect("content"), &body);
+                if let Some(summary) = summary(&body) {
+                    doc.add_text(
+                        index.schema().get_field("summary").expect("summary"),
+                        &summary,
+                    );
                 }
             } else {
                 // add the text anyway its small even if it is a dup
@@ -715,6 +2348,13 @@ pub fn remote_index(url: &str, index: &Index, meta: UrlMeta, getter: impl IndexG
             );
             doc.add_text(index.schema().get_field("title").expect("title"), &title);
 
+            // what the text looks like beats what the template declares
+            page.lang = detect_language(&body).or(page.lang);
+            add_page_meta(&mut doc, &index.schema(), &page);
+            if let Some(lang) = &page.lang {
+                add_language_fields(&mut doc, &index.schema(), lang, &title, &body);
+            }
+
             let _found_urls = document
                 .find(select::predicate::Name("a"))
                 .filter_map(|n| n.attr("href"))
@@ -745,12 +2385,41 @@ pub fn remote_index(url: &str, index: &Index, meta: UrlMeta, getter: impl IndexG
         _ => {}
     }
 
+    // keep the page under its canonical url and remember the url it was found at
+    let url = match canonical.filter(|canonical| canonical_usable(url, canonical)) {
+        Some(canonical) => {
+            if normalize_url(&canonical) != normalize_url(url) {
+                doc.add_text(
+                    index.schema().get_field("aliases").expect("aliases"),
+                    &normalize_url(url),
+                );
+            }
+            canonical
+        }
+        None => url.to_string(),
+    };
+    let url_hash = url_id(&url);
+
+    if let Some(original) = content_hash.and_then(|hash| near_duplicate_of(hash, &url_hash)) {
+        doc.add_i64(index.schema().get_field("duplicate").expect("duplicate"), 1);
+        doc.add_text(
+            index
+                .schema()
+                .get_field("duplicate_of")
+                .expect("duplicate_of"),
+            &original,
+        );
+    }
+
     doc.add_text(index.schema().get_field("url").expect("url"), &url);
 
     doc.add_text(
         index.schema().get_field("domain").expect("domain"),
         parsed.domain().unwrap_or(""),
     );
+    if let Some(host) = url_host(&url) {
+        doc.add_text(index.schema().get_field("host").expect("host"), &host);
+    }
     doc.add_date(
         index.schema().get_field("added_at").expect("added_at"),
         &Utc::now(),
@@ -796,19 +2465,49 @@ pub fn remote_index(url: &str, index: &Index, meta: UrlMeta, getter: impl IndexG
         index.schema().get_field("bookmarked").expect("bookmarked"),
         0,
     );
-    doc.add_text(index.schema().get_field("id").expect("id"), &url_hash);
-    let json = index.schema().to_json(&doc);
-
-    let mut index_writer = index.writer(50_000_000).expect("writer");
-    index_writer.add_document(doc);
-    index_writer.commit().expect("commit");
-    index_writer.wait_merging_threads().expect("merge");
-
-    write_source(&url_hash, json);
-}
-pub fn index_url(url: String, meta: UrlMeta, index: Option<&Index>, getter: impl IndexGetter) {
-    if CACHEDCONFIG.indexer_enabled {
-        let i;
+    doc.add_i64(
+        index
+            .schema()
+            .get_field("fetched_at_i")
+            .expect("fetched_at_i"),
+        Utc::now().timestamp(),
+    );
+    if let Some(status) = meta
+        .reading_status
+        .as_deref()
+        .filter(|status| READING_STATUSES.contains(status))
+    {
+        let now = Utc::now().timestamp();
+        doc.add_text(
+            index
+                .schema()
+                .get_field("reading_status")
+                .expect("reading_status"),
+            status,
+        );
+        doc.add_i64(
+            index
+                .schema()
+                .get_field("reading_status_at_i")
+                .expect("reading_status_at_i"),
+            now,
+        );
+        if status == "unread" {
+            doc.add_i64(
+                index
+                    .schema()
+                    .get_field("queued_at_i")
+                    .expect("queued_at_i"),
+                now,
+            );
+        }
+    }
+    doc.add_text(index.schema().get_field("id").expect("id"), &url_hash);
+    Some(doc)
+}
+pub fn index_url(url: String, meta: UrlMeta, index: Option<&Index>, getter: impl IndexGetter) {
+    if CACHEDCONFIG.indexer_enabled {
+        let i;
         let index = match index {
             Some(index) => index,
             None => {
@@ -824,12 +2523,19 @@ pub fn index_url(url: String, meta: UrlMeta, index: Option<&Index>, getter: impl
         } else {
             url
         };
-        let url_hash = md5_hash(&url);
+        let url_hash = url_id(&url);
         println!("indexing {} {}", &url_hash, &url);
         if url_skip(&url) {
             println!("skip {}", url);
-        } else if let Some(_doc_address) = find_url(&url, &index) {
-            println!("have {}", url);
+        } else if let Some(doc_address) = find_url(&url, &index) {
+            if refetch_due(&index, doc_address, &meta) {
+                // the stored url, which is the canonical one when the page has it
+                let url = document_url(&index, doc_address).unwrap_or(url);
+                println!("refetch {}", url);
+                refetch_url(&url, &index, getter);
+            } else {
+                println!("have {}", url);
+            }
         } else if source_exists(&url_hash) {
             println!("cached file {}", url);
             let mut index_writer = index.writer(50_000_000).expect("writer");
@@ -845,93 +2551,1038 @@ pub fn index_url(url: String, meta: UrlMeta, index: Option<&Index>, getter: impl
         };
     }
 }
-pub fn source_exists(filename: &str) -> bool {
+// frequently revisited urls get fetched again so we can keep a copy of each version
+pub fn refetch_due(index: &Index, doc_address: tantivy::DocAddress, meta: &UrlMeta) -> bool {
+    if CACHEDCONFIG.refetch_after_days <= 0 {
+        return false;
+    }
+    let searcher = searcher(&index);
+    let doc = searcher.doc(doc_address).expect("doc");
+    let schema = index.schema();
+    let stored_i64 = |name: &str| {
+        doc.get_first(schema.get_field(name).expect("field"))
+            .map(|value| value.i64_value())
+    };
+
+    let visits = stored_i64("accessed_count")
+        .unwrap_or(0)
+        .max(meta.access_count.unwrap_or(0));
+    let fetched_at = stored_i64("fetched_at_i")
+        .or_else(|| stored_i64("added_at_i"))
+        .unwrap_or(0);
+    visits >= CACHEDCONFIG.refetch_min_visits
+        && Utc::now().timestamp() - fetched_at > CACHEDCONFIG.refetch_after_days * 24 * 60 * 60
+}
+
+// fields the user or the browser sync set. a refetch keeps them from the old copy
+const KEPT_ON_REFETCH: [&str; 17] = [
+    "id",
+    "url",
+    "aliases",
+    "added_at",
+    "added_at_i",
+    "last_accessed_at",
+    "last_accessed_at_i",
+    "accessed_count",
+    "bookmarked",
+    "pinned",
+    "hidden",
+    "tags",
+    "notes",
+    "highlights",
+    "reading_status",
+    "reading_status_at_i",
+    "queued_at_i",
+];
+
+// the fetched document with the fields in KEPT_ON_REFETCH taken from the old
+// json. tags, aliases and highlights of both are combined
+fn keep_user_fields(doc: &Document, old_json: &Value, schema: &Schema) -> Document {
+    let mut json: Value = serde_json::from_str(&schema.to_json(doc)).expect("json round trip");
+    for key in KEPT_ON_REFETCH.iter() {
+        if let Some(old_value) = old_json.get(key) {
+            if *key == "tags" || *key == "aliases" || *key == "highlights" {
+                let mut tags = json[key].as_array().cloned().unwrap_or_default();
+                for tag in old_value.as_array().cloned().unwrap_or_default() {
+                    if !tags.contains(&tag) {
+                        tags.push(tag);
+                    }
+                }
+                json[key] = json!(tags);
+            } else {
+                json[key] = old_value.clone();
+            }
+        }
+    }
+    schema
+        .parse_document(&json.to_string())
+        .expect("doc from json")
+}
+
+// returns true when the content changed and the old copy was kept as a version
+pub fn refetch_url(url: &str, index: &Index, getter: impl IndexGetter) -> bool {
+    let url_hash = indexed_id(url, index);
+    let (doc, changed) = match refetch_document(url, index, getter) {
+        Some(refetched) => refetched,
+        None => return false,
+    };
+    let schema = index.schema();
+    let json = schema.to_json(&doc);
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    index_writer.delete_term(Term::from_field_text(
+        schema.get_field("id").expect("id"),
+        &url_hash,
+    ));
+    index_writer.add_document(doc);
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+    write_source(&url_hash, json);
+    changed
+}
+
+// the new document for an indexed url and whether the page changed. the old
+// copy is kept as a version, the caller replaces the document and its source
+pub fn refetch_document(
+    url: &str,
+    index: &Index,
+    getter: impl IndexGetter,
+) -> Option<(Document, bool)> {
+    let url_hash = indexed_id(url, index);
+    let old_json_string = read_source(&url_hash)?;
+    let old_json: Value = serde_json::from_str(&old_json_string).expect("cached json parse fail!");
+    let mut meta = UrlMeta::default();
+    meta.title = old_json["title"][0].as_str().map(str::to_string);
+
+    let schema = index.schema();
+    let (doc, changed) = match fetch_document(url, index, meta, getter) {
+        Some(doc) => {
+            let old_hash = old_json["content_hash"][0].as_i64();
+            let new_hash = doc
+                .get_first(schema.get_field("content_hash").expect("content_hash"))
+                .map(|value| value.i64_value());
+            if old_hash != new_hash {
+                let doc = keep_user_fields(&doc, &old_json, &schema);
+                (doc, true)
+            } else {
+                (update_fetched_at(&old_json, index), false)
+            }
+        }
+        // could not get the page this time. try again later
+        None => (update_fetched_at(&old_json, index), false),
+    };
+
+    if changed {
+        let fetched_at = old_json["fetched_at_i"][0]
+            .as_i64()
+            .or_else(|| old_json["added_at_i"][0].as_i64())
+            .unwrap_or_else(|| Utc::now().timestamp());
+        write_version(&url_hash, &old_json_string, fetched_at);
+    }
+
+    register_simhash(&doc, &schema);
+    if changed {
+        vectors::register_embedding(&doc, &schema);
+    }
+    Some((doc, changed))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Notes {
+    pub notes: String,
+    pub highlights: Vec<String>,
+}
+
+pub fn read_notes(url_hash: &str) -> Option<Notes> {
+    let json: Value = serde_json::from_str(&read_source(url_hash)?).ok()?;
+    Some(Notes {
+        notes: json["notes"][0].as_str().unwrap_or("").to_string(),
+        highlights: json["highlights"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect(),
+    })
+}
+
+// replaces the note when notes is set and adds or removes highlights
+pub fn update_notes(
+    url_hash: &str,
+    index: &Index,
+    notes: Option<String>,
+    highlights_add: Vec<String>,
+    highlights_remove: Vec<String>,
+) -> Option<Notes> {
+    if !source_exists(url_hash) {
+        return None;
+    }
+    let mut meta = UrlMeta::default();
+    meta.notes = notes;
+    meta.highlights_add = Some(highlights_add);
+    meta.highlights_remove = Some(highlights_remove);
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    index_writer.delete_term(Term::from_field_text(
+        index.schema().get_field("id").expect("id"),
+        url_hash,
+    ));
+    update_cached(url_hash, index, meta, &mut index_writer);
+    index_writer.wait_merging_threads().expect("merge");
+    read_notes(url_hash)
+}
+
+// visible pages with a reading status, the ones added to the list first. that
+// is when they were queued, or indexed for pages that never were
+pub fn reading_queue(index: &Index, status: &str, limit: usize) -> Vec<Document> {
+    let schema = index.schema();
+    let searcher = searcher(index);
+    let query = BooleanQuery::from(
+        vec![
+            Term::from_field_text(
+                schema.get_field("reading_status").expect("reading_status"),
+                status,
+            ),
+            Term::from_field_i64(schema.get_field("hidden").expect("hidden"), 0),
+        ]
+        .into_iter()
+        .map(|term| {
+            let query: Box<dyn Query> = Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+            (Occur::Must, query)
+        })
+        .collect::<Vec<_>>(),
+    );
+    let count = searcher.search(&query, &Count).expect("count");
+    if count == 0 {
+        return vec![];
+    }
+    let queued_at = schema.get_field("queued_at_i").expect("queued_at_i");
+    let added_at = schema.get_field("added_at_i").expect("added_at_i");
+    let mut docs = searcher
+        .search(&query, &TopDocs::with_limit(count))
+        .expect("search")
+        .into_iter()
+        .filter_map(|(_, address)| searcher.doc(address).ok())
+        .collect::<Vec<_>>();
+    docs.sort_by_key(|doc| {
+        doc.get_first(queued_at)
+            .or_else(|| doc.get_first(added_at))
+            .map(|value| value.i64_value())
+            .unwrap_or(0)
+    });
+    docs.truncate(limit);
+    docs
+}
+
+// removes the sources, versions and vectors of documents already deleted from
+// the index. run after the commit so a failed one leaves them in place
+pub fn remove_document_files(ids: &[String]) {
+    for url_hash in ids {
+        delete_versions(url_hash);
+        SOURCE_STORE.delete(url_hash).expect("delete source");
+    }
+    vectors::remove_embeddings(ids);
+}
+
+fn update_fetched_at(old_json: &Value, index: &Index) -> Document {
+    let mut json = old_json.clone();
+    json["fetched_at_i"] = json!(vec![Utc::now().timestamp()]);
+    index
+        .schema()
+        .parse_document(&json.to_string())
+        .expect("doc from json")
+}
+
+// where the compressed source json of each document lives. the free functions
+// below handle compression so a store only moves bytes around
+pub trait SourceStore {
+    fn exists(&self, url_hash: &str) -> bool;
+    fn get(&self, url_hash: &str) -> std::io::Result<Option<Vec<u8>>>;
+    fn put(&self, url_hash: &str, bytes: &[u8]) -> std::io::Result<()>;
+    fn delete(&self, url_hash: &str) -> std::io::Result<()>;
+    fn hashes(&self) -> std::io::Result<Vec<String>>;
+    fn keys_with_prefix(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        Ok(self
+            .hashes()?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect())
+    }
+    // reclaim space from replaced and deleted sources. returns bytes saved
+    fn compact(&self) -> std::io::Result<u64> {
+        Ok(0)
+    }
+}
+
+// source/xx/<hash>.jsonc, one brotli file per document
+pub struct FileSourceStore {
+    root: std::path::PathBuf,
+}
+
+impl FileSourceStore {
+    pub fn new(root: std::path::PathBuf) -> Self {
+        FileSourceStore { root }
+    }
+
+    fn path(&self, url_hash: &str) -> std::path::PathBuf {
+        let mut dir = url_hash.to_string();
+        dir.truncate(2);
+        self.root.join(dir).join(format!("{}.jsonc", url_hash))
+    }
+}
+
+impl SourceStore for FileSourceStore {
+    fn exists(&self, url_hash: &str) -> bool {
+        self.path(url_hash).exists()
+    }
+
+    fn get(&self, url_hash: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(url_hash)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, url_hash: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let path = self.path(url_hash);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, bytes)
+    }
+
+    fn delete(&self, url_hash: &str) -> std::io::Result<()> {
+        match fs::remove_file(self.path(url_hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn hashes(&self) -> std::io::Result<Vec<String>> {
+        let entries = glob(&format!(
+            "{}/*/*.jsonc",
+            self.root.to_str().expect("source_dir")
+        ))
+        .expect("Failed to read glob pattern");
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|file| {
+                file.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(str::to_string)
+            })
+            .collect())
+    }
+
+    // only the directory the prefix falls in is listed
+    fn keys_with_prefix(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let mut dir = prefix.to_string();
+        dir.truncate(2);
+        let entries = glob(&format!(
+            "{}/{}/{}*.jsonc",
+            self.root.to_str().expect("source_dir"),
+            glob::Pattern::escape(&dir),
+            glob::Pattern::escape(prefix)
+        ))
+        .expect("Failed to read glob pattern");
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|file| {
+                file.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(str::to_string)
+            })
+            .collect())
+    }
+}
+
+// a single append only file. each record is
+// [hash length u8][hash][payload length u32 le][payload]
+// and an empty payload deletes the hash. the offsets are rebuilt by scanning
+// the file and picked up again when another process appends to it or swaps in
+// a compacted copy.
+pub struct PackSourceStore {
+    path: std::path::PathBuf,
+    state: std::sync::Mutex<PackState>,
+}
+
+#[derive(Default)]
+struct PackState {
+    offsets: HashMap<String, (u64, u32)>,
+    scanned_to: u64,
+    // inode of the scanned file, compaction replaces it with a new one
+    file_id: u64,
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+// no inode to compare, a shorter file still gives the swap away
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+impl PackSourceStore {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("pack dir");
+        }
+        PackSourceStore {
+            path,
+            state: std::sync::Mutex::new(PackState::default()),
+        }
+    }
+
+    fn state(&self) -> std::io::Result<std::sync::MutexGuard<'_, PackState>> {
+        let mut state = self.state.lock().expect("pack lock");
+        self.scan(&mut state)?;
+        Ok(state)
+    }
+
+    // reads the records added since the last scan, or the whole file again
+    // when it was replaced
+    fn scan(&self, state: &mut PackState) -> std::io::Result<()> {
+        let (len, id) = match fs::metadata(&self.path) {
+            Ok(metadata) => (metadata.len(), file_id(&metadata)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, 0),
+            Err(e) => return Err(e),
+        };
+        if len < state.scanned_to || id != state.file_id {
+            *state = PackState {
+                file_id: id,
+                ..PackState::default()
+            };
+        }
+        if len > state.scanned_to {
+            let mut reader = std::io::BufReader::new(File::open(&self.path)?);
+            std::io::Seek::seek(&mut reader, std::io::SeekFrom::Start(state.scanned_to))?;
+            let mut position = state.scanned_to;
+            loop {
+                let mut hash_len = [0u8; 1];
+                let mut payload_len = [0u8; 4];
+                if position + 1 > len || reader.read_exact(&mut hash_len).is_err() {
+                    break;
+                }
+                let mut hash = vec![0u8; hash_len[0] as usize];
+                let header = 1 + hash.len() as u64 + 4;
+                if position + header > len
+                    || reader.read_exact(&mut hash).is_err()
+                    || reader.read_exact(&mut payload_len).is_err()
+                {
+                    break;
+                }
+                let payload_len = u32::from_le_bytes(payload_len);
+                // a record still being written by someone else. read it next time
+                if position + header + payload_len as u64 > len {
+                    break;
+                }
+                std::io::Seek::seek(&mut reader, std::io::SeekFrom::Current(payload_len as i64))?;
+                let hash = String::from_utf8_lossy(&hash).to_string();
+                if payload_len == 0 {
+                    state.offsets.remove(&hash);
+                } else {
+                    state.offsets.insert(hash, (position + header, payload_len));
+                }
+                position += header + payload_len as u64;
+            }
+            state.scanned_to = position;
+        }
+        Ok(())
+    }
+
+    fn read_at(&self, offset: u64, len: u32) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path)?;
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(offset))?;
+        let mut bytes = vec![0u8; len as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // the caller holds the state lock so a compaction can not drop the record
+    fn append_to(path: &Path, url_hash: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(url_hash.len() + bytes.len() + 5);
+        record.push(url_hash.len() as u8);
+        record.extend_from_slice(url_hash.as_bytes());
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(bytes);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&record)
+    }
+}
+
+impl SourceStore for PackSourceStore {
+    fn exists(&self, url_hash: &str) -> bool {
+        self.state()
+            .map(|state| state.offsets.contains_key(url_hash))
+            .unwrap_or(false)
+    }
+
+    fn get(&self, url_hash: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let state = self.state()?;
+        match state.offsets.get(url_hash) {
+            Some((offset, len)) => self.read_at(*offset, *len).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, url_hash: &str, bytes: &[u8]) -> std::io::Result<()> {
+        let _state = self.state.lock().expect("pack lock");
+        PackSourceStore::append_to(&self.path, url_hash, bytes)
+    }
+
+    fn delete(&self, url_hash: &str) -> std::io::Result<()> {
+        let state = self.state()?;
+        if state.offsets.contains_key(url_hash) {
+            PackSourceStore::append_to(&self.path, url_hash, &[])
+        } else {
+            Ok(())
+        }
+    }
+
+    fn hashes(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.state()?.offsets.keys().cloned().collect())
+    }
+
+    // copies the live records to a new pack and swaps it in under the lock so
+    // no append from this process is lost. this also drops a record torn by a
+    // crash in the middle of a write. other processes see the new file and
+    // rescan it, but anything they append during the copy is lost so run it
+    // while the server is stopped
+    fn compact(&self) -> std::io::Result<u64> {
+        let mut state = self.state()?;
+        let before = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        let compacted = self.path.with_extension("compact");
+        let _ = fs::remove_file(&compacted);
+        for (url_hash, (offset, len)) in state.offsets.iter() {
+            PackSourceStore::append_to(&compacted, url_hash, &self.read_at(*offset, *len)?)?;
+        }
+        if compacted.exists() {
+            fs::rename(&compacted, &self.path)?;
+        } else {
+            let _ = fs::remove_file(&self.path);
+        }
+        *state = PackState::default();
+        self.scan(&mut state)?;
+        let after = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        Ok(before.saturating_sub(after))
+    }
+}
+
+// files or pack
+pub fn open_source_store(kind: &str) -> Box<dyn SourceStore + Send + Sync> {
     let index_path = Path::new(BASE_INDEX_DIR.as_str());
-    let source_path = index_path.join("source");
-    let mut dir = filename.clone().to_string();
-    dir.truncate(2);
-    let source_path = source_path.join(dir);
-    source_path.join(format!("{}.jsonc", filename)).exists()
+    match kind {
+        "pack" => Box::new(PackSourceStore::new(
+            index_path.join("pack").join("sources.pack"),
+        )),
+        _ => Box::new(FileSourceStore::new(index_path.join("source"))),
+    }
 }
 
-pub fn write_source(url_hash: &str, json: String) {
+// the old copies of pages, kept next to the sources in the same kind of store
+// under <hash>@<fetched_at>
+pub fn open_version_store(kind: &str) -> Box<dyn SourceStore + Send + Sync> {
     let index_path = Path::new(BASE_INDEX_DIR.as_str());
-    let source_path = index_path.join("source");
-    let mut dir = url_hash.clone().to_string();
-    dir.truncate(2);
-    let source_path = source_path.join(dir);
-    if std::fs::create_dir(source_path.clone()).is_ok() {}
-    let output = File::create(source_path.join(format!("{}.jsonc", url_hash))).expect("write file");
-    let mut writer = brotli::CompressorWriter::new(output, 4096, 11, 22);
-    writer
-        .write_all(json.as_bytes())
+    let store: Box<dyn SourceStore + Send + Sync> = match kind {
+        "pack" => Box::new(PackSourceStore::new(
+            index_path.join("pack").join("versions.pack"),
+        )),
+        _ => Box::new(FileSourceStore::new(index_path.join("versions"))),
+    };
+    adopt_legacy_versions(store.as_ref());
+    store
+}
+
+// versions used to be written as versions/xx/<hash>/<fetched_at>.jsonc outside
+// the store. they are moved in the first time the store is opened
+fn adopt_legacy_versions(store: &(dyn SourceStore + Send + Sync)) {
+    let versions_path = Path::new(BASE_INDEX_DIR.as_str()).join("versions");
+    let files = match glob(&format!(
+        "{}/*/*/*.jsonc",
+        versions_path.to_str().expect("versions_dir")
+    )) {
+        Ok(files) => files,
+        Err(_) => return,
+    };
+    for file in files.filter_map(|entry| entry.ok()) {
+        let url_hash = file
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str());
+        let fetched_at = file
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<i64>().ok());
+        if let (Some(url_hash), Some(fetched_at)) = (url_hash, fetched_at) {
+            let bytes = fs::read(&file).expect("read old version");
+            store
+                .put(&version_key(url_hash, fetched_at), &bytes)
+                .expect("write version");
+            fs::remove_file(&file).expect("remove old version");
+            if let Some(dir) = file.parent() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+    }
+}
+
+fn compress(json: &str) -> Vec<u8> {
+    let mut bytes = vec![];
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut bytes, 4096, 11, 22);
+        writer.write_all(json.as_bytes()).expect("compress source");
+    }
+    bytes
+}
+
+fn decompress(bytes: &[u8]) -> std::io::Result<String> {
+    let mut reader = brotli::Decompressor::new(
+        bytes, 4096, // buffer size
+    );
+    let mut json = String::new();
+    reader.read_to_string(&mut json)?;
+    Ok(json)
+}
+
+pub fn source_exists(filename: &str) -> bool {
+    SOURCE_STORE.exists(filename)
+}
+
+pub fn write_source(url_hash: &str, json: String) {
+    SOURCE_STORE
+        .put(url_hash, &seal(compress(&json)))
         .expect("write source file");
-    //output.write_all(json.as_bytes()).expect("write");
 }
+
 pub fn read_source(url_hash: &str) -> Option<String> {
-    let index_path = Path::new(BASE_INDEX_DIR.as_str());
-    let source_path = index_path.join("source");
-    let mut dir = url_hash.clone().to_string();
-    dir.truncate(2);
-    let source_path = source_path.join(dir);
-    if let Ok(input) = File::open(source_path.join(format!("{}.jsonc", url_hash))) {
-        let mut reader = brotli::Decompressor::new(
-            input, 4096, // buffer size
-        );
-        let mut json = String::new();
-        reader.read_to_string(&mut json).expect("read source file");
-        Some(json)
-    } else {
-        None
+    read_source_checked(url_hash).expect("read source file")
+}
+
+// Ok(None) when there is no source, Err when it is truncated or corrupt
+pub fn read_source_checked(url_hash: &str) -> std::io::Result<Option<String>> {
+    match SOURCE_STORE.get(url_hash)? {
+        Some(bytes) => decompress(&unseal(bytes)?).map(Some),
+        None => Ok(None),
     }
 }
 
-pub fn duplicate(domain: &str, content_hash: &u64) -> bool {
-    let index = hash_index(BASE_INDEX_DIR.as_str()).expect("hash index");
-    let searcher = searcher(&index);
-    let query_parser = QueryParser::for_index(
-        &index,
-        vec![index.schema().get_field("domain").expect("domain field")],
-    );
+// copies every source to the other store kind, switches the settings over and
+// only then removes them from the old one. a crash while copying leaves the old
+// store in use and the migration can be run again. versions move the same way.
+// returns the sources and versions moved
+pub fn migrate_source_store(to: &str) -> usize {
+    let mut settings = read_settings();
+    if settings.source_store == to {
+        return 0;
+    }
+    // the versions store is opened first so it takes in any old version files
+    let stores = [
+        (&*VERSION_STORE, open_version_store(to)),
+        (&*SOURCE_STORE, open_source_store(to)),
+    ];
+    let mut copied = vec![];
+    let mut moved = 0;
+    for (store, target) in stores.iter() {
+        let hashes = store.hashes().expect("source list");
+        for url_hash in hashes.iter() {
+            if let Some(bytes) = store.get(url_hash).expect("read source") {
+                target.put(url_hash, &bytes).expect("write source");
+                moved += 1;
+                if moved % 10000 == 0 {
+                    println!("copied {}", moved);
+                }
+            }
+        }
+        target.compact().expect("compact sources");
+        copied.push(hashes);
+    }
+    settings.source_store = to.to_string();
+    write_settings(&settings);
 
-    let domain_hash = md5_hash(&domain);
-    let query = query_parser
-        .parse_query(&format!("\"!{}\"", domain_hash))
-        .expect("query parse for domain match");
+    for ((store, _), hashes) in stores.iter().zip(copied.iter()) {
+        for url_hash in hashes.iter() {
+            store.delete(url_hash).expect("remove source");
+        }
+        store.compact().expect("compact sources");
+    }
+    moved
+}
 
-    let top_docs = searcher
-        .search(&query, &TopDocs::with_limit(1))
-        .expect("search");
-    let content_hash_bytes = content_hash.to_le_bytes();
-    for result in top_docs {
-        for s in searcher
-            .doc(result.1)
-            .expect("doc")
-            .get_all(index.schema().get_field("hashes").expect("f"))
+// folds documents whose urls normalize to the same id, or that are an alias of
+// another document, into one document under that id. visits are added up, tags
+// and aliases combined and pins and bookmarks kept. returns the documents removed
+pub fn merge_duplicates() -> usize {
+    let index = search_index().expect("index");
+    let schema = index.schema();
+
+    let mut documents = vec![];
+    for_each_document(&index, |doc| {
+        let id = doc
+            .get_first(schema.get_field("id").expect("id"))
+            .and_then(|value| value.text())
+            .unwrap_or("")
+            .to_string();
+        // the stored document lacks the page text, so one without a source is
+        // left for fsck to refetch rather than merged into something hollow
+        match read_source(&id).and_then(|json| serde_json::from_str::<Value>(&json).ok()) {
+            Some(json) => documents.push((id, json)),
+            None => println!("skipping {}, it has no source", id),
+        }
+    });
+
+    let mut owners = HashMap::<String, String>::new();
+    for (_, json) in documents.iter() {
+        if let Some(url) = json["url"][0].as_str() {
+            for alias in json["aliases"].as_array().cloned().unwrap_or_default() {
+                if let Some(alias) = alias.as_str() {
+                    owners.insert(md5_hash(alias), url_id(url));
+                }
+            }
+        }
+    }
+    let mut groups = HashMap::<String, Vec<(String, Value)>>::new();
+    for (id, json) in documents {
+        let key = match json["url"][0].as_str() {
+            Some(url) => url_id(url),
+            None => continue,
+        };
+        let key = owners.get(&key).cloned().unwrap_or(key);
+        groups.entry(key).or_insert_with(Vec::new).push((id, json));
+    }
+
+    let mut removed = 0;
+    // ids folded into another, their files go once the commit went through
+    let mut merged_away = vec![];
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    for (key, mut group) in groups {
+        if group.len() == 1 && group[0].0 == key {
+            continue;
+        }
+        let i64_of = |json: &Value, name: &str| json[name][0].as_i64().unwrap_or(0);
+        // the document already under the id wins, otherwise the most visited
+        group.sort_by_key(|(id, json)| (id != &key, -i64_of(json, "accessed_count")));
+        let mut merged = group[0].1.clone();
+        let primary_url = normalize_url(merged["url"][0].as_str().unwrap_or(""));
+        merged["id"] = json!([key]);
+
+        let first_added = group
             .iter()
-        {
-            if let tantivy::schema::Value::Facet(facet) = s {
-                let hash_number = facet
-                    .to_path()
-                    .remove(0)
-                    .parse::<i64>()
-                    .unwrap_or(0)
-                    .to_le_bytes();
-
-                let ham = hamming(&hash_number, &content_hash_bytes);
-                if ham < 4 {
-                    return true;
+            .min_by_key(|(_, json)| i64_of(json, "added_at_i"))
+            .map(|(_, json)| json.clone())
+            .expect("group");
+        let last_accessed = group
+            .iter()
+            .max_by_key(|(_, json)| i64_of(json, "last_accessed_at_i"))
+            .map(|(_, json)| json.clone())
+            .expect("group");
+        for (name, from) in vec![
+            ("added_at", &first_added),
+            ("added_at_i", &first_added),
+            ("last_accessed_at", &last_accessed),
+            ("last_accessed_at_i", &last_accessed),
+        ] {
+            if let Some(value) = from.get(name) {
+                merged[name] = value.clone();
+            }
+        }
+        let accessed_count: i64 = group
+            .iter()
+            .map(|(_, json)| i64_of(json, "accessed_count"))
+            .sum();
+        merged["accessed_count"] = json!([accessed_count]);
+        for name in &["pinned", "bookmarked"] {
+            let value = group
+                .iter()
+                .map(|(_, json)| i64_of(json, name))
+                .max()
+                .unwrap_or(0);
+            merged[*name] = json!([value]);
+        }
+        // the latest reading status wins
+        let last_status = group
+            .iter()
+            .filter(|(_, json)| json["reading_status"][0].is_string())
+            .max_by_key(|(_, json)| i64_of(json, "reading_status_at_i"));
+        if let Some((_, from)) = last_status {
+            for name in &["reading_status", "reading_status_at_i", "queued_at_i"] {
+                if let Some(value) = from.get(*name) {
+                    merged[*name] = value.clone();
                 }
-            };
+            }
+        }
+        let notes = group
+            .iter()
+            .filter_map(|(_, json)| json["notes"][0].as_str())
+            .filter(|notes| !notes.trim().is_empty())
+            .collect::<Vec<_>>();
+        if !notes.is_empty() {
+            merged["notes"] = json!([notes.join("\n\n")]);
+        }
+        for name in &["tags", "aliases", "highlights"] {
+            let mut values = vec![];
+            for (_, json) in group.iter() {
+                for value in json[*name].as_array().cloned().unwrap_or_default() {
+                    if !values.contains(&value) {
+                        values.push(value);
+                    }
+                }
+            }
+            if *name == "aliases" {
+                for (_, json) in group.iter() {
+                    let alias = json!(normalize_url(json["url"][0].as_str().unwrap_or("")));
+                    if alias != json!(primary_url) && !values.contains(&alias) {
+                        values.push(alias);
+                    }
+                }
+            }
+            if !values.is_empty() {
+                merged[*name] = json!(values);
+            }
+        }
+
+        index_writer.delete_term(Term::from_field_text(
+            schema.get_field("id").expect("id"),
+            &key,
+        ));
+        for (id, _) in group.iter() {
+            index_writer.delete_term(Term::from_field_text(
+                schema.get_field("id").expect("id"),
+                id,
+            ));
+        }
+        write_source(&key, merged.to_string());
+        for (id, _) in group.iter().filter(|(id, _)| id != &key) {
+            for fetched_at in versions(id) {
+                if let Some(json) = read_version(id, fetched_at) {
+                    write_version(&key, &json, fetched_at);
+                }
+            }
+            merged_away.push(id.clone());
+        }
+
+        // content is not stored so it is rebuilt from the cached html
+        if let Some(raw) = merged["content_raw"][0].as_str() {
+            if !raw.is_empty() {
+                if let Some(text) = main_content_text(&document::Document::from(raw)) {
+                    merged["content"] = json!([text]);
+                }
+            }
         }
+        let doc = schema
+            .parse_document(&merged.to_string())
+            .expect("doc from json");
+        register_simhash(&doc, &schema);
+        vectors::register_embedding(&doc, &schema);
+        index_writer.add_document(doc);
+        removed += group.len() - 1;
+        println!("merged {} {}", key, primary_url);
     }
-    false
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+    remove_document_files(&merged_away);
+    removed
+}
+
+fn version_key(url_hash: &str, fetched_at: i64) -> String {
+    format!("{}@{}", url_hash, fetched_at)
 }
+
+// old copies of a page live in VERSION_STORE under <hash>@<fetched_at>
+pub fn write_version(url_hash: &str, json: &str, fetched_at: i64) {
+    VERSION_STORE
+        .put(&version_key(url_hash, fetched_at), &seal(compress(json)))
+        .expect("write version file");
+}
+
+// fetched_at timestamps of the old copies, oldest first
+pub fn versions(url_hash: &str) -> Vec<i64> {
+    let prefix = format!("{}@", url_hash);
+    let mut versions = VERSION_STORE
+        .keys_with_prefix(&prefix)
+        .expect("version list")
+        .iter()
+        .filter_map(|key| key[prefix.len()..].parse::<i64>().ok())
+        .collect::<Vec<_>>();
+    versions.sort_unstable();
+    versions
+}
+
+pub fn read_version(url_hash: &str, fetched_at: i64) -> Option<String> {
+    VERSION_STORE
+        .get(&version_key(url_hash, fetched_at))
+        .expect("read version file")
+        .map(|bytes| {
+            unseal(bytes)
+                .and_then(|bytes| decompress(&bytes))
+                .expect("read version file")
+        })
+}
+
+fn delete_versions(url_hash: &str) {
+    for fetched_at in versions(url_hash) {
+        VERSION_STORE
+            .delete(&version_key(url_hash, fetched_at))
+            .expect("delete version");
+    }
+}
+
+// None or the fetched_at of the current copy reads the current source
+pub fn read_version_or_source(url_hash: &str, fetched_at: Option<i64>) -> Option<String> {
+    match fetched_at {
+        Some(fetched_at) => read_version(url_hash, fetched_at).or_else(|| {
+            let json = read_source(url_hash)?;
+            let value: Value = serde_json::from_str(&json).ok()?;
+            if value["fetched_at_i"][0].as_i64() == Some(fetched_at) {
+                Some(json)
+            } else {
+                None
+            }
+        }),
+        None => read_source(url_hash),
+    }
+}
+
+// the readable text of a stored source, used to compare versions
+pub fn source_text(json: &Value) -> String {
+    if let Some(raw) = json["content_raw"][0].as_str() {
+        if !raw.is_empty() {
+            let document = document::Document::from(raw);
+            if let Some(text) = main_content_text(&document) {
+                return text;
+            }
+        }
+    }
+    json["content"][0].as_str().unwrap_or("").to_string()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DiffChunk {
+    // same, add or remove
+    pub op: String,
+    pub text: String,
+}
+
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c == '.' || c == '!' || c == '?' {
+            if let Some((next, ' ')) = chars.peek() {
+                sentences.push(text[start..*next].trim());
+                start = *next;
+            }
+        }
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+// sentence level diff. anything too big to compare is reported as fully replaced
+pub fn diff_text(old: &str, new: &str) -> Vec<DiffChunk> {
+    let chunk = |op: &str, text: &str| DiffChunk {
+        op: op.to_string(),
+        text: text.to_string(),
+    };
+    let old = sentences(old);
+    let new = sentences(new);
+
+    let prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let mut chunks: Vec<DiffChunk> = old[..prefix].iter().map(|s| chunk("same", s)).collect();
+    let (n, m) = (old_middle.len(), new_middle.len());
+    if n * m > 4_000_000 {
+        chunks.extend(old_middle.iter().map(|s| chunk("remove", s)));
+        chunks.extend(new_middle.iter().map(|s| chunk("add", s)));
+    } else {
+        // longest common subsequence table from the end
+        let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i * (m + 1) + j] = if old_middle[i] == new_middle[j] {
+                    lcs[(i + 1) * (m + 1) + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * (m + 1) + j].max(lcs[i * (m + 1) + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_middle[i] == new_middle[j] {
+                chunks.push(chunk("same", old_middle[i]));
+                i += 1;
+                j += 1;
+            } else if i < n && (j == m || lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+                chunks.push(chunk("remove", old_middle[i]));
+                i += 1;
+            } else {
+                chunks.push(chunk("add", new_middle[j]));
+                j += 1;
+            }
+        }
+    }
+    chunks.extend(old[old.len() - suffix..].iter().map(|s| chunk("same", s)));
+    chunks
+}
+
 // move over to id hash
+// by the id of the normalized url, then the id of the url as it was given,
+// which pages indexed before normalization still have until a reindex, then
+// the aliases
 pub fn find_url(url: &str, index: &Index) -> std::option::Option<tantivy::DocAddress> {
+    find_id(&url_id(url), index)
+        .or_else(|| find_id(&md5_hash(url), index))
+        .or_else(|| {
+            let searcher = searcher(&index);
+            let query = TermQuery::new(
+                Term::from_field_text(
+                    index.schema().get_field("aliases").expect("aliases"),
+                    &normalize_url(url),
+                ),
+                IndexRecordOption::Basic,
+            );
+            searcher
+                .search(&query, &TopDocs::with_limit(1))
+                .expect("search")
+                .get(0)
+                .map(|(_, doc_address)| *doc_address)
+        })
+}
+
+fn stored_text(index: &Index, doc_address: tantivy::DocAddress, field: &str) -> Option<String> {
+    let doc = searcher(&index).doc(doc_address).ok()?;
+    doc.get_first(index.schema().get_field(field).expect(field))
+        .and_then(|value| value.text())
+        .map(str::to_string)
+}
+
+pub fn document_id(index: &Index, doc_address: tantivy::DocAddress) -> Option<String> {
+    stored_text(index, doc_address, "id")
+}
+
+pub fn document_url(index: &Index, doc_address: tantivy::DocAddress) -> Option<String> {
+    stored_text(index, doc_address, "url")
+}
+
+pub fn find_id(url_hash: &str, index: &Index) -> std::option::Option<tantivy::DocAddress> {
     let searcher = searcher(&index);
 
-    let url_hash = md5_hash(url);
     let query_parser = QueryParser::for_index(
         &index,
         vec![index.schema().get_field("id").expect("idfield")],
@@ -952,40 +3603,929 @@ pub fn find_url(url: &str, index: &Index) -> std::option::Option<tantivy::DocAdd
     }
 }
 
+const FUZZY_FIELDS: [&str; 3] = ["title", "url", "content"];
+
+// short words get one edit, longer ones two
+fn fuzzy_distance(word: &str) -> u8 {
+    if word.chars().count() <= 5 {
+        1
+    } else {
+        2
+    }
+}
+
+pub fn is_fuzzy_query(query: &str) -> bool {
+    query
+        .split_whitespace()
+        .any(|word| word.len() > 1 && word.ends_with('~'))
+}
+
+// the query a search runs, hidden pages are left out unless asked for
+pub fn search_query(
+    index: &Index,
+    query: &str,
+    lang: Option<&str>,
+    fuzzy: bool,
+) -> Result<Box<dyn Query>, tantivy::query::QueryParserError> {
+    let default_fields: Vec<Field> = index
+        .schema()
+        .fields()
+        .filter(|&(_, ref field_entry)| match *field_entry.field_type() {
+            FieldType::Str(ref text_field_options) => {
+                text_field_options.get_indexing_options().is_some()
+            }
+            _ => false,
+        })
+        .map(|(field, _)| field)
+        .collect();
+
+    let query_parser = QueryParser::new(index.schema(), default_fields, index.tokenizers().clone());
+    // built as terms so a request parameter can not add to the query
+    let schema = index.schema();
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![];
+    if !query.contains("hidden:") {
+        clauses.push((
+            Occur::Must,
+            Box::new(TermQuery::new(
+                Term::from_field_i64(schema.get_field("hidden").expect("hidden"), 0),
+                IndexRecordOption::Basic,
+            )),
+        ));
+    }
+    if let Some(lang) = lang {
+        clauses.push((
+            Occur::Must,
+            Box::new(TermQuery::new(
+                Term::from_field_text(schema.get_field("lang").expect("lang"), lang),
+                IndexRecordOption::Basic,
+            )),
+        ));
+    }
+    if query.trim().is_empty() {
+        // only the filters, for facet counts without a search
+        if clauses.is_empty() {
+            return Ok(Box::new(AllQuery));
+        }
+    } else if fuzzy || is_fuzzy_query(query) {
+        clauses.push((Occur::Must, fuzzy_query(index, query, fuzzy)));
+    } else {
+        clauses.push((Occur::Must, query_parser.parse_query(query)?));
+    }
+    Ok(Box::new(BooleanQuery::from(clauses)))
+}
+
+// every word has to match title, url or content. with fuzzy set, or for words
+// ending in ~, a typo or two still matches. field:value parts go through the
+// query parser as usual
+pub fn fuzzy_query(index: &Index, query: &str, fuzzy: bool) -> Box<dyn Query> {
+    let schema = index.schema();
+    let fields = FUZZY_FIELDS
+        .iter()
+        .map(|name| schema.get_field(name).expect(name))
+        .collect::<Vec<_>>();
+    let query_parser = QueryParser::for_index(index, fields.clone());
+    let analyzer = index
+        .tokenizer_for_field(fields[2])
+        .expect("content tokenizer");
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![];
+    for word in query.split_whitespace() {
+        if word == "AND" {
+            continue;
+        }
+        let (occur, word) = match word.strip_prefix('-') {
+            Some(word) => (Occur::MustNot, word),
+            None => (Occur::Must, word.trim_start_matches('+')),
+        };
+        if word.contains(':') {
+            if let Ok(query) = query_parser.parse_query(word) {
+                clauses.push((occur, query));
+            }
+            continue;
+        }
+        let tilde = word.ends_with('~');
+        let mut tokens = analyzer.token_stream(word.trim_end_matches('~'));
+        while let Some(token) = tokens.next() {
+            let alternatives = fields
+                .iter()
+                .map(|field| {
+                    let term = Term::from_field_text(*field, &token.text);
+                    let query: Box<dyn Query> = if fuzzy || tilde {
+                        Box::new(FuzzyTermQuery::new(term, fuzzy_distance(&token.text), true))
+                    } else {
+                        Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs))
+                    };
+                    (Occur::Should, query)
+                })
+                .collect::<Vec<_>>();
+            clauses.push((occur, Box::new(BooleanQuery::from(alternatives))));
+        }
+    }
+    Box::new(BooleanQuery::from(clauses))
+}
+
+// the closest word in the title and content term dictionaries, sharing the
+// first letter and at most two edits away. ties go to the more common word
+fn closest_term(index: &Index, word: &str) -> Option<String> {
+    let first = word.chars().next()?;
+    let next = std::char::from_u32(first as u32 + 1)?;
+    let (lower, upper) = (first.to_string(), next.to_string());
+    let mut candidates = HashMap::<String, u64>::new();
+    for segment_reader in searcher(index).segment_readers() {
+        for name in ["title", "content"].iter() {
+            let field = index.schema().get_field(name).expect(name);
+            let inverted_index = segment_reader.inverted_index(field);
+            let mut stream = inverted_index
+                .terms()
+                .range()
+                .ge(&lower)
+                .lt(&upper)
+                .into_stream();
+            while stream.advance() {
+                let key = stream.key();
+                if (key.len() as isize - word.len() as isize).abs() > 2 {
+                    continue;
+                }
+                if triple_accel::levenshtein(word.as_bytes(), key) <= 2 {
+                    if let Ok(term) = std::str::from_utf8(key) {
+                        *candidates.entry(term.to_string()).or_insert(0) +=
+                            stream.value().doc_freq as u64;
+                    }
+                }
+            }
+        }
+    }
+    candidates
+        .into_iter()
+        .max_by_key(|(term, doc_freq)| {
+            (
+                std::cmp::Reverse(triple_accel::levenshtein(word.as_bytes(), term.as_bytes())),
+                *doc_freq,
+            )
+        })
+        .map(|(term, _)| term)
+}
+
+// for queries without results, the same query with each unknown word swapped
+// for its closest indexed word
+pub fn did_you_mean(index: &Index, query: &str) -> Option<String> {
+    let schema = index.schema();
+    let fields = ["title", "content"]
+        .iter()
+        .map(|name| schema.get_field(name).expect(name))
+        .collect::<Vec<_>>();
+    let searcher = searcher(index);
+    let mut changed = false;
+    let words = query
+        .split_whitespace()
+        .map(|word| {
+            let plain = word.trim_end_matches('~');
+            if plain.is_empty() || !plain.chars().all(char::is_alphanumeric) {
+                return word.to_string();
+            }
+            let lower = plain.to_lowercase();
+            let known = fields
+                .iter()
+                .any(|field| searcher.doc_freq(&Term::from_field_text(*field, &lower)) > 0);
+            if known {
+                return word.to_string();
+            }
+            match closest_term(index, &lower) {
+                Some(term) => {
+                    changed = true;
+                    term
+                }
+                None => word.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+    if changed {
+        Some(words.join(" "))
+    } else {
+        None
+    }
+}
+
+// stops completions for one or two letters from walking the whole dictionary
+const COMPLETION_SCAN: usize = 5000;
+
+// indexed terms of a field starting with prefix, most documents first
+pub fn terms_with_prefix(index: &Index, field: &str, prefix: &str, limit: usize) -> Vec<String> {
+    let field = index.schema().get_field(field).expect(field);
+    let mut counts = HashMap::<String, u64>::new();
+    for segment_reader in searcher(index).segment_readers() {
+        let inverted_index = segment_reader.inverted_index(field);
+        let mut stream = inverted_index.terms().range().ge(prefix).into_stream();
+        let mut scanned = 0;
+        while scanned < COMPLETION_SCAN && stream.advance() {
+            if !stream.key().starts_with(prefix.as_bytes()) {
+                break;
+            }
+            if let Ok(term) = std::str::from_utf8(stream.key()) {
+                *counts.entry(term.to_string()).or_insert(0) += stream.value().doc_freq as u64;
+            }
+            scanned += 1;
+        }
+    }
+    let mut terms = counts.into_iter().collect::<Vec<_>>();
+    terms.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    terms
+        .into_iter()
+        .take(limit)
+        .map(|(term, _)| term)
+        .collect()
+}
+
+// completes the last word of what was typed from the words in titles
+pub fn title_completions(index: &Index, prefix: &str, limit: usize) -> Vec<String> {
+    if prefix.is_empty() || prefix.ends_with(char::is_whitespace) {
+        return vec![];
+    }
+    let (typed, last) = match prefix.rfind(char::is_whitespace) {
+        Some(split) => prefix.split_at(split + 1),
+        None => ("", prefix),
+    };
+    let last = last.to_lowercase();
+    terms_with_prefix(index, "title", &last, limit + 1)
+        .into_iter()
+        .filter(|term| *term != last)
+        .take(limit)
+        .map(|term| format!("{}{}", typed, term))
+        .collect()
+}
+
+pub fn domain_completions(index: &Index, prefix: &str, limit: usize) -> Vec<String> {
+    let prefix = prefix.trim().to_lowercase();
+    if prefix.is_empty() || prefix.contains(char::is_whitespace) {
+        return vec![];
+    }
+    terms_with_prefix(index, "host", prefix.trim_start_matches("www."), limit)
+}
+
+// pages cached before the metadata and language fields existed get them from the
+// stored html
+fn backfill_page_meta(url_hash: &str, doc: &mut Document, schema: &Schema) {
+    let has_meta = ["author", "lang", "canonical_url", "image", "meta_type"]
+        .iter()
+        .any(|name| doc.get_first(schema.get_field(name).expect(name)).is_some());
+    let has_language_fields = LANGUAGE_ANALYZERS.iter().any(|(language, _)| {
+        let name = format!("content_{}", language);
+        doc.get_first(schema.get_field(&name).expect("language field"))
+            .is_some()
+    });
+    if has_meta && has_language_fields {
+        return;
+    }
+    let json =
+        match read_source(url_hash).and_then(|json| serde_json::from_str::<Value>(&json).ok()) {
+            Some(json) => json,
+            None => return,
+        };
+    let raw = json["content_raw"][0].as_str().unwrap_or("");
+    let document = document::Document::from(raw);
+    let body = match json["content"][0].as_str() {
+        Some(body) => body.to_string(),
+        None => main_content_text(&document).unwrap_or_default(),
+    };
+    let mut lang = doc
+        .get_first(schema.get_field("lang").expect("lang"))
+        .and_then(|value| value.text())
+        .map(str::to_string);
+    if !has_meta && !raw.is_empty() {
+        if let Some(url) = json["url"][0].as_str() {
+            let mut page = page_meta(url, &document);
+            page.lang = detect_language(&body).or(page.lang);
+            lang = page.lang.clone();
+            add_page_meta(doc, schema, &page);
+        }
+    }
+    if !has_language_fields {
+        if let Some(lang) = lang.or_else(|| detect_language(&body)) {
+            let title = json["title"][0].as_str().unwrap_or("");
+            add_language_fields(doc, schema, &lang, title, &body);
+        }
+    }
+}
+
+fn backfill_host(doc: &mut Document, schema: &Schema) {
+    let host = schema.get_field("host").expect("host");
+    if doc.get_first(host).is_some() {
+        return;
+    }
+    let url = doc
+        .get_first(schema.get_field("url").expect("url"))
+        .and_then(|value| value.text())
+        .and_then(url_host);
+    if let Some(url) = url {
+        doc.add_text(host, &url);
+    }
+}
+
 pub fn backfill_from_cached() {
-    let path = Path::new(BASE_INDEX_DIR.as_str());
-    let path_name = path.join("source");
-    let entries = glob(&format!(
-        "{}/*/*.jsonc",
-        path_name.to_str().expect("source_dir")
-    ))
-    .expect("Failed to read glob pattern");
     let mut counter = 0;
 
     let index = search_index().unwrap();
     let mut index_writer = index.writer(50_000_000).expect("writer");
-    for entry in entries {
-        if let Ok(file) = entry {
-            {
-                if counter % 10000 == 0 {
-                    println!("commited {}", counter);
-                    index_writer.commit().expect("commit");
+    // every document is added again from its source
+    index_writer.delete_all_documents().expect("clear index");
+    // documents without a vector are embedded in batches
+    let mut unembedded = vec![];
+    for url_hash in SOURCE_STORE.hashes().expect("source list") {
+        // hidden and the other user fields come from the source as they are
+        let mut doc = update_document(&url_hash, &index, UrlMeta::default());
+        backfill_page_meta(&url_hash, &mut doc, &index.schema());
+        backfill_host(&mut doc, &index.schema());
+        if !vectors::has_embedding(&url_hash) {
+            if let Some(document) = vectors::document_text(&doc, &index.schema()) {
+                unembedded.push(document);
+            }
+            if unembedded.len() == vectors::EMBEDDING_BATCH {
+                vectors::add_embeddings(&unembedded);
+                unembedded.clear();
+            }
+        }
+        index_writer.add_document(doc);
+        counter += 1;
+        // the deletion goes out with the first batch, never on its own, so
+        // a search in between does not find an empty index
+        if counter % 10000 == 0 {
+            println!("commited {}", counter);
+            index_writer.commit().expect("commit");
+        }
+    }
+    vectors::add_embeddings(&unembedded);
+    index_writer.commit().expect("last commit");
+    index_writer.wait_merging_threads().expect("merge");
+}
+
+// calls f with every live document in the index
+pub fn for_each_document(index: &Index, mut f: impl FnMut(Document)) {
+    let searcher = searcher(&index);
+    for segment_reader in searcher.segment_readers() {
+        let store_reader = segment_reader.get_store_reader();
+        for doc_id in segment_reader.doc_ids_alive() {
+            f(store_reader.get(doc_id).expect("stored doc"));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub documents: usize,
+    pub sources: usize,
+    // a source without a document in the index
+    pub orphaned_sources: Vec<String>,
+    // a document in the index without a source
+    pub missing_sources: Vec<String>,
+    // sources that do not decompress or parse
+    pub corrupt_sources: Vec<String>,
+    // ids indexed more than once
+    pub duplicate_documents: Vec<String>,
+    // versions kept for a page that is gone from the index and the sources
+    pub orphaned_versions: Vec<String>,
+    // lsh entries whose document is gone, changed or became a duplicate
+    pub stale_hashes: usize,
+    // documents that are not duplicates but are missing from the lsh index
+    pub missing_hashes: Vec<String>,
+    // duplicates of a document that is no longer indexed
+    pub dangling_duplicates: Vec<String>,
+    pub repaired: usize,
+    // missing or corrupt sources that could not be fetched again. they are left
+    // as they are
+    pub unrecoverable: Vec<String>,
+    pub compacted_bytes: u64,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphaned_sources.is_empty()
+            && self.missing_sources.is_empty()
+            && self.corrupt_sources.is_empty()
+            && self.duplicate_documents.is_empty()
+            && self.orphaned_versions.is_empty()
+            && self.stale_hashes == 0
+            && self.missing_hashes.is_empty()
+            && self.dangling_duplicates.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct DiskUsage {
+    pub directory: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+pub fn disk_usage() -> Vec<DiskUsage> {
+    fn walk(path: &Path, usage: &mut DiskUsage) {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                match entry.metadata() {
+                    Ok(metadata) if metadata.is_dir() => walk(&entry.path(), usage),
+                    Ok(metadata) => {
+                        usage.files += 1;
+                        usage.bytes += metadata.len();
+                    }
+                    Err(_) => {}
                 }
             }
-            let file_string = file.to_str().expect("file_path");
-            let url_hash = file_string.replace(".jsonc", "");
-            let url_hash = if cfg!(target_os = "windows") {
-                url_hash.split('\\').last().unwrap()
-            } else {
-                url_hash.split('/').last().unwrap()
+        }
+    }
+
+    let index_path = Path::new(BASE_INDEX_DIR.as_str());
+    vec!["source", "pack", "index", "lsh", "vectors", "versions"]
+        .into_iter()
+        .map(|directory| {
+            let mut usage = DiskUsage {
+                directory: directory.to_string(),
+                files: 0,
+                bytes: 0,
             };
-            let mut meta = UrlMeta::default();
-            meta.hidden = Some(0);
-            let doc = update_document(&url_hash, &index, meta);
+            walk(&index_path.join(directory), &mut usage);
+            usage
+        })
+        .collect()
+}
+
+// reconcile the index, the source files and the hashes index. with repair the
+// index and sources are fixed up from each other where one of them is still good
+pub fn fsck(repair: bool) -> FsckReport {
+    let index = search_index().expect("search index");
+    let schema = index.schema();
+    let id_field = schema.get_field("id").expect("id");
+    let url_field = schema.get_field("url").expect("url");
+    let content_hash_field = schema.get_field("content_hash").expect("content_hash");
+    let duplicate_of_field = schema.get_field("duplicate_of").expect("duplicate_of");
+    let mut report = FsckReport::default();
+
+    // id -> times indexed. id -> simhash of the documents that are not duplicates.
+    // id -> the document it duplicates
+    let mut documents = HashMap::<String, usize>::new();
+    let mut simhashes = HashMap::<String, i64>::new();
+    let mut duplicates = HashMap::<String, String>::new();
+    for_each_document(&index, |doc| {
+        report.documents += 1;
+        let id = doc
+            .get_first(id_field)
+            .and_then(|value| value.text())
+            .unwrap_or("")
+            .to_string();
+        match doc
+            .get_first(duplicate_of_field)
+            .and_then(|value| value.text())
+        {
+            Some(original) => {
+                duplicates.insert(id.clone(), original.to_string());
+            }
+            None => {
+                if let Some(hash) = doc.get_first(content_hash_field) {
+                    simhashes.insert(id.clone(), hash.i64_value());
+                }
+            }
+        }
+        *documents.entry(id).or_insert(0) += 1;
+    });
+    report.duplicate_documents = documents
+        .iter()
+        .filter(|(_, count)| **count > 1)
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    let mut sources = HashSet::new();
+    for url_hash in SOURCE_STORE.hashes().expect("source list") {
+        report.sources += 1;
+        let parsed = match read_source_checked(&url_hash) {
+            Ok(Some(json)) => schema.parse_document(&json).is_ok(),
+            _ => false,
+        };
+        if !parsed {
+            report.corrupt_sources.push(url_hash.clone());
+        } else if !documents.contains_key(&url_hash) {
+            report.orphaned_sources.push(url_hash.clone());
+        }
+        sources.insert(url_hash);
+    }
+    report.missing_sources = documents
+        .keys()
+        .filter(|id| !sources.contains(*id))
+        .cloned()
+        .collect();
+
+    let mut orphaned_versions = HashSet::new();
+    for key in VERSION_STORE.hashes().expect("version list") {
+        if let Some((url_hash, _)) = key.split_once('@') {
+            if !documents.contains_key(url_hash) && !sources.contains(url_hash) {
+                orphaned_versions.insert(url_hash.to_string());
+            }
+        }
+    }
+    report.orphaned_versions = orphaned_versions.into_iter().collect();
+
+    let lsh = lsh_index().expect("lsh index");
+    let lsh_id = lsh.schema().get_field("id").expect("id");
+    let lsh_hash = lsh.schema().get_field("hash").expect("hash");
+    let mut stale_hashes = vec![];
+    let mut registered = HashSet::new();
+    for_each_document(&lsh, |doc| {
+        let id = doc
+            .get_first(lsh_id)
+            .and_then(|value| value.text())
+            .unwrap_or("")
+            .to_string();
+        let hash = doc.get_first(lsh_hash).map(|value| value.i64_value());
+        if hash.is_some() && simhashes.get(&id) == hash.as_ref() {
+            registered.insert(id);
+        } else {
+            stale_hashes.push(id);
+        }
+    });
+    report.stale_hashes = stale_hashes.len();
+    report.missing_hashes = simhashes
+        .keys()
+        .filter(|id| !registered.contains(*id))
+        .cloned()
+        .collect();
+    report.dangling_duplicates = duplicates
+        .iter()
+        .filter(|(_, original)| !documents.contains_key(*original))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    if repair {
+        let mut index_writer = index.writer(50_000_000).expect("writer");
+        let searcher = searcher(&index);
+        let stored = |url_hash: &str| {
+            find_id(url_hash, &index).map(|address| searcher.doc(address).expect("doc"))
+        };
+
+        // reindexed documents that still need an lsh entry
+        let mut reindexed = vec![];
+        for url_hash in report.orphaned_sources.iter() {
+            let doc = update_document(&url_hash, &index, UrlMeta::default());
+            if doc.get_first(duplicate_of_field).is_none() {
+                if let Some(hash) = doc.get_first(content_hash_field) {
+                    simhashes.insert(url_hash.clone(), hash.i64_value());
+                    reindexed.push(url_hash.clone());
+                }
+            }
             index_writer.add_document(doc);
-            counter += 1;
+            report.repaired += 1;
+        }
+        // the index only stores some fields, so a lost source is fetched again
+        // and keeps what the user set on the stored document
+        let mut refetched = HashSet::new();
+        for url_hash in report
+            .missing_sources
+            .iter()
+            .chain(report.corrupt_sources.iter())
+        {
+            let doc = stored(url_hash).and_then(|stored| {
+                let url = stored.get_first(url_field)?.text()?.to_string();
+                let stored_json: Value =
+                    serde_json::from_str(&schema.to_json(&stored)).expect("doc json");
+                let mut meta = UrlMeta::default();
+                meta.title = stored_json["title"][0].as_str().map(str::to_string);
+                let fetched = fetch_document(&url, &index, meta, NoAuthBlockingGetter {})?;
+                Some(keep_user_fields(&fetched, &stored_json, &schema))
+            });
+            match doc {
+                Some(doc) => {
+                    write_source(url_hash, schema.to_json(&doc));
+                    index_writer.delete_term(Term::from_field_text(id_field, url_hash));
+                    index_writer.add_document(doc);
+                    refetched.insert(url_hash.clone());
+                    report.repaired += 1;
+                }
+                None => report.unrecoverable.push(url_hash.clone()),
+            }
+        }
+        // one copy again, rebuilt from the source since the stored document
+        // lacks the text
+        for url_hash in report.duplicate_documents.iter() {
+            if refetched.contains(url_hash) {
+                continue;
+            }
+            if read_source_checked(url_hash).ok().flatten().is_some() {
+                let doc = update_document(url_hash, &index, UrlMeta::default());
+                index_writer.delete_term(Term::from_field_text(id_field, url_hash));
+                index_writer.add_document(doc);
+                report.repaired += 1;
+            } else if !report.unrecoverable.contains(url_hash) {
+                report.unrecoverable.push(url_hash.clone());
+            }
+        }
+        for url_hash in report.orphaned_versions.iter() {
+            delete_versions(url_hash);
+            report.repaired += 1;
+        }
+        index_writer.commit().expect("commit");
+        index_writer.wait_merging_threads().expect("merge");
+        report.compacted_bytes = SOURCE_STORE.compact().expect("compact sources")
+            + VERSION_STORE.compact().expect("compact versions");
+
+        // a dangling duplicate becomes an original itself
+        let mut index_writer = index.writer(50_000_000).expect("writer");
+        for url_hash in report.dangling_duplicates.iter() {
+            if let Some(json) = read_source(url_hash) {
+                let mut json: Value = serde_json::from_str(&json).expect("cached json parse fail!");
+                if let Some(object) = json.as_object_mut() {
+                    object.remove("duplicate_of");
+                }
+                json["duplicate"] = json!([0]);
+                let doc = schema
+                    .parse_document(&json.to_string())
+                    .expect("doc from json");
+                if let Some(hash) = doc.get_first(content_hash_field) {
+                    simhashes.insert(url_hash.clone(), hash.i64_value());
+                }
+                index_writer.delete_term(Term::from_field_text(id_field, url_hash));
+                index_writer.add_document(doc);
+                write_source(url_hash, json.to_string());
+                report.repaired += 1;
+            }
+        }
+        index_writer.commit().expect("commit");
+        index_writer.wait_merging_threads().expect("merge");
+
+        let mut lsh_writer = lsh.writer(50_000_000).expect("writer");
+        for id in stale_hashes.iter() {
+            lsh_writer.delete_term(Term::from_field_text(lsh_id, id));
+            report.repaired += 1;
+        }
+        for id in stale_hashes
+            .iter()
+            .chain(report.missing_hashes.iter())
+            .chain(report.dangling_duplicates.iter())
+            .chain(reindexed.iter())
+        {
+            if let Some(hash) = simhashes.get(id) {
+                lsh_writer.delete_term(Term::from_field_text(lsh_id, id));
+                lsh_writer.add_document(lsh_document(&lsh, id, *hash as u64));
+                report.repaired += 1;
+            }
+        }
+        lsh_writer.commit().expect("commit");
+        lsh_writer.wait_merging_threads().expect("merge");
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_bytes_open_with_the_same_key() {
+        let key = derive_key(b"secret", b"salt", 10);
+        let sealed = seal_with(&key, b"a cached page");
+        assert!(sealed.starts_with(SEALED_MAGIC));
+        assert_eq!(open_with(&key, &sealed).unwrap(), b"a cached page".to_vec());
+        // a fresh nonce every time
+        assert_ne!(sealed, seal_with(&key, b"a cached page"));
+    }
+
+    #[test]
+    fn sealed_bytes_do_not_open_with_another_key() {
+        let key = derive_key(b"secret", b"salt", 10);
+        let sealed = seal_with(&key, b"a cached page");
+        assert!(open_with(&derive_key(b"guess", b"salt", 10), &sealed).is_err());
+        assert!(open_with(&derive_key(b"secret", b"pepper", 10), &sealed).is_err());
+    }
+
+    #[test]
+    fn tampered_or_truncated_sealed_bytes_do_not_open() {
+        let key = derive_key(b"secret", b"salt", 10);
+        let mut sealed = seal_with(&key, b"a cached page");
+        assert!(open_with(&key, &sealed[..SEALED_MAGIC.len() + 4]).is_err());
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open_with(&key, &sealed).is_err());
+    }
+
+    #[test]
+    fn detected_languages_are_two_letter_codes() {
+        let polish = "Wszyscy ludzie rodzą się wolni i równi pod względem swej godności i swych praw. \
+                      Są oni obdarzeni rozumem i sumieniem i powinni postępować wobec innych w duchu braterstwa.";
+        assert_eq!(detect_language(polish).as_deref(), Some("pl"));
+        let english = "All human beings are born free and equal in dignity and rights. \
+                       They are endowed with reason and conscience and should act towards one another.";
+        assert_eq!(detect_language(english).as_deref(), Some("en"));
+    }
+
+    #[test]
+    fn tracking_params_are_stripped() {
+        let settings = SystemSettings::default();
+        assert_eq!(
+            normalize_url_with(
+                "https://example.com/post?utm_source=feed&id=4&fbclid=x",
+                &settings
+            ),
+            "https://example.com/post?id=4"
+        );
+        assert_eq!(
+            normalize_url_with("https://example.com/post?utm_medium=rss", &settings),
+            "https://example.com/post"
+        );
+    }
+
+    #[test]
+    fn www_and_trailing_slash_are_dropped() {
+        let settings = SystemSettings::default();
+        assert_eq!(
+            normalize_url_with("https://www.example.com/blog/", &settings),
+            "https://example.com/blog"
+        );
+        assert_eq!(
+            normalize_url_with("https://www.example.com/", &settings),
+            "https://example.com"
+        );
+        assert_eq!(
+            normalize_url_with("https://example.com/page#section", &settings),
+            "https://example.com/page"
+        );
+    }
+
+    #[test]
+    fn query_params_are_sorted() {
+        let settings = SystemSettings::default();
+        assert_eq!(
+            normalize_url_with("https://example.com/search?b=2&a=1", &settings),
+            normalize_url_with("https://example.com/search?a=1&b=2", &settings)
+        );
+    }
+
+    #[test]
+    fn http_is_upgraded_unless_turned_off() {
+        let mut settings = SystemSettings::default();
+        assert_eq!(
+            normalize_url_with("http://example.com/page", &settings),
+            "https://example.com/page"
+        );
+        settings.force_https = false;
+        settings.strip_www = false;
+        settings.strip_trailing_slash = false;
+        assert_eq!(
+            normalize_url_with("http://www.example.com/page/", &settings),
+            "http://www.example.com/page/"
+        );
+    }
+
+    #[test]
+    fn canonical_links_stay_on_the_same_site() {
+        let mut settings = SystemSettings::default();
+        assert!(canonical_usable_with(
+            "https://www.example.com/post?id=4",
+            "https://example.com/post",
+            &settings
+        ));
+        assert!(!canonical_usable_with(
+            "https://example.com/post",
+            "https://syndicator.net/post",
+            &settings
+        ));
+        assert!(!canonical_usable_with(
+            "https://example.com/post",
+            "/post",
+            &settings
+        ));
+        settings.respect_canonical = false;
+        assert!(!canonical_usable_with(
+            "https://example.com/post?id=4",
+            "https://example.com/post",
+            &settings
+        ));
+    }
+
+    #[test]
+    fn plain_bytes_pass_through_unseal() {
+        assert_eq!(unseal(b"{}".to_vec()).unwrap(), b"{}".to_vec());
+    }
+
+    fn pack(name: &str) -> PackSourceStore {
+        let path = env::temp_dir()
+            .join(format!("ps_pack_{}_{}", name, std::process::id()))
+            .join("sources.pack");
+        let _ = fs::remove_file(&path);
+        PackSourceStore::new(path)
+    }
+
+    #[test]
+    fn pack_append_replaces_earlier_records() {
+        let store = pack("append");
+        store.put("a", b"one").unwrap();
+        store.put("b", b"two").unwrap();
+        store.put("a", b"three").unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(b"three".to_vec()));
+        assert_eq!(store.get("b").unwrap(), Some(b"two".to_vec()));
+        assert_eq!(store.get("c").unwrap(), None);
+        let mut hashes = store.hashes().unwrap();
+        hashes.sort();
+        assert_eq!(hashes, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn pack_tombstone_deletes() {
+        let store = pack("tombstone");
+        store.put("a", b"one").unwrap();
+        store.delete("a").unwrap();
+        assert!(!store.exists("a"));
+        assert_eq!(store.get("a").unwrap(), None);
+        // deleting what is not there writes nothing
+        let len = fs::metadata(&store.path).unwrap().len();
+        store.delete("a").unwrap();
+        assert_eq!(fs::metadata(&store.path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn pack_skips_torn_tail() {
+        let store = pack("torn");
+        store.put("a", b"one").unwrap();
+        let mut file = OpenOptions::new().append(true).open(&store.path).unwrap();
+        // header of a record whose payload never made it to disk
+        file.write_all(&[1, b'b', 10, 0, 0, 0, b'x']).unwrap();
+        assert_eq!(store.hashes().unwrap(), vec!["a"]);
+        assert_eq!(store.get("a").unwrap(), Some(b"one".to_vec()));
+        assert!(store.compact().unwrap() > 0);
+        assert_eq!(store.hashes().unwrap(), vec!["a"]);
+    }
+
+    #[test]
+    fn pack_compaction_keeps_live_records() {
+        let store = pack("compact");
+        store.put("a", b"one").unwrap();
+        store.put("a", b"two").unwrap();
+        store.put("b", b"three").unwrap();
+        store.delete("b").unwrap();
+        assert!(store.compact().unwrap() > 0);
+        assert_eq!(store.get("a").unwrap(), Some(b"two".to_vec()));
+        assert!(!store.exists("b"));
+        store.put("c", b"four").unwrap();
+        assert_eq!(store.get("c").unwrap(), Some(b"four".to_vec()));
+    }
+
+    #[test]
+    fn pack_rescans_a_file_swapped_by_another_store() {
+        let store = pack("swap");
+        let other = PackSourceStore::new(store.path.clone());
+        store.put("a", b"one").unwrap();
+        store.put("a", b"two").unwrap();
+        store.put("b", b"three").unwrap();
+        assert_eq!(other.get("b").unwrap(), Some(b"three".to_vec()));
+        store.delete("a").unwrap();
+        store.compact().unwrap();
+        assert_eq!(other.get("a").unwrap(), None);
+        assert_eq!(other.get("b").unwrap(), Some(b"three".to_vec()));
+    }
+
+    #[test]
+    fn sealed_directory_holds_a_working_index() {
+        let path = env::temp_dir().join(format!("ps_sealed_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let directory = SealedDirectory {
+            inner: tantivy::directory::MmapDirectory::open(&path).unwrap(),
+            key: Some(derive_key(b"secret", b"salt", 10)),
+        };
+        let mut schema_builder = Schema::builder();
+        let title = schema_builder.add_text_field("title", TEXT | STORED);
+        let index = Index::open_or_create(directory, schema_builder.build()).unwrap();
+        let mut index_writer = index.writer(3_000_000).unwrap();
+        let mut doc = Document::default();
+        doc.add_text(title, "a sealed page");
+        index_writer.add_document(doc);
+        index_writer.commit().unwrap();
+        index_writer.wait_merging_threads().unwrap();
+
+        let reader = index.reader().unwrap();
+        let query = TermQuery::new(
+            Term::from_field_text(title, "sealed"),
+            IndexRecordOption::Basic,
+        );
+        assert_eq!(reader.searcher().search(&query, &Count).unwrap(), 1);
+        for entry in fs::read_dir(&path).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".lock") {
+                let bytes = fs::read(entry.path()).unwrap();
+                assert!(bytes.starts_with(SEALED_MAGIC), "{} is not sealed", name);
+            }
+        }
+    }
+
+    #[test]
+    fn versions_are_listed_by_hash_prefix() {
+        let root = env::temp_dir().join(format!("ps_versions_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let stores: Vec<Box<dyn SourceStore>> = vec![
+            Box::new(FileSourceStore::new(root)),
+            Box::new(pack("versions")),
+        ];
+        for store in stores.iter() {
+            store.put(&version_key("ab12", 100), b"one").unwrap();
+            store.put(&version_key("ab12", 200), b"two").unwrap();
+            store.put(&version_key("ab123", 300), b"three").unwrap();
+            let mut keys = store.keys_with_prefix("ab12@").unwrap();
+            keys.sort();
+            assert_eq!(keys, vec!["ab12@100", "ab12@200"]);
         }
     }
-    index_writer.commit().expect("last commit");
-    index_writer.wait_merging_threads().expect("merge");
 }
@@ -1,3 +1,9 @@
 #[macro_use]
 extern crate lazy_static;
+pub mod bulk;
+pub mod collections;
+pub mod export;
 pub mod indexer;
+pub mod queries;
+pub mod tagging;
+pub mod vectors;
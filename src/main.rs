@@ -1,27 +1,21 @@
-use std::collections::HashMap;
 use std::path::PathBuf;
 use structopt::StructOpt;
-use tantivy::collector::TopDocs;
-use tantivy::query::QueryParser;
+use tantivy::collector::{Count, FacetCollector};
+use tantivy::query::TermQuery;
+use tantivy::schema::{IndexRecordOption, Term};
+#[cfg(feature = "tui")]
+mod bulk;
+mod collections;
+mod export;
 mod indexer;
+mod tagging;
+#[cfg(feature = "tui")]
+mod tui;
+mod vectors;
 
 #[derive(StructOpt, Debug)]
+#[structopt(name = "personal_search")]
 pub struct Opt {
-    #[structopt(long = "query", name = "query")]
-    query: Option<String>,
-    #[structopt(long = "import_url")]
-    import_url: Option<String>,
-    #[structopt(long = "facet")]
-    facet: Option<String>,
-    #[structopt(long = "facet_field")]
-    facet_field: Option<String>,
-
-    #[structopt(long = "json_source")]
-    json_source: Option<String>,
-    #[structopt(long = "backfillcached")]
-    backfillcached: bool,
-    #[structopt(long = "movecachefiles")]
-    movecachefiles: bool,
     #[structopt(short = "s", long = "silent")]
     silent: bool,
     #[structopt(short = "v", long = "verbose")]
@@ -29,88 +23,408 @@ pub struct Opt {
     #[structopt(long = "search_folder")]
     #[structopt(parse(from_os_str))]
     search_folder_path: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Command,
 }
-use tantivy::collector::FacetCollector;
 
-use tantivy::query::AllQuery;
-use tantivy::schema::Facet;
-fn facets(index: tantivy::Index, field: &str, facet: &str) {
-    let searcher = indexer::searcher(&index);
-    let tags = index
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// search the index like the search page does
+    Search {
+        query: String,
+        /// tolerate typos in every query word, not just the ones ending in ~
+        #[structopt(long)]
+        fuzzy: bool,
+        /// only pages in this language
+        #[structopt(long)]
+        lang: Option<String>,
+        #[structopt(long, default_value = "10")]
+        limit: usize,
+        /// text, md, html, csv or json
+        #[structopt(long, default_value = "text")]
+        format: String,
+        /// the same as --format json
+        #[structopt(long)]
+        json: bool,
+    },
+    /// count the facets below one like /tags
+    Facets {
+        #[structopt(default_value = "/")]
+        facet: String,
+        #[structopt(long, default_value = "tags")]
+        field: String,
+        /// count only the pages this search finds
+        #[structopt(long)]
+        query: Option<String>,
+        #[structopt(long)]
+        limit: Option<usize>,
+        #[structopt(long)]
+        json: bool,
+    },
+    /// fetch and index urls
+    Import {
+        #[structopt(required = true)]
+        urls: Vec<String>,
+        #[structopt(long)]
+        json: bool,
+    },
+    /// print a cached page by id or url
+    Show {
+        id: String,
+        /// the whole cached source
+        #[structopt(long)]
+        json: bool,
+    },
+    /// rebuild the index from the cached pages, after a schema change
+    Reindex,
+    /// count documents, reading statuses and disk usage
+    Stats {
+        #[structopt(long)]
+        json: bool,
+    },
+    /// check the index against the cached pages
+    Fsck {
+        /// fix what can be fixed
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// list, rename, merge and delete tags
+    Tags(TagsCommand),
+    /// replace summaries that are the start of the page text with model summaries
+    Summarize,
+    /// merge documents whose urls normalize to the same id
+    MergeDuplicates,
+    /// move the cached pages to files or pack
+    MigrateStore { store: String },
+    /// encrypt sources, versions and settings with a passphrase
+    EncryptExisting,
+    /// print a collection as a static page
+    ExportCollection {
+        name: String,
+        /// md or html
+        #[structopt(long, default_value = "md")]
+        format: String,
+    },
+    /// move cached pages from a flat folder to sub folders
+    MoveCacheFiles,
+    /// search interactively in the terminal
+    #[cfg(feature = "tui")]
+    Tui,
+}
+
+#[derive(StructOpt, Debug)]
+enum TagsCommand {
+    /// list tags under a facet like /tags with their document counts
+    List {
+        #[structopt(default_value = "/tags")]
+        prefix: String,
+        #[structopt(long)]
+        json: bool,
+    },
+    Rename {
+        from: String,
+        to: String,
+        /// also rename the tags below it
+        #[structopt(long)]
+        subtree: bool,
+    },
+    /// merge tags into the last one given
+    Merge {
+        #[structopt(required = true, min_values = 2)]
+        tags: Vec<String>,
+        #[structopt(long)]
+        subtree: bool,
+    },
+    Delete {
+        tag: String,
+        #[structopt(long)]
+        subtree: bool,
+    },
+    /// add the tags of the tag_rules setting to pages already indexed
+    ApplyRules,
+}
+
+fn print_json(value: &impl serde::Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("output json")
+    );
+}
+
+fn search(
+    index: &tantivy::Index,
+    query: &str,
+    fuzzy: bool,
+    lang: Option<&str>,
+    limit: usize,
+    format: &str,
+) -> Result<(), String> {
+    let parsed = indexer::search_query(index, query, lang, fuzzy)
+        .map_err(|e| format!("could not parse {}: {:?}", query, e))?;
+    let rows = export::rows(index, parsed.as_ref(), limit);
+    if format != "text" {
+        print!(
+            "{}",
+            export::render(&format!("Search: {}", query), &rows, format)?
+        );
+        return Ok(());
+    }
+    if rows.is_empty() {
+        if let Some(suggestion) = indexer::did_you_mean(index, query) {
+            eprintln!("did you mean: {}", suggestion);
+        }
+    }
+    for row in rows {
+        println!("{}", export::title_or_url(&row));
+        println!("  {}", row.url);
+        println!(
+            "  {} · last visit {}",
+            row.id,
+            export::day(&row.last_accessed_at)
+        );
+        if !row.tags.is_empty() {
+            println!("  {}", row.tags.join(" "));
+        }
+    }
+    Ok(())
+}
+
+fn facets(
+    index: &tantivy::Index,
+    field: &str,
+    facet: &str,
+    query: Option<&str>,
+    limit: Option<usize>,
+    json: bool,
+) -> Result<(), String> {
+    let field = index
         .schema()
         .get_field(field)
-        .unwrap_or_else(|| panic!("{} not a field", field));
-    let mut facet_collector = FacetCollector::for_field(tags);
-    facet_collector.add_facet(facet);
-    let facet_counts = searcher.search(&AllQuery, &facet_collector).expect("facet");
-
-    // This lists all of the facet counts
-    let facets: Vec<(&Facet, u64)> = facet_counts.get(facet).collect();
-    dbg!(facets);
+        .ok_or_else(|| format!("{} is not a field", field))?;
+    let facet = tagging::tag_facet(facet);
+    let query = indexer::search_query(index, query.unwrap_or(""), None, false)
+        .map_err(|e| format!("could not parse query: {:?}", e))?;
+    let mut facet_collector = FacetCollector::for_field(field);
+    facet_collector.add_facet(facet.as_str());
+    let facet_counts = indexer::searcher(index)
+        .search(query.as_ref(), &facet_collector)
+        .map_err(|e| format!("facet search failed: {}", e))?;
+    let mut counts = facet_counts
+        .get(facet.as_str())
+        .map(|(facet, count)| (facet.to_string(), count))
+        .collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(limit.unwrap_or_else(|| counts.len()));
+    if json {
+        let counts = counts
+            .iter()
+            .map(|(facet, count)| serde_json::json!({ "facet": facet, "count": count }))
+            .collect::<Vec<_>>();
+        print_json(&counts);
+    } else {
+        for (facet, count) in counts {
+            println!("{} {}", count, facet);
+        }
+    }
+    Ok(())
 }
-fn search(query: String, index: tantivy::Index) {
-    let searcher = indexer::searcher(&index);
-    let default_fields: Vec<tantivy::schema::Field> = index
-        .schema()
-        .fields()
-        .filter(|&(_, ref field_entry)| match *field_entry.field_type() {
-            tantivy::schema::FieldType::Str(ref text_field_options) => {
-                text_field_options.get_indexing_options().is_some()
-            }
-            _ => false,
-        })
-        .map(|(field, _)| field)
-        .collect();
 
-    let query_parser = QueryParser::new(index.schema(), default_fields, index.tokenizers().clone());
+fn import(index: &tantivy::Index, urls: &[String], json: bool) -> Result<(), String> {
+    let mut failed = vec![];
+    let mut imported = vec![];
+    for url in urls {
+        if url::Url::parse(url).is_err() {
+            failed.push(url.clone());
+            continue;
+        }
+        indexer::index_url(
+            url.clone(),
+            indexer::UrlMeta::default(),
+            Some(index),
+            indexer::NoAuthBlockingGetter {},
+        );
+        match indexer::find_url(url, index).and_then(|address| indexer::document_id(index, address))
+        {
+            Some(id) => imported.push((id, url.clone())),
+            None => failed.push(url.clone()),
+        }
+    }
+    if json {
+        let imported = imported
+            .iter()
+            .map(|(id, url)| serde_json::json!({ "id": id, "url": url }))
+            .collect::<Vec<_>>();
+        print_json(&serde_json::json!({ "imported": imported, "failed": failed }));
+    } else {
+        for (id, url) in imported.iter() {
+            println!("{} {}", id, url);
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("could not index {}", failed.join(" ")))
+    }
+}
 
-    let query = query_parser.parse_query(&query).expect("query parse");
-    let top_docs = searcher
-        .search(&query, &TopDocs::with_limit(10))
-        .expect("serach");
-    let schema = index.schema();
-    for (score, doc_address) in top_docs {
-        let retrieved_doc = searcher.doc(doc_address).expect("doc");
-        let mut m = HashMap::new();
-        for f in retrieved_doc.field_values().iter() {
-            m.entry(schema.get_field_name(f.field()))
-                .or_insert_with(Vec::new)
-                .push(f.value())
+fn show(index: &tantivy::Index, id: &str, json: bool) -> Result<(), String> {
+    // an url is looked up through its canonical page and aliases
+    let id = if id.contains("://") {
+        indexer::find_url(id, index)
+            .and_then(|address| indexer::document_id(index, address))
+            .unwrap_or_else(|| indexer::url_id(id))
+    } else {
+        id.to_string()
+    };
+    let source = indexer::read_source(&id).ok_or_else(|| format!("{} not found", id))?;
+    let source: serde_json::Value =
+        serde_json::from_str(&source).map_err(|e| format!("{} is corrupt: {}", id, e))?;
+    if json {
+        print_json(&source);
+        return Ok(());
+    }
+    let text = |name: &str| source[name][0].as_str().unwrap_or("").to_string();
+    let date = |name: &str| {
+        source[name][0]
+            .as_i64()
+            .map(|timestamp| {
+                chrono::NaiveDateTime::from_timestamp(timestamp, 0)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default()
+    };
+    let tags = source["tags"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|tag| tag.as_str().map(str::to_string))
+        .collect::<Vec<_>>();
+    for (name, value) in vec![
+        ("id", id.clone()),
+        ("title", text("title")),
+        ("url", text("url")),
+        ("tags", tags.join(" ")),
+        ("added", date("added_at_i")),
+        ("last visit", date("last_accessed_at_i")),
+        ("visits", source["accessed_count"][0].to_string()),
+        ("status", text("reading_status")),
+        ("summary", text("summary")),
+        ("notes", text("notes")),
+    ] {
+        if !value.is_empty() && value != "null" {
+            println!("{:<10} {}", name, value);
         }
+    }
+    Ok(())
+}
 
-        let m: HashMap<_, _> = retrieved_doc
-            .get_sorted_field_values()
+fn stats(index: &tantivy::Index, json: bool) -> Result<(), String> {
+    let searcher = indexer::searcher(index);
+    let schema = index.schema();
+    let count = |term: Term| {
+        searcher
+            .search(&TermQuery::new(term, IndexRecordOption::Basic), &Count)
+            .unwrap_or(0)
+    };
+    let field = |name: &str| schema.get_field(name).expect(name);
+    let mut counts = vec![
+        ("documents".to_string(), searcher.num_docs() as usize),
+        (
+            "pinned".to_string(),
+            count(Term::from_field_i64(field("pinned"), 1)),
+        ),
+        (
+            "hidden".to_string(),
+            count(Term::from_field_i64(field("hidden"), 1)),
+        ),
+    ];
+    for status in indexer::READING_STATUSES.iter() {
+        counts.push((
+            status.to_string(),
+            count(Term::from_field_text(field("reading_status"), status)),
+        ));
+    }
+    counts.push(("tags".to_string(), tagging::list_tags("/").len()));
+    counts.push(("collections".to_string(), collections::list().len()));
+    let usage = indexer::disk_usage();
+    if json {
+        let mut stats = counts
             .into_iter()
-            .map(|a| (schema.get_field_name(a.0), a.1))
-            .collect();
-
-        let title = m
-            .get("title")
-            .and_then(|r| r.first().unwrap().value().text())
-            .unwrap_or("");
-        let url = m
-            .get("url")
-            .and_then(|r| r.first().unwrap().value().text())
-            .unwrap_or("");
-        let summary = m
-            .get("summary")
-            .and_then(|r| r.first().unwrap().value().text())
-            .unwrap_or("");
-
-        let pinned = m
-            .get("pinned")
-            .map(|r| r.first().unwrap().value().i64_value())
-            .unwrap_or(0);
+            .map(|(name, count)| (name, serde_json::json!(count)))
+            .collect::<serde_json::Map<_, _>>();
+        let usage = usage
+            .iter()
+            .map(|usage| {
+                serde_json::json!({
+                    "directory": usage.directory,
+                    "files": usage.files,
+                    "bytes": usage.bytes,
+                })
+            })
+            .collect::<Vec<_>>();
+        stats.insert("disk_usage".to_string(), serde_json::json!(usage));
+        print_json(&stats);
+    } else {
+        for (name, count) in counts {
+            println!("{:<12} {:>10}", name, count);
+        }
+        println!("\ndisk usage:");
+        for usage in usage {
+            println!(
+                "{:<10} {:>10} files {:>10.1} MB",
+                usage.directory,
+                usage.files,
+                usage.bytes as f64 / 1024.0 / 1024.0
+            );
+        }
+    }
+    Ok(())
+}
+
+// an error when problems were found and left alone, for scripts
+fn fsck(repair: bool, verbose: bool) -> Result<(), String> {
+    let report = indexer::fsck(repair);
+    let list = |name: &str, ids: &Vec<String>| {
+        println!("{}: {}", name, ids.len());
+        if verbose {
+            for id in ids {
+                println!("  {}", id);
+            }
+        }
+    };
+    println!("documents: {}", report.documents);
+    println!("sources: {}", report.sources);
+    list("orphaned sources", &report.orphaned_sources);
+    list("missing sources", &report.missing_sources);
+    list("corrupt sources", &report.corrupt_sources);
+    list("duplicate documents", &report.duplicate_documents);
+    list("orphaned versions", &report.orphaned_versions);
+    println!("stale hashes: {}", report.stale_hashes);
+    list("missing hashes", &report.missing_hashes);
+    list("dangling duplicates", &report.dangling_duplicates);
+    if repair {
+        println!("repaired: {}", report.repaired);
+        list("unrecoverable", &report.unrecoverable);
+        println!("compacted: {} bytes", report.compacted_bytes);
+    }
+
+    println!("\ndisk usage:");
+    for usage in indexer::disk_usage() {
         println!(
-            "{score}: {title} - {url}\n{summary}\n{pinned}",
-            score = score,
-            title = title,
-            url = url,
-            summary = summary,
-            pinned = pinned
+            "{:<10} {:>10} files {:>10.1} MB",
+            usage.directory,
+            usage.files,
+            usage.bytes as f64 / 1024.0 / 1024.0
         );
-        let json = index.schema().to_json(&retrieved_doc);
-        println!("{}:\n{}", score, json);
+    }
+    if !repair && !report.is_clean() {
+        Err("fsck found problems, run it with --repair to fix them".to_string())
+    } else if !report.unrecoverable.is_empty() {
+        Err("some documents could not be fetched again, see unrecoverable".to_string())
+    } else {
+        Ok(())
     }
 }
 
@@ -120,7 +434,6 @@ fn movefiles() {
     use std::path::Path;
     let path = Path::new(indexer::BASE_INDEX_DIR.as_str());
     let path_name = path.join("source");
-    dbg!(&path_name);
     let entries = glob(&format!(
         "{}/*.jsonc",
         path_name.to_str().expect("source_dir")
@@ -133,51 +446,137 @@ fn movefiles() {
             let dir_name = file.to_str().unwrap().to_string();
 
             let filename = dir_name.split('/').last().unwrap();
-            dbg!(filename);
             let mut new_dir_name = filename.clone().to_string();
             new_dir_name.truncate(2);
-            dbg!(&new_dir_name);
-            dbg!(base.join(new_dir_name.clone()));
             if std::fs::create_dir(base.join(new_dir_name.clone())).is_ok() {}
             let dir_path = base.join(new_dir_name.clone());
-            dbg!(dir_path.join(filename));
             if std::fs::rename(file, dir_path.join(filename)).is_ok() {}
         }
     }
 }
 
-fn main() -> tantivy::Result<()> {
-    let index = indexer::search_index();
-
-    let opt = Opt::from_args();
+fn tags(command: TagsCommand) -> Result<(), String> {
+    match command {
+        TagsCommand::List { prefix, json } => {
+            let tags = tagging::list_tags(&prefix);
+            if json {
+                let tags = tags
+                    .iter()
+                    .map(|(tag, count)| serde_json::json!({ "tag": tag, "count": count }))
+                    .collect::<Vec<_>>();
+                print_json(&tags);
+            } else {
+                for (tag, count) in tags {
+                    println!("{} {}", count, tag);
+                }
+            }
+        }
+        TagsCommand::Rename { from, to, subtree } => {
+            println!(
+                "changed {} documents",
+                tagging::rewrite_tags(&[from], Some(&to), subtree)
+            );
+        }
+        TagsCommand::Merge { mut tags, subtree } => {
+            let to = tags.pop().expect("merge target");
+            println!(
+                "changed {} documents",
+                tagging::rewrite_tags(&tags, Some(&to), subtree)
+            );
+        }
+        TagsCommand::Delete { tag, subtree } => {
+            println!(
+                "changed {} documents",
+                tagging::rewrite_tags(&[tag], None, subtree)
+            );
+        }
+        TagsCommand::ApplyRules => println!("tagged {}", tagging::apply_tag_rules()),
+    }
+    Ok(())
+}
 
-    match index {
-        Ok(index) => {
-            if opt.movecachefiles {
-                movefiles();
-            } else if opt.backfillcached {
-                indexer::backfill_from_cached();
-            } else if let Some(query) = opt.query {
-                search(query, index);
-            } else if let Some(url) = opt.json_source {
-                println!(
-                    "{}",
-                    indexer::read_source(&url).unwrap_or("not found".to_string())
-                )
-            } else if let Some(url) = opt.import_url {
-                indexer::index_url(
-                    url,
-                    indexer::UrlMeta::default(),
-                    Some(&index),
-                    indexer::NoAuthBlockingGetter {},
-                );
-            } else if let Some(facet) = opt.facet {
-                let field = opt.facet_field.unwrap_or_else(|| "tags".to_string());
-                facets(index, &field, &facet);
+fn run(opt: Opt) -> Result<(), String> {
+    let index = indexer::search_index().map_err(|e| format!("could not access index: {}", e))?;
+    match opt.command {
+        Command::Search {
+            query,
+            fuzzy,
+            lang,
+            limit,
+            format,
+            json,
+        } => {
+            let format = if json { "json".to_string() } else { format };
+            search(&index, &query, fuzzy, lang.as_deref(), limit, &format)
+        }
+        Command::Facets {
+            facet,
+            field,
+            query,
+            limit,
+            json,
+        } => facets(&index, &field, &facet, query.as_deref(), limit, json),
+        Command::Import { urls, json } => import(&index, &urls, json),
+        Command::Show { id, json } => show(&index, &id, json),
+        Command::Reindex => {
+            indexer::backfill_from_cached();
+            Ok(())
+        }
+        Command::Stats { json } => stats(&index, json),
+        Command::Fsck { repair } => fsck(repair, opt.verbose),
+        Command::Tags(command) => tags(command),
+        Command::Summarize => {
+            if indexer::summarizer_available() {
+                println!("summarized {}", indexer::summarize_truncated());
+                Ok(())
+            } else {
+                Err("no summarization model. build with --features ml and set summarize_on_cpu without a gpu".to_string())
+            }
+        }
+        Command::MergeDuplicates => {
+            println!("merged {}", indexer::merge_duplicates());
+            Ok(())
+        }
+        Command::MigrateStore { store } => match store.as_str() {
+            "files" | "pack" => {
+                println!("moved {}", indexer::migrate_source_store(&store));
+                Ok(())
+            }
+            _ => Err(format!("unknown store {}. use files or pack", store)),
+        },
+        Command::EncryptExisting => {
+            let secret = indexer::encryption_secret("new passphrase: ");
+            if secret.is_empty() {
+                return Err("refusing to encrypt with an empty passphrase".to_string());
+            }
+            // a typo here would lock the archive for good
+            if std::env::var("PS_KEY_FILE").is_err()
+                && std::env::var("PS_PASSPHRASE").is_err()
+                && indexer::encryption_secret("again: ") != secret
+            {
+                return Err("the passphrases do not match".to_string());
             }
+            let count = indexer::encrypt_existing(&secret)?;
+            println!("encrypted {}", count);
+            Ok(())
+        }
+        Command::ExportCollection { name, format } => {
+            print!("{}", collections::export(&name, &format)?);
+            Ok(())
+        }
+        Command::MoveCacheFiles => {
+            movefiles();
+            Ok(())
         }
-        Err(_) => println!("count not access index"),
+        #[cfg(feature = "tui")]
+        Command::Tui => tui::run(index),
     }
+}
 
-    Ok(())
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,339 @@
+use crate::indexer;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+
+// older searches are dropped from the history, their counts are kept
+const HISTORY_LIMIT: usize = 5000;
+// the same query again within this many seconds, like a reload after pinning
+// a result, updates the last entry instead of adding one
+const REPEAT_SECONDS: i64 = 60;
+// searches and clicks are written to disk at most this often
+pub const FLUSH_SECONDS: u64 = 30;
+// clicks this many days old count half as much in ranking
+const CLICK_HALF_LIFE_DAYS: f64 = 90.0;
+// how much clicks can lift a relevance score
+const CLICK_WEIGHT: f32 = 0.5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct QueryStats {
+    pub count: i64,
+    pub last_searched_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct QueryEntry {
+    pub query: String,
+    pub searched_at: i64,
+    // ids of the results opened from this search
+    pub clicks: Vec<String>,
+}
+
+// a result opened through /go, position counts from 0
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Click {
+    pub query: String,
+    pub id: String,
+    pub position: Option<usize>,
+    pub clicked_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SavedSearch {
+    pub name: String,
+    pub query: String,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+struct QueryLog {
+    counts: HashMap<String, QueryStats>,
+    history: Vec<QueryEntry>,
+    saved: Vec<SavedSearch>,
+    clicks: Vec<Click>,
+}
+
+lazy_static::lazy_static! {
+    // loaded on first use. changes mark it dirty and flush writes it back
+    static ref QUERIES: RwLock<Option<QueryLog>> = RwLock::new(None);
+    static ref DIRTY: AtomicBool = AtomicBool::new(false);
+    // one flush writes the file at a time so an older copy never lands last
+    static ref WRITING: Mutex<()> = Mutex::new(());
+    // record_queries from the settings, set_recording keeps it current when the
+    // server changes them
+    static ref RECORDING: AtomicBool = AtomicBool::new(indexer::CACHEDCONFIG.record_queries);
+}
+
+fn queries_path() -> PathBuf {
+    Path::new(indexer::BASE_INDEX_DIR.as_str()).join("queries.json")
+}
+
+fn load() -> QueryLog {
+    fs::read(queries_path())
+        .ok()
+        .and_then(|bytes| indexer::unseal(bytes).ok())
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+// writes the log when it changed since the last flush. the log is copied
+// under the lock and written without it so searches do not wait on the disk
+pub fn flush() {
+    let _writing = WRITING.lock().expect("queries write lock");
+    if !DIRTY.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let json = match QUERIES.read().expect("queries lock").as_ref() {
+        Some(log) => serde_json::to_vec(log).expect("queries json"),
+        None => return,
+    };
+    let path = queries_path();
+    let written = path.with_extension("json.tmp");
+    fs::write(&written, indexer::seal(json)).expect("write queries");
+    fs::rename(&written, &path).expect("write queries");
+}
+
+fn mark_changed() {
+    DIRTY.store(true, Ordering::SeqCst);
+}
+
+fn with_log<T>(f: impl FnOnce(&mut QueryLog) -> T) -> T {
+    let mut guard = QUERIES.write().expect("queries lock");
+    f(guard.get_or_insert_with(load))
+}
+
+// lowercased with single spaces so "Rust  Async" and "rust async" count together
+pub fn normalize_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+pub fn recording_enabled() -> bool {
+    RECORDING.load(Ordering::Relaxed)
+}
+
+pub fn set_recording(enabled: bool) {
+    RECORDING.store(enabled, Ordering::Relaxed);
+}
+
+pub fn record(query: &str) {
+    let query = normalize_query(query);
+    if query.is_empty() || !recording_enabled() {
+        return;
+    }
+    let now = Utc::now().timestamp();
+    with_log(|log| {
+        let stats = log
+            .counts
+            .entry(query.clone())
+            .or_insert_with(QueryStats::default);
+        match log.history.last_mut() {
+            Some(last) if last.query == query && now - last.searched_at < REPEAT_SECONDS => {
+                last.searched_at = now;
+            }
+            _ => {
+                stats.count += 1;
+                log.history.push(QueryEntry {
+                    query,
+                    searched_at: now,
+                    clicks: vec![],
+                });
+            }
+        }
+        stats.last_searched_at = now;
+        if log.history.len() > HISTORY_LIMIT {
+            let extra = log.history.len() - HISTORY_LIMIT;
+            log.history.drain(..extra);
+        }
+    });
+    mark_changed();
+}
+
+// a result opened from the latest search for query
+pub fn record_click(query: &str, id: &str, position: Option<usize>) {
+    let query = normalize_query(query);
+    if query.is_empty() || id.is_empty() || !recording_enabled() {
+        return;
+    }
+    with_log(|log| {
+        if let Some(entry) = log.history.iter_mut().rev().find(|e| e.query == query) {
+            if !entry.clicks.iter().any(|click| click == id) {
+                entry.clicks.push(id.to_string());
+            }
+        }
+        log.clicks.push(Click {
+            query,
+            id: id.to_string(),
+            position,
+            clicked_at: Utc::now().timestamp(),
+        });
+        if log.clicks.len() > HISTORY_LIMIT {
+            let extra = log.clicks.len() - HISTORY_LIMIT;
+            log.clicks.drain(..extra);
+        }
+    });
+    mark_changed();
+}
+
+// plain words of a query, field:value parts are left out
+fn query_terms(query: &str) -> Vec<String> {
+    normalize_query(query)
+        .split(' ')
+        .filter(|term| !term.contains(':'))
+        .map(|term| {
+            term.trim_matches(|c: char| !c.is_alphanumeric())
+                .to_string()
+        })
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+// learned from clicks: for each document opened from searches sharing words
+// with query, the share of words in common. older clicks fade and clicks far
+// down the list say more than clicks on the first result
+pub fn click_boosts(query: &str) -> HashMap<String, f32> {
+    let terms = query_terms(query);
+    let mut boosts = HashMap::new();
+    if terms.is_empty() {
+        return boosts;
+    }
+    let now = Utc::now().timestamp();
+    with_log(|log| {
+        for click in log.clicks.iter() {
+            let click_terms = query_terms(&click.query);
+            let shared = terms
+                .iter()
+                .filter(|term| click_terms.contains(term))
+                .count();
+            if shared == 0 {
+                continue;
+            }
+            let age_days = (now - click.clicked_at).max(0) as f64 / (24 * 60 * 60) as f64;
+            let position = click.position.unwrap_or(0) as f64;
+            let weight = shared as f64 / terms.len() as f64
+                * 0.5f64.powf(age_days / CLICK_HALF_LIFE_DAYS)
+                * (1.0 + position.ln_1p());
+            *boosts.entry(click.id.clone()).or_insert(0.0) += weight as f32;
+        }
+    });
+    boosts
+}
+
+// the first clicks lift a score the most
+pub fn boosted_score(score: f32, clicks: f32) -> f32 {
+    score * (1.0 + CLICK_WEIGHT * clicks.ln_1p())
+}
+
+// earlier queries starting with prefix, most searched first
+pub fn popular(prefix: &str, limit: usize) -> Vec<String> {
+    let prefix = normalize_query(prefix);
+    if prefix.is_empty() {
+        return vec![];
+    }
+    with_log(|log| {
+        let mut matches = log
+            .counts
+            .iter()
+            .filter(|(query, _)| query.starts_with(&prefix) && **query != prefix)
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| {
+            b.1.count
+                .cmp(&a.1.count)
+                .then_with(|| b.1.last_searched_at.cmp(&a.1.last_searched_at))
+        });
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(query, _)| query.clone())
+            .collect()
+    })
+}
+
+// latest search for each query, newest first, with the clicks of all its searches
+pub fn recent(limit: usize) -> Vec<QueryEntry> {
+    with_log(|log| {
+        let mut seen = HashSet::new();
+        let mut entries: Vec<QueryEntry> = vec![];
+        for entry in log.history.iter().rev() {
+            if seen.insert(entry.query.clone()) {
+                if entries.len() == limit {
+                    continue;
+                }
+                entries.push(entry.clone());
+            } else if let Some(first) = entries.iter_mut().find(|e| e.query == entry.query) {
+                for click in entry.clicks.iter() {
+                    if !first.clicks.contains(click) {
+                        first.clicks.push(click.clone());
+                    }
+                }
+            }
+        }
+        entries
+    })
+}
+
+// the list of recent searches. suggestions and click ranking keep what they learned
+pub fn clear_history() {
+    with_log(|log| log.history.clear());
+    mark_changed();
+    flush();
+}
+
+// how often each query was searched, which the suggestions come from
+pub fn clear_counts() {
+    with_log(|log| log.counts.clear());
+    mark_changed();
+    flush();
+}
+
+// the results opened from searches, which lift them in ranking
+pub fn clear_clicks() {
+    with_log(|log| log.clicks.clear());
+    mark_changed();
+    flush();
+}
+
+pub fn saved() -> Vec<SavedSearch> {
+    with_log(|log| log.saved.clone())
+}
+
+// saving under an existing name replaces that search
+pub fn save_search(name: &str, query: &str) -> Vec<SavedSearch> {
+    let saved = with_log(|log| {
+        let saved = SavedSearch {
+            name: name.trim().to_string(),
+            query: query.trim().to_string(),
+            created_at: Utc::now().timestamp(),
+        };
+        match log.saved.iter_mut().find(|s| s.name == saved.name) {
+            Some(existing) => *existing = saved,
+            None => log.saved.push(saved),
+        }
+        log.saved.clone()
+    });
+    mark_changed();
+    flush();
+    saved
+}
+
+pub fn delete_saved_search(name: &str) -> Vec<SavedSearch> {
+    let saved = with_log(|log| {
+        log.saved.retain(|s| s.name != name);
+        log.saved.clone()
+    });
+    mark_changed();
+    flush();
+    saved
+}
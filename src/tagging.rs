@@ -0,0 +1,397 @@
+use crate::indexer;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tantivy::collector::TopDocs;
+use tantivy::query::TermQuery;
+use tantivy::schema::*;
+use tantivy::Index;
+
+// keyphrases become facets under this path, next to /keywords from meta tags
+const AUTO_FACET: &str = "/auto";
+// longer phrases make poor tags
+const MAX_PHRASE_WORDS: usize = 3;
+// a phrase used once is rarely what the page is about
+const MIN_OCCURRENCES: usize = 2;
+// characters of title and content read for keyphrases
+const KEYPHRASE_CHARS: usize = 20000;
+
+const STOP_WORDS: &[&str] = &[
+    "a", "about", "above", "after", "again", "against", "all", "also", "am", "an", "and", "any",
+    "are", "as", "at", "be", "because", "been", "before", "being", "below", "between", "both",
+    "but", "by", "can", "could", "did", "do", "does", "doing", "down", "during", "each", "even",
+    "few", "for", "from", "further", "get", "got", "had", "has", "have", "having", "he", "her",
+    "here", "hers", "him", "his", "how", "i", "if", "in", "into", "is", "it", "its", "just",
+    "like", "may", "me", "might", "more", "most", "much", "must", "my", "new", "no", "nor", "not",
+    "now", "of", "off", "on", "once", "one", "only", "or", "other", "our", "out", "over", "own",
+    "same", "she", "should", "so", "some", "such", "than", "that", "the", "their", "them", "then",
+    "there", "these", "they", "this", "those", "through", "to", "too", "under", "until", "up",
+    "use", "used", "very", "was", "we", "were", "what", "when", "where", "which", "while", "who",
+    "will", "with", "would", "you", "your",
+];
+
+// tags a page gets when its url and text match. empty patterns match anything
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TagRule {
+    // regex on the url
+    pub url: String,
+    // regex on the title and text
+    pub content: String,
+    // facet to add, /tags/ is put in front when it has no leading /
+    pub tag: String,
+}
+
+struct CompiledRule {
+    url: Option<Regex>,
+    content: Option<Regex>,
+    tag: String,
+}
+
+lazy_static::lazy_static! {
+    static ref TAG_RULES: Vec<CompiledRule> = compile_rules(&indexer::CACHEDCONFIG.tag_rules);
+}
+
+fn pattern(pattern: &str) -> Result<Option<Regex>, regex::Error> {
+    if pattern.is_empty() {
+        Ok(None)
+    } else {
+        Regex::new(pattern).map(Some)
+    }
+}
+
+pub fn tag_facet(tag: &str) -> String {
+    let tag = tag.trim();
+    if tag.starts_with('/') {
+        tag.to_string()
+    } else {
+        format!("/tags/{}", tag)
+    }
+}
+
+fn compile_rules(rules: &[TagRule]) -> Vec<CompiledRule> {
+    rules
+        .iter()
+        .filter_map(|rule| {
+            if rule.tag.trim().is_empty() || (rule.url.is_empty() && rule.content.is_empty()) {
+                println!("skipping tag rule without a tag or a pattern {:?}", rule);
+                return None;
+            }
+            match (pattern(&rule.url), pattern(&rule.content)) {
+                (Ok(url), Ok(content)) => Some(CompiledRule {
+                    url,
+                    content,
+                    tag: tag_facet(&rule.tag),
+                }),
+                (Err(e), _) | (_, Err(e)) => {
+                    println!("bad tag rule {:?}: {}", rule, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// facets of the rules matching a page
+pub fn rule_tags(url: &str, text: &str) -> Vec<String> {
+    TAG_RULES
+        .iter()
+        .filter(|rule| rule.url.as_ref().map_or(true, |re| re.is_match(url)))
+        .filter(|rule| rule.content.as_ref().map_or(true, |re| re.is_match(text)))
+        .map(|rule| rule.tag.clone())
+        .collect()
+}
+
+// candidate phrases are runs of words between stop words and punctuation, as in rake
+fn candidate_phrases(text: &str) -> Vec<Vec<String>> {
+    let mut phrases = vec![];
+    for fragment in text.split(|c: char| ".,;:!?()[]{}<>\"|/\\\n\t".contains(c)) {
+        let mut phrase: Vec<String> = vec![];
+        for word in fragment.split_whitespace() {
+            let word = word
+                .trim_matches(|c: char| !c.is_alphanumeric())
+                .to_lowercase();
+            let keep = word.chars().count() > 2
+                && word.chars().any(char::is_alphabetic)
+                && !STOP_WORDS.contains(&word.as_str());
+            if keep {
+                phrase.push(word);
+            } else if !phrase.is_empty() {
+                phrases.push(std::mem::take(&mut phrase));
+            }
+        }
+        if !phrase.is_empty() {
+            phrases.push(phrase);
+        }
+    }
+    phrases
+}
+
+// rake scores weighted by how rare the words are in the index, best first
+pub fn keyphrases(index: &Index, text: &str, limit: usize) -> Vec<String> {
+    let text = text.chars().take(KEYPHRASE_CHARS).collect::<String>();
+    let runs = candidate_phrases(&text);
+    let mut frequency = HashMap::<&str, f32>::new();
+    let mut degree = HashMap::<&str, f32>::new();
+    for run in runs.iter() {
+        for word in run.iter() {
+            *frequency.entry(word).or_insert(0.0) += 1.0;
+            *degree.entry(word).or_insert(0.0) += run.len().min(MAX_PHRASE_WORDS) as f32;
+        }
+    }
+    // long runs are cut into the shorter phrases inside them
+    let mut occurrences = HashMap::<String, usize>::new();
+    let mut phrases = vec![];
+    for run in runs.iter() {
+        for length in 1..=MAX_PHRASE_WORDS.min(run.len()) {
+            for phrase in run.windows(length) {
+                let joined = phrase.join(" ");
+                *occurrences.entry(joined.clone()).or_insert(0) += 1;
+                if occurrences[&joined] == 1 {
+                    phrases.push(phrase);
+                }
+            }
+        }
+    }
+
+    let searcher = indexer::searcher(index);
+    let content = index.schema().get_field("content").expect("content");
+    let documents = searcher.num_docs() as f32;
+    let idf = |word: &str| {
+        let found = searcher.doc_freq(&Term::from_field_text(content, word)) as f32;
+        // smoothed so a small index still ranks phrases
+        ((documents + 1.0) / (found + 1.0)).ln_1p()
+    };
+
+    let mut scored = phrases
+        .iter()
+        .filter(|phrase| occurrences[&phrase.join(" ")] >= MIN_OCCURRENCES)
+        .map(|phrase| {
+            let rake: f32 = phrase
+                .iter()
+                .map(|w| degree[w.as_str()] / frequency[w.as_str()])
+                .sum();
+            let rarity = phrase.iter().map(|w| idf(w)).sum::<f32>() / phrase.len() as f32;
+            (phrase.join(" "), rake * rarity)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    // a phrase inside one already picked, or the other way around, says nothing new
+    let mut picked: Vec<String> = vec![];
+    for (phrase, _) in scored {
+        if picked.len() == limit {
+            break;
+        }
+        let overlaps = picked.iter().any(|p| {
+            format!(" {} ", p).contains(&format!(" {} ", phrase))
+                || format!(" {} ", phrase).contains(&format!(" {} ", p))
+        });
+        if !overlaps {
+            picked.push(phrase);
+        }
+    }
+    picked
+}
+
+fn first_text<'a>(doc: &'a Document, schema: &Schema, name: &str) -> &'a str {
+    doc.get_first(schema.get_field(name).expect(name))
+        .and_then(|value| value.text())
+        .unwrap_or("")
+}
+
+// adds rule tags and, for english or undetected pages, keyphrase tags to a new document
+pub fn add_tags(doc: &mut Document, index: &Index) {
+    let schema = index.schema();
+    let tags = schema.get_field("tags").expect("tags");
+    let url = first_text(doc, &schema, "url").to_string();
+    let text = format!(
+        "{}\n{}",
+        first_text(doc, &schema, "title"),
+        first_text(doc, &schema, "content")
+    );
+    let mut facets = rule_tags(&url, &text);
+    let lang = first_text(doc, &schema, "lang");
+    let limit = indexer::CACHEDCONFIG.auto_tags;
+    if limit > 0 && (lang.is_empty() || lang == "en") {
+        facets.extend(
+            keyphrases(index, &text, limit)
+                .iter()
+                .map(|phrase| format!("{}/{}", AUTO_FACET, phrase)),
+        );
+    }
+    let existing = doc
+        .get_all(tags)
+        .iter()
+        .filter_map(|value| match value {
+            Value::Facet(facet) => Some(facet.to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for facet in facets {
+        if !existing.contains(&facet) {
+            doc.add_facet(tags, Facet::from(&facet));
+        }
+    }
+}
+
+// adds the tags of the current rules to every document. returns how many changed
+pub fn apply_tag_rules() -> usize {
+    if TAG_RULES.is_empty() {
+        return 0;
+    }
+    let index = indexer::search_index().expect("search index");
+    let id_field = index.schema().get_field("id").expect("id");
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    let mut count = 0;
+    for url_hash in indexer::SOURCE_STORE.hashes().expect("source list") {
+        let json = match indexer::read_source(&url_hash)
+            .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        {
+            Some(json) => json,
+            None => continue,
+        };
+        let first = |name: &str| json[name][0].as_str().unwrap_or("").to_string();
+        let text = format!("{}\n{}", first("title"), first("content"));
+        let existing = json["tags"].as_array().cloned().unwrap_or_default();
+        let missing = rule_tags(&first("url"), &text)
+            .into_iter()
+            .filter(|tag| !existing.contains(&serde_json::Value::String(tag.clone())))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            continue;
+        }
+        let doc = indexer::patch_source(&url_hash, &index, |json| {
+            let mut tags = json["tags"].as_array().cloned().unwrap_or_default();
+            tags.extend(missing.into_iter().map(serde_json::Value::String));
+            json["tags"] = serde_json::Value::Array(tags);
+        });
+        let doc = match doc {
+            Some(doc) => doc,
+            None => continue,
+        };
+        index_writer.delete_term(Term::from_field_text(id_field, &url_hash));
+        index_writer.add_document(doc);
+        count += 1;
+        if count % 10000 == 0 {
+            println!("commited {}", count);
+            index_writer.commit().expect("commit");
+        }
+    }
+    index_writer.commit().expect("last commit");
+    index_writer.wait_merging_threads().expect("merge");
+    count
+}
+
+// documents tagged with facet or anything below it
+fn tagged_ids(index: &Index, facet: &str) -> Vec<String> {
+    let searcher = indexer::searcher(index);
+    let schema = index.schema();
+    let id_field = schema.get_field("id").expect("id");
+    let query = TermQuery::new(
+        Term::from_facet(schema.get_field("tags").expect("tags"), &Facet::from(facet)),
+        IndexRecordOption::Basic,
+    );
+    let limit = (searcher.num_docs() as usize).max(1);
+    searcher
+        .search(&query, &TopDocs::with_limit(limit))
+        .expect("search")
+        .into_iter()
+        .filter_map(|(_, address)| {
+            let doc = searcher.doc(address).ok()?;
+            Some(doc.get_first(id_field)?.text()?.to_string())
+        })
+        .collect()
+}
+
+// every tag under prefix with the number of documents that have it
+pub fn list_tags(prefix: &str) -> Vec<(String, u64)> {
+    let prefix = tag_facet(prefix);
+    let index = indexer::search_index().expect("search index");
+    let tags = index.schema().get_field("tags").expect("tags");
+    let mut counts = HashMap::<String, u64>::new();
+    indexer::for_each_document(&index, |doc| {
+        for value in doc.get_all(tags) {
+            if let Value::Facet(facet) = value {
+                let facet = facet.to_path_string();
+                if prefix == "/" || facet == prefix || facet.starts_with(&format!("{}/", prefix)) {
+                    *counts.entry(facet).or_insert(0) += 1;
+                }
+            }
+        }
+    });
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+// the new name of tag when from is renamed to, None when it goes away
+fn rewritten(tag: &str, from: &str, to: Option<&str>, subtree: bool) -> Option<Option<String>> {
+    if tag == from {
+        Some(to.map(str::to_string))
+    } else if subtree && tag.starts_with(&format!("{}/", from)) {
+        Some(to.map(|to| format!("{}{}", to, &tag[from.len()..])))
+    } else {
+        None
+    }
+}
+
+// renames each tag in from to to in the sources and the index, or removes
+// it when to is None. with subtree the tags below each one move along.
+// returns how many documents changed
+pub fn rewrite_tags(from: &[String], to: Option<&str>, subtree: bool) -> usize {
+    let from = from.iter().map(|tag| tag_facet(tag)).collect::<Vec<_>>();
+    let to = to.map(tag_facet);
+    let index = indexer::search_index().expect("search index");
+    let id_field = index.schema().get_field("id").expect("id");
+    let mut ids = from
+        .iter()
+        .flat_map(|facet| tagged_ids(&index, facet))
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    let mut count = 0;
+    for id in ids {
+        let json = match indexer::read_source(&id)
+            .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        {
+            Some(json) => json,
+            None => continue,
+        };
+        let old_tags = json["tags"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|tag| tag.as_str().map(str::to_string))
+            .collect::<Vec<_>>();
+        let mut new_tags: Vec<String> = vec![];
+        for tag in old_tags.iter() {
+            let tag = from
+                .iter()
+                .find_map(|from| rewritten(tag, from, to.as_deref(), subtree))
+                .unwrap_or_else(|| Some(tag.clone()));
+            if let Some(tag) = tag {
+                if !new_tags.contains(&tag) {
+                    new_tags.push(tag);
+                }
+            }
+        }
+        if new_tags == old_tags {
+            continue;
+        }
+        let doc = indexer::patch_source(&id, &index, |json| {
+            json["tags"] = serde_json::json!(new_tags);
+        });
+        if let Some(doc) = doc {
+            index_writer.delete_term(Term::from_field_text(id_field, &id));
+            index_writer.add_document(doc);
+            count += 1;
+        }
+    }
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+    count
+}
@@ -0,0 +1,446 @@
+use crate::bulk;
+use crate::indexer;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{Frame, Terminal};
+use std::io::{stdout, Stdout};
+use std::process::{Command, Stdio};
+use tantivy::collector::TopDocs;
+use tantivy::schema::*;
+use tantivy::Index;
+
+const RESULTS: usize = 200;
+const HELP: &str = "enter/o open · p pin · x hide · t tag · pgup/pgdn scroll · / search · q quit";
+
+#[derive(PartialEq)]
+enum Focus {
+    Query,
+    Results,
+    // typing a tag for the selected result
+    Tag,
+}
+
+struct Row {
+    id: String,
+    title: String,
+    url: String,
+    domain: String,
+    // last visit
+    date: String,
+    tags: Vec<String>,
+    pinned: bool,
+}
+
+struct App {
+    index: Index,
+    query: String,
+    tag: String,
+    focus: Focus,
+    rows: Vec<Row>,
+    list_state: ListState,
+    preview: Vec<Line<'static>>,
+    scroll: u16,
+    status: String,
+    // the indexer prints while changing documents, the screen needs a full redraw
+    redraw: bool,
+}
+
+// puts the terminal back when the ui ends, also on a panic
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(stdout(), LeaveAlternateScreen);
+    }
+}
+
+fn search(index: &Index, query: &str) -> Result<Vec<Row>, String> {
+    if query.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let parsed =
+        indexer::search_query(index, query, None, false).map_err(|e| format!("{:?}", e))?;
+    let schema = index.schema();
+    let searcher = indexer::searcher(index);
+    let text = |doc: &Document, name: &str| {
+        doc.get_first(schema.get_field(name).expect(name))
+            .and_then(|value| value.text())
+            .unwrap_or("")
+            .to_string()
+    };
+    let number = |doc: &Document, name: &str| {
+        doc.get_first(schema.get_field(name).expect(name))
+            .map(|value| value.i64_value())
+            .unwrap_or(0)
+    };
+    let rows = searcher
+        .search(parsed.as_ref(), &TopDocs::with_limit(RESULTS))
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter_map(|(_, address)| searcher.doc(address).ok())
+        .map(|doc| {
+            let url = text(&doc, "url");
+            Row {
+                id: text(&doc, "id"),
+                title: text(&doc, "title"),
+                domain: url::Url::parse(&url)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_default(),
+                url,
+                date: chrono::NaiveDateTime::from_timestamp(number(&doc, "last_accessed_at_i"), 0)
+                    .format("%Y-%m-%d")
+                    .to_string(),
+                tags: doc
+                    .get_all(schema.get_field("tags").expect("tags"))
+                    .iter()
+                    .filter_map(|value| match value {
+                        Value::Facet(facet) => Some(facet.to_path_string()),
+                        _ => None,
+                    })
+                    .collect(),
+                pinned: number(&doc, "pinned") == 1,
+            }
+        })
+        .collect();
+    Ok(rows)
+}
+
+// the stored text of a page with its title, url, tags and summary on top
+fn preview(row: &Row) -> Vec<Line<'static>> {
+    let json = indexer::read_source(&row.id)
+        .and_then(|json| serde_json::from_str::<serde_json::Value>(&json).ok())
+        .unwrap_or_default();
+    let text = |name: &str| json[name][0].as_str().unwrap_or("").to_string();
+    let mut lines = vec![
+        Line::from(Span::styled(
+            row.title.clone(),
+            Style::default().add_modifier(Modifier::BOLD),
+        )),
+        Line::from(Span::styled(
+            row.url.clone(),
+            Style::default().fg(Color::Cyan),
+        )),
+    ];
+    if !row.tags.is_empty() {
+        lines.push(Line::from(Span::styled(
+            row.tags.join(" "),
+            Style::default().fg(Color::Yellow),
+        )));
+    }
+    for name in &["summary", "notes"] {
+        let value = text(name);
+        if !value.trim().is_empty() {
+            lines.push(Line::from(""));
+            lines.push(Line::from(Span::styled(
+                value,
+                Style::default().fg(Color::Gray),
+            )));
+        }
+    }
+    lines.push(Line::from(""));
+    lines.extend(
+        text("content")
+            .lines()
+            .map(|line| Line::from(line.to_string())),
+    );
+    lines
+}
+
+// the default browser of the platform
+fn open_in_browser(url: &str) -> std::io::Result<()> {
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(&["/C", "start", ""]);
+        command
+    } else {
+        Command::new("xdg-open")
+    };
+    command
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+impl App {
+    fn new(index: Index) -> App {
+        App {
+            index,
+            query: String::new(),
+            tag: String::new(),
+            focus: Focus::Query,
+            rows: vec![],
+            list_state: ListState::default(),
+            preview: vec![],
+            scroll: 0,
+            status: HELP.to_string(),
+            redraw: false,
+        }
+    }
+
+    fn selected(&self) -> Option<&Row> {
+        self.list_state.selected().and_then(|i| self.rows.get(i))
+    }
+
+    // runs the query again, keeping the selection where it was
+    fn refresh(&mut self) {
+        match search(&self.index, &self.query) {
+            Ok(rows) => {
+                self.rows = rows;
+                self.status = format!("{} results · {}", self.rows.len(), HELP);
+            }
+            // half typed queries keep the last results
+            Err(e) => self.status = e,
+        }
+        let selected = if self.rows.is_empty() {
+            None
+        } else {
+            Some(
+                self.list_state
+                    .selected()
+                    .unwrap_or(0)
+                    .min(self.rows.len() - 1),
+            )
+        };
+        self.select(selected);
+    }
+
+    fn select(&mut self, selected: Option<usize>) {
+        self.list_state.select(selected);
+        self.scroll = 0;
+        self.preview = self.selected().map(preview).unwrap_or_default();
+    }
+
+    fn move_selection(&mut self, by: i64) {
+        if self.rows.is_empty() {
+            return;
+        }
+        let current = self.list_state.selected().unwrap_or(0) as i64;
+        let selected = (current + by).max(0).min(self.rows.len() as i64 - 1);
+        self.select(Some(selected as usize));
+    }
+
+    // pin, unpin, hide or tag the selected result
+    fn act(&mut self, action: &str, value: Option<&str>) {
+        let id = match self.selected() {
+            Some(row) => row.id.clone(),
+            None => return,
+        };
+        self.status = match bulk::run(&self.index, &[id], action, value) {
+            Ok(report) if report.changed > 0 => format!("{} done", action),
+            Ok(_) => format!("{} changed nothing", action),
+            Err(e) => e,
+        };
+        let status = self.status.clone();
+        self.refresh();
+        self.status = status;
+        self.redraw = true;
+    }
+
+    fn open(&mut self) {
+        let id = match self.selected() {
+            Some(row) => row.id.clone(),
+            None => return,
+        };
+        // counted as a visit like opening it from the search page
+        self.redraw = true;
+        let url = indexer::record_visit(&id, &self.index)
+            .or_else(|| self.selected().map(|row| row.url.clone()));
+        if let Some(url) = url {
+            self.status = match open_in_browser(&url) {
+                Ok(_) => format!("opened {}", url),
+                Err(e) => format!("could not open {}: {}", url, e),
+            };
+        }
+    }
+
+    // false when the ui should end
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+        match self.focus {
+            Focus::Query => match key.code {
+                KeyCode::Esc => return false,
+                KeyCode::Enter | KeyCode::Down | KeyCode::Tab => {
+                    if !self.rows.is_empty() {
+                        self.focus = Focus::Results;
+                    }
+                }
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.list_state.select(None);
+                    self.refresh();
+                }
+                KeyCode::Char(c) => {
+                    self.query.push(c);
+                    self.list_state.select(None);
+                    self.refresh();
+                }
+                _ => {}
+            },
+            Focus::Results => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return false,
+                KeyCode::Char('/') | KeyCode::Tab => self.focus = Focus::Query,
+                KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+                KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+                KeyCode::Enter | KeyCode::Char('o') => self.open(),
+                KeyCode::Char('p') => {
+                    let pinned = self.selected().map_or(false, |row| row.pinned);
+                    self.act(if pinned { "unpin" } else { "pin" }, None);
+                }
+                KeyCode::Char('x') => self.act("hide", None),
+                KeyCode::Char('t') => {
+                    self.tag.clear();
+                    self.focus = Focus::Tag;
+                }
+                _ => {}
+            },
+            Focus::Tag => match key.code {
+                KeyCode::Esc => self.focus = Focus::Results,
+                KeyCode::Enter => {
+                    let tag = self.tag.clone();
+                    self.act("tag", Some(&tag));
+                    self.focus = Focus::Results;
+                }
+                KeyCode::Backspace => {
+                    self.tag.pop();
+                }
+                KeyCode::Char(c) => self.tag.push(c),
+                _ => {}
+            },
+        }
+        true
+    }
+}
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .split(frame.size());
+    let body = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(45), Constraint::Percentage(55)])
+        .split(rows[1]);
+
+    let (title, input) = if app.focus == Focus::Tag {
+        ("tag", app.tag.as_str())
+    } else {
+        ("search", app.query.as_str())
+    };
+    let border = |focused: bool| {
+        if focused {
+            Style::default().fg(Color::Cyan)
+        } else {
+            Style::default()
+        }
+    };
+    frame.render_widget(
+        Paragraph::new(input).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border(app.focus != Focus::Results))
+                .title(title),
+        ),
+        rows[0],
+    );
+    if app.focus != Focus::Results {
+        frame.set_cursor(rows[0].x + 1 + input.chars().count() as u16, rows[0].y + 1);
+    }
+
+    let items = app
+        .rows
+        .iter()
+        .map(|row| {
+            let title = if row.title.is_empty() {
+                row.url.clone()
+            } else {
+                row.title.clone()
+            };
+            let pin = if row.pinned { "★ " } else { "" };
+            ListItem::new(vec![
+                Line::from(format!("{}{}", pin, title)),
+                Line::from(vec![
+                    Span::styled(row.domain.clone(), Style::default().fg(Color::Cyan)),
+                    Span::raw(" "),
+                    Span::styled(row.date.clone(), Style::default().fg(Color::Gray)),
+                    Span::raw(" "),
+                    Span::styled(row.tags.join(" "), Style::default().fg(Color::Yellow)),
+                ]),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border(app.focus == Focus::Results))
+                .title("results"),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol("> ");
+    frame.render_stateful_widget(list, body[0], &mut app.list_state);
+
+    frame.render_widget(
+        Paragraph::new(app.preview.clone())
+            .block(Block::default().borders(Borders::ALL).title("preview"))
+            .wrap(Wrap { trim: false })
+            .scroll((app.scroll, 0)),
+        body[1],
+    );
+    frame.render_widget(
+        Paragraph::new(app.status.as_str()).style(Style::default().fg(Color::Gray)),
+        rows[2],
+    );
+}
+
+pub fn run(index: Index) -> Result<(), String> {
+    // the passphrase prompt can not be answered in raw mode
+    indexer::ensure_unlocked()?;
+    enable_raw_mode().map_err(|e| e.to_string())?;
+    let _guard = TerminalGuard;
+    execute!(stdout(), EnterAlternateScreen).map_err(|e| e.to_string())?;
+    let mut terminal: Terminal<CrosstermBackend<Stdout>> =
+        Terminal::new(CrosstermBackend::new(stdout())).map_err(|e| e.to_string())?;
+    let mut app = App::new(index);
+    indexer::spawn_visit_flusher();
+    loop {
+        terminal
+            .draw(|frame| draw(frame, &mut app))
+            .map_err(|e| e.to_string())?;
+        if let Event::Key(key) = event::read().map_err(|e| e.to_string())? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if !app.handle_key(key) {
+                indexer::flush_visits(&app.index);
+                break;
+            }
+            if app.redraw {
+                app.redraw = false;
+                terminal.clear().map_err(|e| e.to_string())?;
+            }
+        }
+    }
+    Ok(())
+}
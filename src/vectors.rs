@@ -0,0 +1,340 @@
+use crate::indexer;
+#[cfg(feature = "ml")]
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModel,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "ml")]
+use std::sync::Mutex;
+use tantivy::collector::TopDocs;
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::*;
+use tantivy::{DocAddress, Index, Searcher};
+
+// random hyperplane signatures, split into bands so similar vectors can be
+// found with term lookups like the simhash bands for near duplicates. short
+// bands match often, candidates sharing the most bands are compared first
+const SIGNATURE_BITS: usize = 64;
+const SIGNATURE_BANDS: usize = 16;
+// fixed so signatures stay comparable between runs
+const HYPERPLANE_SEED: u64 = 0x5eed_1e55_b0a7_5eed;
+// up to this many vectors every one is compared instead of using the bands
+const EXACT_SEARCH_DOCS: u64 = 5000;
+const CANDIDATES: usize = 2000;
+// less similar documents are left out of semantic results
+const MIN_SIMILARITY: f32 = 0.2;
+// characters of title and content given to the model
+const EMBEDDING_CHARS: usize = 2000;
+pub const EMBEDDING_BATCH: usize = 32;
+
+#[cfg(feature = "ml")]
+lazy_static::lazy_static! {
+    // loaded once per process, None when there is no model on disk
+    static ref EMBEDDING_MODEL: Mutex<Option<SentenceEmbeddingsModel>> = Mutex::new(load_model());
+}
+
+// a sentence embeddings model exported for rust-bert, by default in
+// models/embeddings in the index directory
+pub fn model_directory() -> PathBuf {
+    let configured = &indexer::CACHEDCONFIG.embedding_model;
+    if configured.is_empty() {
+        Path::new(indexer::BASE_INDEX_DIR.as_str()).join("models/embeddings")
+    } else {
+        PathBuf::from(configured)
+    }
+}
+
+#[cfg(feature = "ml")]
+fn load_model() -> Option<SentenceEmbeddingsModel> {
+    let directory = model_directory();
+    if !directory.is_dir() {
+        println!("no embedding model in {}", directory.display());
+        return None;
+    }
+    match SentenceEmbeddingsBuilder::local(&directory)
+        .with_device(tch::Device::Cpu)
+        .create_model()
+    {
+        Ok(model) => Some(model),
+        Err(e) => {
+            println!("embedding model error {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(feature = "ml")]
+pub fn embed_batch(texts: &[String]) -> Option<Vec<Vec<f32>>> {
+    let model = EMBEDDING_MODEL.lock().expect("embedding model lock");
+    let model = model.as_ref()?;
+    match model.encode(texts) {
+        Ok(embeddings) => Some(embeddings.into_iter().map(normalized).collect()),
+        Err(e) => {
+            println!("embedding error {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "ml"))]
+pub fn embed_batch(_texts: &[String]) -> Option<Vec<Vec<f32>>> {
+    None
+}
+
+pub fn embed(text: &str) -> Option<Vec<f32>> {
+    embed_batch(&[text.to_string()])?.pop()
+}
+
+#[cfg(feature = "ml")]
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let length = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= length);
+    }
+    vector
+}
+
+// vectors are normalized when they are made, so this is the cosine similarity
+fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn signature(vector: &[f32]) -> u64 {
+    let mut state = HYPERPLANE_SEED;
+    let mut signature = 0u64;
+    for bit in 0..SIGNATURE_BITS {
+        let side: f32 = vector
+            .iter()
+            .map(|x| {
+                let plane = (splitmix64(&mut state) >> 11) as f32 / (1u64 << 53) as f32 - 0.5;
+                x * plane
+            })
+            .sum();
+        if side >= 0.0 {
+            signature |= 1 << bit;
+        }
+    }
+    signature
+}
+
+fn signature_bands(signature: u64) -> Vec<String> {
+    let width = SIGNATURE_BITS / SIGNATURE_BANDS;
+    (0..SIGNATURE_BANDS)
+        .map(|band| {
+            let bits = (signature >> (band * width)) & ((1 << width) - 1);
+            format!("{}:{:x}", band, bits)
+        })
+        .collect()
+}
+
+fn to_bytes(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|x| x.to_le_bytes().to_vec())
+        .collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+fn vectors_directory(
+) -> Result<indexer::SealedDirectory, tantivy::directory::error::OpenDirectoryError> {
+    let path = Path::new(indexer::BASE_INDEX_DIR.as_str()).join("vectors");
+    if !path.is_dir() {
+        fs::create_dir_all(&path).expect("could not make vectors dir");
+    }
+    indexer::SealedDirectory::open(path)
+}
+
+// one embedding per document, next to the search index
+pub fn vector_index() -> std::result::Result<tantivy::Index, tantivy::TantivyError> {
+    let mut schema_builder = Schema::builder();
+    schema_builder.add_text_field("id", STRING | STORED);
+    schema_builder.add_bytes_field("vector");
+    schema_builder.add_text_field("bands", STRING);
+
+    let schema = schema_builder.build();
+    match vectors_directory() {
+        Ok(dir) => Index::open_or_create(dir, schema),
+        Err(_) => Err(tantivy::TantivyError::SystemError(format!(
+            "could not open vectors directory {}",
+            indexer::BASE_INDEX_DIR.as_str()
+        ))),
+    }
+}
+
+fn vector_document(index: &Index, id: &str, vector: &[f32]) -> Document {
+    let schema = index.schema();
+    let mut doc = Document::default();
+    doc.add_text(schema.get_field("id").expect("id"), id);
+    doc.add_bytes(
+        schema.get_field("vector").expect("vector"),
+        to_bytes(vector),
+    );
+    for band in signature_bands(signature(vector)) {
+        doc.add_text(schema.get_field("bands").expect("bands"), &band);
+    }
+    doc
+}
+
+// what the model reads for a search document
+pub fn document_text(doc: &Document, schema: &Schema) -> Option<(String, String)> {
+    let text = |name: &str| {
+        doc.get_first(schema.get_field(name).expect(name))
+            .and_then(|value| value.text())
+            .unwrap_or("")
+            .to_string()
+    };
+    let id = text("id");
+    if id.is_empty() {
+        return None;
+    }
+    let text = format!("{}\n{}", text("title"), text("content"));
+    Some((id, text.chars().take(EMBEDDING_CHARS).collect()))
+}
+
+pub fn has_embedding(id: &str) -> bool {
+    let index = vector_index().expect("vector index");
+    let query = TermQuery::new(
+        Term::from_field_text(index.schema().get_field("id").expect("id"), id),
+        IndexRecordOption::Basic,
+    );
+    indexer::searcher(&index)
+        .search(&query, &TopDocs::with_limit(1))
+        .map(|docs| !docs.is_empty())
+        .unwrap_or(false)
+}
+
+// embeds a batch of (id, text) and replaces their vectors. nothing happens
+// without a model
+pub fn add_embeddings(documents: &[(String, String)]) -> usize {
+    let texts = documents
+        .iter()
+        .map(|(_, text)| text.clone())
+        .collect::<Vec<_>>();
+    let embeddings = match embed_batch(&texts) {
+        Some(embeddings) => embeddings,
+        None => return 0,
+    };
+    let index = vector_index().expect("vector index");
+    let id_field = index.schema().get_field("id").expect("id");
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    for ((id, _), vector) in documents.iter().zip(embeddings.iter()) {
+        index_writer.delete_term(Term::from_field_text(id_field, id));
+        index_writer.add_document(vector_document(&index, id, vector));
+    }
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+    embeddings.len()
+}
+
+pub fn register_embedding(doc: &Document, schema: &Schema) {
+    if let Some(document) = document_text(doc, schema) {
+        add_embeddings(&[document]);
+    }
+}
+
+// with one commit
+pub fn remove_embeddings(ids: &[String]) {
+    if ids.is_empty() {
+        return;
+    }
+    let index = vector_index().expect("vector index");
+    let id_field = index.schema().get_field("id").expect("id");
+    let mut index_writer = index.writer(50_000_000).expect("writer");
+    for id in ids {
+        index_writer.delete_term(Term::from_field_text(id_field, id));
+    }
+    index_writer.commit().expect("commit");
+    index_writer.wait_merging_threads().expect("merge");
+}
+
+fn stored_vector(
+    searcher: &Searcher,
+    schema: &Schema,
+    address: DocAddress,
+) -> Option<(String, Vec<f32>)> {
+    let segment_reader = searcher.segment_reader(address.segment_ord());
+    let vectors = segment_reader
+        .fast_fields()
+        .bytes(schema.get_field("vector").expect("vector"))?;
+    let doc = searcher.doc(address).ok()?;
+    let id = doc.get_first(schema.get_field("id").expect("id"))?.text()?;
+    Some((id.to_string(), from_bytes(vectors.get_bytes(address.doc()))))
+}
+
+// documents most similar to the text, with their cosine similarity
+pub fn nearest(text: &str, limit: usize) -> Vec<(String, f32)> {
+    let query_vector = match embed(text) {
+        Some(vector) => vector,
+        None => return vec![],
+    };
+    let index = vector_index().expect("vector index");
+    let schema = index.schema();
+    let searcher = indexer::searcher(&index);
+    let bands_field = schema.get_field("bands").expect("bands");
+    let exact = searcher.num_docs() <= EXACT_SEARCH_DOCS;
+    let query: Box<dyn Query> = if exact {
+        Box::new(AllQuery)
+    } else {
+        Box::new(BooleanQuery::from(
+            signature_bands(signature(&query_vector))
+                .iter()
+                .map(|band| {
+                    let query: Box<dyn Query> = Box::new(TermQuery::new(
+                        Term::from_field_text(bands_field, band),
+                        IndexRecordOption::Basic,
+                    ));
+                    (Occur::Should, query)
+                })
+                .collect::<Vec<_>>(),
+        ))
+    };
+    let limit_candidates = if exact {
+        searcher.num_docs() as usize
+    } else {
+        (searcher.num_docs() as usize).min(CANDIDATES.max(limit))
+    }
+    .max(1);
+    let candidates = searcher
+        .search(&query, &TopDocs::with_limit(limit_candidates))
+        .expect("search");
+    let mut scored = HashMap::<String, f32>::new();
+    for (_, address) in candidates {
+        if let Some((id, vector)) = stored_vector(&searcher, &schema, address) {
+            scored.insert(id, similarity(&query_vector, &vector));
+        }
+    }
+    let mut scored = scored
+        .into_iter()
+        .filter(|(_, similarity)| *similarity >= MIN_SIMILARITY)
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    scored.truncate(limit);
+    scored
+}
+
+// the words of a search without field:value parts and query syntax
+pub fn query_text(query: &str) -> String {
+    query
+        .split_whitespace()
+        .filter(|word| !word.contains(':') && !["AND", "OR", "NOT"].contains(word))
+        .filter(|word| !word.starts_with('-'))
+        .map(|word| word.trim_matches(|c: char| c == '+' || c == '~' || c == '"'))
+        .collect::<Vec<_>>()
+        .join(" ")
+}